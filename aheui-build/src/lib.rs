@@ -0,0 +1,325 @@
+// Build script support: compiles the `.aheui` files of a crate into a static
+// library and generates Rust declarations for their entry functions.
+//
+//     // build.rs
+//     extern crate aheui_build;
+//     fn main() { aheui_build::compile("aheui"); }
+//
+//     // src/main.rs
+//     include!(concat!(env!("OUT_DIR"), "/aheui.rs"));
+//     fn main() { std::process::exit(hello::run()); }
+//
+// The steps are the ones from README.md: the aheui compiler (`$AHEUI`,
+// default `aheui`) emits LLVM IR, `llc` (`$LLC`) turns it into an object
+// file and `ar` (`$AR`) archives the objects. The runtime (src/rt.rs) is
+// built into an rlib with `rustc` (`$RUSTC`), the compiler cargo builds the
+// crate with, so that the crate and the runtime share one copy of std.
+
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+const RT_SRC: &'static str = include_str!("../../src/rt.rs");
+
+// rt.rs logs through `log`, which a bare rustc invocation cannot find
+const RT_ROOT: &'static str = "
+#![crate_type = \"rlib\"]
+#![allow(dead_code, unused_variables)]
+macro_rules! debug { ($($t:tt)*) => () }
+#[path = \"rt.rs\"]
+pub mod rt;
+#[no_mangle]
+pub extern \"C\" fn aheui_trace(ctx: *mut rt::AheuiRt, x: i32, y: i32, c: char, flow: i8,
+                              idx: i8) {
+    rt::aheui_trace(ctx, x, y, c, flow, idx)
+}
+";
+
+pub struct Config {
+    src_dir: PathBuf,
+    entry_prefix: String,
+    entries: Vec<(String, String)>,
+    runtime: bool,
+}
+
+pub struct Program {
+    pub path: PathBuf,
+    pub name: String,
+    pub symbol: String,
+}
+
+pub fn compile<P: AsRef<Path>>(src_dir: P) {
+    Config::new(src_dir).compile();
+}
+
+fn fail(msg: &str) -> ! {
+    println!("\n\n{}\n\n", msg);
+    panic!()
+}
+
+fn getenv(v: &str) -> Option<String> {
+    env::var(v).ok()
+}
+
+fn tool(var: &str, default: &str) -> String {
+    getenv(var).unwrap_or(default.to_string())
+}
+
+fn run(cmd: &mut Command) {
+    println!("running: {:?}", cmd);
+    let status = match cmd.status() {
+        Ok(s) => s,
+        Err(e) => fail(&format!("failed to execute {:?}: {}", cmd, e)),
+    };
+    if !status.success() {
+        fail(&format!("command {:?} did not succeed: {}", cmd, status));
+    }
+}
+
+// turns a file stem into a Rust identifier, e.g. `hello-world` -> `hello_world`;
+// other characters are escaped by code point so that `안녕` and `세계` differ
+pub fn ident(name: &str) -> String {
+    let mut s = String::new();
+    for c in name.chars() {
+        if (c as u32) < 0x80 && (c.is_alphanumeric() || c == '_') {
+            s.push(c);
+        } else if (c as u32) < 0x80 {
+            s.push('_');
+        } else {
+            s.push_str(&format!("_u{:x}", c as u32));
+        }
+    }
+    if s.is_empty() || s.chars().next().unwrap().is_numeric() {
+        s.insert(0, '_');
+    }
+    s
+}
+
+fn find_sources(dir: &Path, out: &mut Vec<PathBuf>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(e) => e,
+        Err(e) => fail(&format!("cannot read {}: {}", dir.display(), e)),
+    };
+    for entry in entries {
+        let path = entry.unwrap().path();
+        if path.is_dir() {
+            find_sources(&path, out);
+        } else if path.extension().and_then(|e| e.to_str()) == Some("aheui") {
+            out.push(path);
+        }
+    }
+}
+
+// `pub mod NAME { pub fn run() -> i32 }` for every program; each run gets a
+// runtime context of its own
+pub fn declarations(programs: &[Program], runtime: bool) -> String {
+    let mut out = String::new();
+    out.push_str("// generated by aheui-build\n");
+    if runtime {
+        // nothing in the crate names the runtime, but the programs need it
+        out.push_str("#[allow(unused_extern_crates)]\nextern crate aheui_rt;\n");
+    }
+    for p in programs.iter() {
+        writeln!(out, "\n// {}", p.path.display()).unwrap();
+        writeln!(out, "pub mod {} {{", p.name).unwrap();
+        out.push_str("    extern \"C\" {\n");
+        writeln!(out, "        #[link_name = \"{}\"]", p.symbol).unwrap();
+        out.push_str("        pub fn main(ctx: *mut u8) -> i32;\n");
+        out.push_str("        fn aheui_ctx_new() -> *mut u8;\n");
+        out.push_str("        fn aheui_ctx_free(ctx: *mut u8);\n");
+        out.push_str("    }\n\n");
+        out.push_str("    pub fn run() -> i32 {\n");
+        out.push_str("        unsafe {\n");
+        out.push_str("            let ctx = aheui_ctx_new();\n");
+        out.push_str("            let code = main(ctx);\n");
+        out.push_str("            aheui_ctx_free(ctx);\n");
+        out.push_str("            code\n");
+        out.push_str("        }\n");
+        out.push_str("    }\n");
+        out.push_str("}\n");
+    }
+    out
+}
+
+impl Config {
+    pub fn new<P: AsRef<Path>>(src_dir: P) -> Config {
+        Config {
+            src_dir: src_dir.as_ref().to_path_buf(),
+            entry_prefix: "aheui_".to_string(),
+            entries: Vec::new(),
+            runtime: true,
+        }
+    }
+
+    // entry symbols default to the prefix followed by the program name
+    pub fn entry_prefix(&mut self, prefix: &str) -> &mut Config {
+        self.entry_prefix = prefix.to_string();
+        self
+    }
+
+    // overrides the entry symbol (`-m`) of one program
+    pub fn entry(&mut self, name: &str, symbol: &str) -> &mut Config {
+        self.entries.push((name.to_string(), symbol.to_string()));
+        self
+    }
+
+    // whether to build and link the runtime library (default true)
+    pub fn runtime(&mut self, runtime: bool) -> &mut Config {
+        self.runtime = runtime;
+        self
+    }
+
+    pub fn programs(&self) -> Vec<Program> {
+        let dir = match getenv("CARGO_MANIFEST_DIR") {
+            Some(d) => Path::new(&d).join(&self.src_dir),
+            None => self.src_dir.clone(),
+        };
+        let mut paths = Vec::new();
+        find_sources(&dir, &mut paths);
+        paths.sort();
+
+        paths.into_iter().map(|path| {
+            let name = ident(path.file_stem().unwrap().to_str().unwrap());
+            let symbol = match self.entries.iter().find(|e| e.0 == name) {
+                Some(e) => e.1.clone(),
+                None => format!("{}{}", self.entry_prefix, name),
+            };
+            Program { path: path, name: name, symbol: symbol }
+        }).collect()
+    }
+
+    pub fn compile(&self) {
+        let out_dir = match getenv("OUT_DIR") {
+            Some(d) => PathBuf::from(d),
+            None => fail("OUT_DIR is not set; aheui_build must run from a build script"),
+        };
+        let aheui = tool("AHEUI", "aheui");
+        let llc = tool("LLC", "llc");
+        let ar = tool("AR", "ar");
+
+        let programs = self.programs();
+        for (i, p) in programs.iter().enumerate() {
+            if let Some(q) = programs[..i].iter().find(|q| q.name == p.name) {
+                fail(&format!("{} and {} would both be named `{}`",
+                              q.path.display(), p.path.display(), p.name));
+            }
+        }
+
+        let mut objects = Vec::new();
+        for p in programs.iter() {
+            let ll = out_dir.join(format!("{}.ll", p.name));
+            let obj = out_dir.join(format!("{}.o", p.name));
+            run(Command::new(&aheui).arg("-m").arg(&p.symbol)
+                                    .arg("-o").arg(&ll).arg(&p.path));
+            run(Command::new(&llc).arg("-O=3").arg("-filetype=obj")
+                                  .arg("-relocation-model=pic")
+                                  .arg("-o").arg(&obj).arg(&ll));
+            objects.push(obj);
+        }
+
+        let lib = out_dir.join("libaheui_programs.a");
+        let _ = fs::remove_file(&lib);
+        run(Command::new(&ar).arg("crs").arg(&lib).args(&objects));
+
+        if self.runtime {
+            build_runtime(&out_dir);
+        }
+
+        let decls = out_dir.join("aheui.rs");
+        let text = declarations(&programs, self.runtime);
+        File::create(&decls).unwrap().write_all(text.as_bytes()).unwrap();
+
+        println!("cargo:rustc-link-search=native={}", out_dir.display());
+        println!("cargo:rustc-link-lib=static=aheui_programs");
+        if self.runtime {
+            println!("cargo:rustc-link-search=crate={}", out_dir.display());
+        }
+    }
+}
+
+// `libaheui_rt.rlib` in `out_dir`, for `extern crate aheui_rt`
+fn build_runtime(out_dir: &Path) {
+    let rt_dir = out_dir.join("aheui_rt");
+    let _ = fs::create_dir(&rt_dir);
+    File::create(rt_dir.join("rt.rs")).unwrap().write_all(RT_SRC.as_bytes()).unwrap();
+    File::create(rt_dir.join("lib.rs")).unwrap().write_all(RT_ROOT.as_bytes()).unwrap();
+    run(Command::new(tool("RUSTC", "rustc"))
+            .arg("-O").arg("--crate-name").arg("aheui_rt")
+            .arg("--out-dir").arg(out_dir)
+            .arg(rt_dir.join("lib.rs")));
+}
+
+#[test]
+fn test_ident() {
+    assert!(ident("hello") == "hello");
+    assert!(ident("hello-world.v2") == "hello_world_v2");
+    assert!(ident("99bottles") == "_99bottles");
+    assert!(ident("아희") == "_uc544_ud76c");
+    assert!(ident("아희") != ident("안녕"));
+    assert!(ident("hello-아희") == "hello__uc544_ud76c");
+}
+
+#[test]
+fn test_declarations() {
+    let programs = vec!(Program {
+        path: PathBuf::from("aheui/hello.aheui"),
+        name: "hello".to_string(),
+        symbol: "aheui_hello".to_string(),
+    });
+    let decls = declarations(&programs, true);
+    assert!(decls.contains("extern crate aheui_rt;"));
+    assert!(decls.contains("pub mod hello {"));
+    assert!(decls.contains("#[link_name = \"aheui_hello\"]"));
+    assert!(decls.contains("pub fn run() -> i32"));
+    assert!(decls.contains("let code = main(ctx);"));
+}
+
+// a crate built the way a build script sets it up links the runtime once and
+// runs a program; cc stands in for the aheui compiler and llc here
+#[test]
+fn test_link() {
+    let dir = env::temp_dir().join("aheui_build_test_link");
+    let _ = fs::create_dir(&dir);
+    let program = "
+        #include <stdint.h>
+        void aheui_push(void *ctx, int8_t idx, int32_t v);
+        int32_t aheui_pop(void *ctx, int8_t idx);
+        void aheui_putint(void *ctx, int32_t v);
+        int32_t aheui_halt(void *ctx, int8_t idx);
+        int32_t aheui_hello(void *ctx) {
+            aheui_push(ctx, 0, 42);
+            aheui_push(ctx, 0, 3);
+            aheui_putint(ctx, aheui_pop(ctx, 0));
+            return aheui_halt(ctx, 0);
+        }
+    ";
+    File::create(dir.join("hello.c")).unwrap().write_all(program.as_bytes()).unwrap();
+    run(Command::new("cc").arg("-c").arg("-fPIC").arg("-o").arg(dir.join("hello.o"))
+                          .arg(dir.join("hello.c")));
+    let _ = fs::remove_file(dir.join("libaheui_programs.a"));
+    run(Command::new("ar").arg("crs").arg(dir.join("libaheui_programs.a"))
+                          .arg(dir.join("hello.o")));
+    build_runtime(&dir);
+
+    let programs = vec!(Program {
+        path: PathBuf::from("hello.aheui"),
+        name: "hello".to_string(),
+        symbol: "aheui_hello".to_string(),
+    });
+    let main = format!("{}\nfn main() {{ std::process::exit(hello::run()); }}\n",
+                       declarations(&programs, true));
+    File::create(dir.join("main.rs")).unwrap().write_all(main.as_bytes()).unwrap();
+    run(Command::new(tool("RUSTC", "rustc"))
+            .arg("-o").arg(dir.join("main"))
+            .arg("-L").arg(format!("native={}", dir.display()))
+            .arg("-L").arg(format!("crate={}", dir.display()))
+            .arg("-l").arg("static=aheui_programs")
+            .arg(dir.join("main.rs")));
+
+    let output = Command::new(dir.join("main")).output().unwrap();
+    assert!(output.stdout == b"3");
+    assert!(output.status.code() == Some(42));
+}
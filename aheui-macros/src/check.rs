@@ -0,0 +1,75 @@
+// Compile-time checks for the macros. Each problem is reported against the
+// cell (x, y) that causes it, so the expansion can point at the syllable.
+
+use std::collections::HashSet;
+
+use {Hangul, Flow, move_pos};
+
+// Follows the program from the start for as long as the path does not depend
+// on a stored value (up to the first ㅊ or ㅎ, or until it starts to loop),
+// counting values per storage. Popping from a storage that is known to be
+// empty there is an underflow on every run.
+fn guaranteed_underflow(code: &[Vec<Hangul>]) -> Option<(usize, usize)> {
+    use Cho::*;
+
+    if code.is_empty() || code[0].is_empty() {
+        return None;
+    }
+
+    let mut depth = [0usize; 28];
+    let mut seen = HashSet::new();
+    let (mut x, mut y, mut flow, mut cur) = (0, 0, Flow::Down, 0);
+    while seen.insert((x, y, flow as usize, cur)) {
+        let h = &code[y][x];
+        let need = match h.cho {
+            cㄴ | cㄷ | cㄸ | cㄹ | cㅈ | cㅌ | cㅍ => 2,
+            cㅁ | cㅃ | cㅆ | cㅊ => 1,
+            _ => 0,
+        };
+        if depth[cur] < need {
+            return Some((x, y));
+        }
+
+        match h.cho {
+            cㄴ | cㄷ | cㄸ | cㄹ | cㅈ | cㅌ | cㅁ => depth[cur] -= 1,
+            cㅂ | cㅃ => depth[cur] += 1,
+            cㅅ => cur = h.jong as usize,
+            cㅆ => {
+                depth[cur] -= 1;
+                depth[h.jong as usize] += 1;
+            },
+            cㅊ | cㅎ => return None,
+            _ => {},
+        }
+
+        flow = match Flow::from_jung(h.jung) {
+            Some(f) => f,
+            None => flow.reflect(h.jung),
+        };
+        let (nx, ny) = move_pos(code, x, y, h.jung, flow);
+        x = nx;
+        y = ny;
+    }
+    None
+}
+
+pub fn check(code: &[Vec<Hangul>]) -> Vec<(usize, usize, String)> {
+    let mut errs = Vec::new();
+    if let Some((x, y)) = guaranteed_underflow(code) {
+        errs.push((x, y, "storage underflow".to_string()));
+    }
+    errs
+}
+
+#[test]
+fn test_check() {
+    use parse_code;
+
+    assert!(check(&parse_code("밤희")).is_empty());
+    assert!(check(&parse_code("반받나망희")).is_empty());
+    assert!(check(&parse_code("밤차망희")).is_empty());
+    assert!(check(&parse_code("망희")) == vec!((0, 0, "storage underflow".to_string())));
+    assert!(check(&parse_code("밤다희")) == vec!((1, 0, "storage underflow".to_string())));
+    assert!(check(&parse_code("밤상맘희")) == vec!((2, 0, "storage underflow".to_string())));
+    assert!(check(&parse_code("밤치희")).is_empty());
+}
@@ -0,0 +1,148 @@
+// `aheui!` and `aheui_file!`: compile Aheui into Rust at build time.
+//
+//     #![feature(plugin)]
+//     #![plugin(aheui_macros)]
+//
+//     let hello = aheui! {
+//         밤밣따빠밣밟따뿌
+//         ...
+//     };
+//     let ret = hello(&mut std::io::stdin(), &mut std::io::stdout());
+//
+// Both expand to the `run` function of the `--emit=rust` backend, so the
+// result is `fn(&mut Read, &mut Write) -> i32`, returning 125 after reporting
+// an error at run time.
+
+#![feature(non_ascii_idents, custom_derive, plugin, plugin_registrar, rustc_private)]
+#![plugin(num_macros)]
+#![allow(non_camel_case_types)]
+
+extern crate num;
+extern crate rustc;
+extern crate syntax;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use syntax::ast::TokenTree;
+use syntax::codemap::{Span, BytePos, mk_sp};
+use syntax::ext::base::{ExtCtxt, MacResult, MacEager, DummyResult};
+use syntax::ext::base::get_single_str_from_tts;
+use syntax::parse;
+
+use rustc::plugin::Registry;
+
+#[path = "../../src/hangul.rs"]
+pub mod hangul;
+#[path = "../../src/rust.rs"]
+mod rust;
+mod check;
+
+pub use hangul::{Cho, Jung, Jong, Flow, Hangul, next_pos, move_pos, parse_code};
+
+#[cfg(test)]
+fn conformance_cases() -> Vec<(String, String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("..").join("tests").join("conformance");
+    hangul::read_cases(&dir)
+}
+
+fn expand(cx: &mut ExtCtxt, code: &[Vec<Hangul>]) -> Box<MacResult + 'static> {
+    let src = format!("{{\n#[allow({})]\nmod aheui_program {{\n{}}}\naheui_program::run\n}}",
+                      rust::ALLOW, rust::gen_rust_items(code));
+    let expr = parse::parse_expr_from_source_str("<aheui!>".to_string(), src,
+                                                 cx.cfg(), cx.parse_sess());
+    MacEager::expr(expr)
+}
+
+// The program is taken from the source text between the first and the last
+// token, so line breaks survive tokenizing. Continuation lines are dedented
+// by the column of the first token.
+fn expand_aheui(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Box<MacResult + 'static> {
+    if tts.is_empty() {
+        cx.span_err(sp, "aheui!: empty program");
+        return DummyResult::any(sp);
+    }
+    let lo = tts[0].get_span().lo;
+    let hi = tts[tts.len() - 1].get_span().hi;
+    let src = match cx.codemap().span_to_snippet(mk_sp(lo, hi)) {
+        Ok(s) => s,
+        Err(_) => {
+            cx.span_err(sp, "aheui!: cannot read the program source");
+            return DummyResult::any(sp);
+        },
+    };
+    let indent = cx.codemap().lookup_char_pos(lo).col.0;
+
+    let mut code = Vec::new();
+    let mut offsets = Vec::new();
+    let mut start = 0;
+    for (y, line) in src.split('\n').enumerate() {
+        let line_start = start;
+        start += line.len() + 1;
+        let line = line.trim_right_matches('\r');
+
+        let mut cells = Vec::new();
+        let mut offs = Vec::new();
+        let mut skip = if y == 0 { 0 } else { indent };
+        for (i, c) in line.char_indices() {
+            if skip > 0 && c.is_whitespace() {
+                skip -= 1;
+                continue;
+            }
+            skip = 0;
+            cells.push(Hangul::from_char(c));
+            offs.push((line_start + i, c.len_utf8()));
+        }
+        code.push(cells);
+        offsets.push(offs);
+    }
+
+    let errs = check::check(&code);
+    if !errs.is_empty() {
+        for &(x, y, ref msg) in errs.iter() {
+            let (off, len) = offsets[y][x];
+            let cell_lo = BytePos(lo.0 + off as u32);
+            let cell_sp = mk_sp(cell_lo, BytePos(cell_lo.0 + len as u32));
+            cx.span_err(cell_sp, &format!("aheui!: {}", msg));
+        }
+        return DummyResult::any(sp);
+    }
+    expand(cx, &code)
+}
+
+// The path is relative to the file containing the invocation, like
+// `include_str!`.
+fn expand_aheui_file(cx: &mut ExtCtxt, sp: Span, tts: &[TokenTree]) -> Box<MacResult + 'static> {
+    let file = match get_single_str_from_tts(cx, sp, tts, "aheui_file!") {
+        Some(f) => f,
+        None => return DummyResult::any(sp),
+    };
+    let caller = cx.codemap().span_to_filename(sp);
+    let path = match Path::new(&caller).parent() {
+        Some(dir) => dir.join(&file),
+        None => Path::new(&file).to_path_buf(),
+    };
+
+    let mut src = String::new();
+    if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_string(&mut src)) {
+        cx.span_err(sp, &format!("aheui_file!: couldn't read {}: {}", path.display(), e));
+        return DummyResult::any(sp);
+    }
+
+    let code = parse_code(&src);
+    let errs = check::check(&code);
+    if !errs.is_empty() {
+        for &(x, y, ref msg) in errs.iter() {
+            cx.span_err(sp, &format!("aheui_file!: {}:{}:{}: {}", file, y + 1, x + 1, msg));
+        }
+        return DummyResult::any(sp);
+    }
+    expand(cx, &code)
+}
+
+#[plugin_registrar]
+pub fn plugin_registrar(reg: &mut Registry) {
+    reg.register_macro("aheui", expand_aheui);
+    reg.register_macro("aheui_file", expand_aheui_file);
+}
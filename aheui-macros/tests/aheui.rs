@@ -0,0 +1,46 @@
+#![feature(plugin)]
+#![plugin(aheui_macros)]
+
+#[test]
+fn test_aheui() {
+    let prog = aheui! {
+        반받다망희
+    };
+    let mut out = Vec::new();
+    assert!(prog(&mut &b""[..], &mut out) == 0);
+    assert!(out == b"5");
+}
+
+#[test]
+fn test_aheui_io() {
+    let echo = aheui! {
+        방망밯맣희
+    };
+    let mut out = Vec::new();
+    assert!(echo(&mut " 42\n한글\n".as_bytes(), &mut out) == 0);
+    assert!(out == "42한".as_bytes());
+
+    let mut out = Vec::new();
+    assert!(echo(&mut &b""[..], &mut out) == 125);
+    assert!(out == b"-1");
+}
+
+#[test]
+fn test_aheui_error() {
+    // errors that depend on the input are left to run time
+    let prog = aheui! {
+        반받다망방망희
+    };
+    let mut out = Vec::new();
+    assert!(prog(&mut &b"x\n"[..], &mut out) == 125);
+    assert!(out == b"5");
+}
+
+#[test]
+fn test_aheui_file() {
+    // README.md is itself the hello world program
+    let hello = aheui_file!("../../README.md");
+    let mut out = Vec::new();
+    assert!(hello(&mut &b""[..], &mut out) == 0);
+    assert!(out == b"Hello, world!\n");
+}
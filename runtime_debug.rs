@@ -0,0 +1,27 @@
+#![feature(rustc_private)]
+
+#[macro_use] extern crate log;
+
+#[path = "src/rt.rs"]
+pub mod rt;
+#[path = "src/debugger.rs"]
+mod debugger;
+
+extern "C" {
+    fn aheui_main(ctx: *mut rt::AheuiRt) -> i32;
+}
+
+// stops at a prompt on every cell of a program compiled with --trace
+#[no_mangle]
+pub extern "C" fn aheui_trace(ctx: *mut rt::AheuiRt, x: i32, y: i32, c: char, flow: i8,
+                              idx: i8) {
+    debugger::aheui_trace(ctx, x, y, c, flow, idx)
+}
+
+fn main() {
+    rt::install_signals();
+    let ctx = rt::aheui_ctx_new();
+    let code = unsafe { aheui_main(ctx) };
+    rt::aheui_ctx_free(ctx);
+    std::process::exit(code);
+}
@@ -0,0 +1,282 @@
+// Bytecode for the VM in vm.rs. Every reachable (cell, incoming flow) state
+// becomes one fixed-size instruction with its successors already resolved
+// to instruction indices. Instruction 0 is the entry: (0, 0) moving down.
+//
+// `.ahbc` layout, little endian: the magic "AHBC", version: u32,
+// count: u32, then `count` instructions of INSTR_SIZE bytes each:
+// op, arg, flow, 0, next: u32, alt: u32, x: u16, y: u16, character: u32.
+
+use std::collections::HashMap;
+
+use super::{Hangul, Flow, next_pos, move_pos};
+
+pub const MAGIC: &'static [u8] = b"AHBC";
+pub const VERSION: u32 = 2;
+const HEADER_SIZE: usize = 12;
+const INSTR_SIZE: usize = 20;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Op {
+    Nop, Div, Add, Mul, Rem, Cmp, Sub,
+    Pop, PutInt, PutChar, Push, GetInt, GetChar,
+    Dup, Swap, Select, Move, Branch, Halt,
+}
+
+const OPS: [Op; 19] = [
+    Op::Nop, Op::Div, Op::Add, Op::Mul, Op::Rem, Op::Cmp, Op::Sub,
+    Op::Pop, Op::PutInt, Op::PutChar, Op::Push, Op::GetInt, Op::GetChar,
+    Op::Dup, Op::Swap, Op::Select, Op::Move, Op::Branch, Op::Halt,
+];
+
+// `arg` is the pushed value for Push and the storage for Select/Move. `flow`
+// is the flow after the instruction, which the successors are entered with.
+// Only Branch uses `alt`, taken when the popped value is zero. `c` is the
+// character of the cell, for error messages.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct Instr {
+    pub op: Op,
+    pub arg: u8,
+    pub flow: u8,
+    pub next: u32,
+    pub alt: u32,
+    pub x: u16,
+    pub y: u16,
+    pub c: char,
+}
+
+fn op_of(h: &Hangul) -> (Op, u8) {
+    use Cho::*;
+    use Jong::*;
+
+    match h.cho {
+        cㄴ => (Op::Div, 0),
+        cㄷ => (Op::Add, 0),
+        cㄸ => (Op::Mul, 0),
+        cㄹ => (Op::Rem, 0),
+        cㅈ => (Op::Cmp, 0),
+        cㅌ => (Op::Sub, 0),
+        cㅁ => match h.jong {
+            jㅇ => (Op::PutInt, 0),
+            jㅎ => (Op::PutChar, 0),
+            _ => (Op::Pop, 0),
+        },
+        cㅂ => match h.jong {
+            jㅇ => (Op::GetInt, 0),
+            jㅎ => (Op::GetChar, 0),
+            _ => (Op::Push, h.jong.val() as u8),
+        },
+        cㅃ => (Op::Dup, 0),
+        cㅍ => (Op::Swap, 0),
+        cㅅ => (Op::Select, h.jong as u8),
+        cㅆ => (Op::Move, h.jong as u8),
+        cㅊ => (Op::Branch, 0),
+        cㅎ => (Op::Halt, 0),
+        cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => (Op::Nop, 0),
+    }
+}
+
+fn intern(states: &mut Vec<(usize, usize, Flow)>, index: &mut HashMap<(usize, usize, u8), u32>,
+          state: (usize, usize, Flow)) -> u32 {
+    let (x, y, flow) = state;
+    let key = (x, y, flow as u8);
+    if let Some(&i) = index.get(&key) {
+        return i;
+    }
+    let i = states.len() as u32;
+    states.push(state);
+    index.insert(key, i);
+    i
+}
+
+// positions are stored as u16, so wider or taller programs cannot be encoded
+pub fn compile(code: &[Vec<Hangul>]) -> Result<Vec<Instr>, String> {
+    let max = ::std::u16::MAX as usize + 1;
+    if code.len() > max || code.iter().any(|line| line.len() > max) {
+        return Err(format!("programs wider or taller than {} cells cannot be compiled", max));
+    }
+    if code.is_empty() || code[0].is_empty() {
+        return Err("the program has no cell at (0, 0)".to_string());
+    }
+
+    let mut states = vec!((0, 0, Flow::Down));
+    let mut index = HashMap::new();
+    index.insert((0, 0, Flow::Down as u8), 0);
+
+    let mut prog = Vec::new();
+    let mut i = 0;
+    while i < states.len() {
+        let (x, y, flow) = states[i];
+        let h = &code[y][x];
+        let (op, arg) = op_of(h);
+
+        let mut ins = Instr {
+            op: op, arg: arg, flow: flow as u8,
+            next: 0, alt: 0, x: x as u16, y: y as u16, c: h.c,
+        };
+        if op != Op::Halt {
+            match Flow::from_jung(h.jung) {
+                Some(f) => {
+                    ins.flow = f as u8;
+                    ins.next = intern(&mut states, &mut index, {
+                        let (nx, ny) = move_pos(code, x, y, h.jung, f);
+                        (nx, ny, f)
+                    });
+                    if op == Op::Branch {
+                        // the reversed move keeps the unreversed flow
+                        ins.alt = intern(&mut states, &mut index, {
+                            let (rx, ry) = move_pos(code, x, y, h.jung, f.reverse());
+                            (rx, ry, f)
+                        });
+                    }
+                },
+                None => {
+                    let f = flow.reflect(h.jung);
+                    ins.flow = f as u8;
+                    ins.next = intern(&mut states, &mut index, {
+                        let (nx, ny) = next_pos(code, x, y, f);
+                        (nx, ny, f)
+                    });
+                    if op == Op::Branch {
+                        ins.alt = intern(&mut states, &mut index, {
+                            let (rx, ry) = next_pos(code, x, y, f.reverse());
+                            (rx, ry, f)
+                        });
+                    }
+                },
+            }
+        }
+        prog.push(ins);
+        i += 1;
+    }
+    Ok(prog)
+}
+
+fn put_u16(out: &mut Vec<u8>, v: u16) {
+    out.push(v as u8);
+    out.push((v >> 8) as u8);
+}
+
+fn put_u32(out: &mut Vec<u8>, v: u32) {
+    put_u16(out, v as u16);
+    put_u16(out, (v >> 16) as u16);
+}
+
+fn get_u16(b: &[u8], at: usize) -> u16 {
+    b[at] as u16 | (b[at + 1] as u16) << 8
+}
+
+fn get_u32(b: &[u8], at: usize) -> u32 {
+    get_u16(b, at) as u32 | (get_u16(b, at + 2) as u32) << 16
+}
+
+pub fn encode(prog: &[Instr]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + prog.len() * INSTR_SIZE);
+    out.extend(MAGIC.iter().cloned());
+    put_u32(&mut out, VERSION);
+    put_u32(&mut out, prog.len() as u32);
+    for ins in prog.iter() {
+        out.push(ins.op as u8);
+        out.push(ins.arg);
+        out.push(ins.flow);
+        out.push(0);
+        put_u32(&mut out, ins.next);
+        put_u32(&mut out, ins.alt);
+        put_u16(&mut out, ins.x);
+        put_u16(&mut out, ins.y);
+        put_u32(&mut out, ins.c as u32);
+    }
+    out
+}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.len() >= MAGIC.len() && &bytes[..MAGIC.len()] == MAGIC
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Instr>, String> {
+    if bytes.len() < HEADER_SIZE || !is_bytecode(bytes) {
+        return Err("not an aheui bytecode file".to_string());
+    }
+    let version = get_u32(bytes, 4);
+    if version != VERSION {
+        return Err(format!("unsupported bytecode version {} (expected {})", version, VERSION));
+    }
+    let count = get_u32(bytes, 8) as usize;
+    if count == 0 || bytes.len() != HEADER_SIZE + count * INSTR_SIZE {
+        return Err("truncated bytecode".to_string());
+    }
+
+    let mut prog = Vec::with_capacity(count);
+    for i in 0..count {
+        let at = HEADER_SIZE + i * INSTR_SIZE;
+        let op = match OPS.get(bytes[at] as usize) {
+            Some(&op) => op,
+            None => return Err(format!("instruction {}: unknown op {}", i, bytes[at])),
+        };
+        let c = match ::std::char::from_u32(get_u32(bytes, at + 16)) {
+            Some(c) => c,
+            None => return Err(format!("instruction {}: bad character", i)),
+        };
+        let ins = Instr {
+            op: op,
+            arg: bytes[at + 1],
+            flow: bytes[at + 2],
+            next: get_u32(bytes, at + 4),
+            alt: get_u32(bytes, at + 8),
+            x: get_u16(bytes, at + 12),
+            y: get_u16(bytes, at + 14),
+            c: c,
+        };
+        if ins.flow > 3 || ((op == Op::Select || op == Op::Move) && ins.arg > 27) {
+            return Err(format!("instruction {}: bad operand", i));
+        }
+        if op != Op::Halt && ins.next as usize >= count {
+            return Err(format!("instruction {}: successor out of range", i));
+        }
+        if op == Op::Branch && ins.alt as usize >= count {
+            return Err(format!("instruction {}: successor out of range", i));
+        }
+        prog.push(ins);
+    }
+    Ok(prog)
+}
+
+#[test]
+fn test_bytecode() {
+    use super::parse_code;
+
+    // 밤 is entered once moving down; 희 once moving right
+    let prog = compile(&parse_code("밤희")).unwrap();
+    assert!(prog.len() == 2);
+    assert!(prog[0].op == Op::Push && prog[0].arg == 4 && prog[0].next == 1);
+    assert!(prog[1].op == Op::Halt && (prog[1].x, prog[1].y, prog[1].c) == (1, 0, '희'));
+
+    // 이 bounces back, so 아 is entered both moving down and moving left
+    let prog = compile(&parse_code("아이희")).unwrap();
+    assert!(prog.len() == 3);
+    assert!((prog[2].x, prog[2].y, prog[2].flow) == (0, 0, Flow::Right as u8));
+
+    // 치 turns the flow back left; a zero moves right but keeps it
+    let ch = compile(&parse_code("바치희")).unwrap();
+    assert!(ch[1].op == Op::Branch && ch[1].flow == Flow::Left as u8);
+    assert!((ch[ch[1].next as usize].x, ch[ch[1].alt as usize].x) == (0, 2));
+
+    assert!(compile(&parse_code("")).is_err());
+    let wide: String = (0..70000).map(|_| '아').collect();
+    assert!(compile(&parse_code(&wide)).is_err());
+
+    let bytes = encode(&prog);
+    assert!(bytes.len() == HEADER_SIZE + 3 * INSTR_SIZE);
+    assert!(decode(&bytes) == Ok(prog.clone()));
+
+    assert!(decode(b"AHB").is_err());
+    assert!(decode(&bytes[..bytes.len() - 1]).is_err());
+    let mut bad = bytes.clone();
+    bad[4] = 1;
+    assert!(decode(&bad).is_err());
+    let mut bad = bytes.clone();
+    bad[HEADER_SIZE + 4] = 9;
+    assert!(decode(&bad).is_err());
+    let mut bad = bytes.clone();
+    bad[HEADER_SIZE + 18] = 0x11;
+    assert!(decode(&bad).is_err());
+}
@@ -0,0 +1,432 @@
+// C backend: lowers the grid into a single translation unit with one label
+// per cell, following the same per-cell model as `AheuiBlock::gen_bb`.
+
+use std::fmt::Write;
+
+use super::{Hangul, Flow, next_pos, move_pos};
+use rt::ERROR_EXIT;
+
+// the storages, and input and output following the rules of rt.rs: input is
+// read a line at a time, the end of input reads as -1, and failures exit
+// with AHEUI_ERROR_EXIT
+const PRELUDE: &'static str = r#"#include <ctype.h>
+#include <errno.h>
+#include <stdarg.h>
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+/* ring buffer; the top of a stack is the back, the top of the queue (21)
+   is the front */
+struct aheui_storage {
+    int32_t *buf;
+    size_t head;
+    size_t len;
+    size_t cap;
+};
+
+static struct aheui_storage aheui_st[28];
+
+static const int8_t aheui_fl[4][4] = {
+    {0, 1, 2, 3},
+    {1, 0, 2, 3},
+    {0, 1, 3, 2},
+    {1, 0, 3, 2},
+};
+
+static void aheui_fail(const char *fmt, ...) {
+    va_list ap;
+    fflush(stdout);
+    fputs("aheui: ", stderr);
+    va_start(ap, fmt);
+    vfprintf(stderr, fmt, ap);
+    va_end(ap);
+    fputc('\n', stderr);
+    exit(AHEUI_ERROR_EXIT);
+}
+
+static struct aheui_storage *aheui_get(int8_t idx) {
+    if (idx == 27) {
+        aheui_fail("the extension storage (27) is not supported");
+    }
+    return &aheui_st[idx];
+}
+
+static struct aheui_storage *aheui_get_n(int8_t idx, size_t n) {
+    struct aheui_storage *s = aheui_get(idx);
+    if (s->len < n) {
+        if (n == 1) {
+            aheui_fail("storage %d is empty", idx);
+        }
+        aheui_fail("storage %d has fewer than %d values", idx, (int)n);
+    }
+    return s;
+}
+
+static void aheui_grow(struct aheui_storage *s) {
+    size_t cap = s->cap ? s->cap * 2 : 16;
+    int32_t *buf = malloc(cap * sizeof(int32_t));
+    size_t i;
+    if (!buf) {
+        aheui_fail("out of memory");
+    }
+    for (i = 0; i < s->len; i++) {
+        buf[i] = s->buf[(s->head + i) % s->cap];
+    }
+    free(s->buf);
+    s->buf = buf;
+    s->head = 0;
+    s->cap = cap;
+}
+
+static int32_t *aheui_at(struct aheui_storage *s, size_t i) {
+    return &s->buf[(s->head + i) % s->cap];
+}
+
+static int32_t *aheui_top(int8_t idx, struct aheui_storage *s, size_t i) {
+    return idx == 21 ? aheui_at(s, i) : aheui_at(s, s->len - 1 - i);
+}
+
+static void aheui_push(int8_t idx, int32_t v) {
+    struct aheui_storage *s = aheui_get(idx);
+    if (s->len == s->cap) {
+        aheui_grow(s);
+    }
+    s->len++;
+    *aheui_at(s, s->len - 1) = v;
+}
+
+static int32_t aheui_pop(int8_t idx) {
+    struct aheui_storage *s = aheui_get_n(idx, 1);
+    int32_t v = *aheui_top(idx, s, 0);
+    if (idx == 21) {
+        s->head = (s->head + 1) % s->cap;
+    }
+    s->len--;
+    return v;
+}
+
+static void aheui_dup(int8_t idx) {
+    struct aheui_storage *s = aheui_get_n(idx, 1);
+    int32_t v = *aheui_top(idx, s, 0);
+    if (s->len == s->cap) {
+        aheui_grow(s);
+    }
+    if (idx == 21) {
+        s->head = (s->head + s->cap - 1) % s->cap;
+    }
+    s->len++;
+    *aheui_top(idx, s, 0) = v;
+}
+
+static void aheui_swap(int8_t idx) {
+    struct aheui_storage *s = aheui_get_n(idx, 2);
+    int32_t *a = aheui_top(idx, s, 0);
+    int32_t *b = aheui_top(idx, s, 1);
+    int32_t v = *a;
+    *a = *b;
+    *b = v;
+}
+
+static int32_t aheui_halt(int8_t idx) {
+    struct aheui_storage *s = aheui_get(idx);
+    return s->len ? aheui_pop(idx) : 0;
+}
+
+static int32_t aheui_div(int32_t a, int32_t b, int rem) {
+    if (a == 0) {
+        aheui_fail("division by zero");
+    }
+    return (int32_t)(rem ? (uint32_t)b % (uint32_t)a : (uint32_t)b / (uint32_t)a);
+}
+
+/* the next line of input with its newline, or NULL at the end of input */
+static char *aheui_read_line(void) {
+    static char *buf;
+    static size_t cap;
+    size_t len = 0;
+    int c;
+    fflush(stdout);
+    while ((c = getchar()) != EOF) {
+        if (len + 2 > cap) {
+            cap = cap ? cap * 2 : 64;
+            buf = realloc(buf, cap);
+            if (!buf) {
+                aheui_fail("out of memory");
+            }
+        }
+        buf[len++] = (char)c;
+        if (c == '\n') {
+            break;
+        }
+    }
+    if (len == 0) {
+        return NULL;
+    }
+    buf[len] = '\0';
+    return buf;
+}
+
+/* the first character of a line */
+static int32_t aheui_getchar(void) {
+    const unsigned char *s = (const unsigned char *)aheui_read_line();
+    int n, i;
+    int32_t v;
+    if (!s) {
+        return -1;
+    }
+    if (s[0] < 0x80) {
+        return s[0];
+    }
+    n = s[0] >= 0xf0 ? 3 : s[0] >= 0xe0 ? 2 : 1;
+    v = s[0] & (0x3f >> n);
+    for (i = 1; i <= n; i++) {
+        if ((s[i] & 0xc0) != 0x80) {
+            aheui_fail("cannot read input: invalid UTF-8");
+        }
+        v = (v << 6) | (s[i] & 0x3f);
+    }
+    return v;
+}
+
+static void aheui_putchar(int32_t c) {
+    if (c < 0 || c > 0x10ffff || (c >= 0xd800 && c < 0xe000)) {
+        aheui_fail("%d is not a character", c);
+    }
+    if (c < 0x80) {
+        putchar(c);
+    } else if (c < 0x800) {
+        putchar(0xc0 | (c >> 6));
+        putchar(0x80 | (c & 0x3f));
+    } else if (c < 0x10000) {
+        putchar(0xe0 | (c >> 12));
+        putchar(0x80 | ((c >> 6) & 0x3f));
+        putchar(0x80 | (c & 0x3f));
+    } else {
+        putchar(0xf0 | (c >> 18));
+        putchar(0x80 | ((c >> 12) & 0x3f));
+        putchar(0x80 | ((c >> 6) & 0x3f));
+        putchar(0x80 | (c & 0x3f));
+    }
+}
+
+/* a whole line, with spaces around it */
+static int32_t aheui_getint(void) {
+    char *s = aheui_read_line();
+    char *end;
+    size_t len;
+    long v;
+    if (!s) {
+        return -1;
+    }
+    while (isspace((unsigned char)*s)) {
+        s++;
+    }
+    len = strlen(s);
+    while (len && isspace((unsigned char)s[len - 1])) {
+        s[--len] = '\0';
+    }
+    errno = 0;
+    v = strtol(s, &end, 10);
+    if (!len || *end || errno || v < INT32_MIN || v > INT32_MAX) {
+        aheui_fail("bad input: \"%s\" is not an integer", s);
+    }
+    return (int32_t)v;
+}
+
+static void aheui_putint(int32_t i) {
+    printf("%d", i);
+}
+"#;
+
+fn label(x: usize, y: usize) -> String {
+    format!("aheui_bb_{}_{}", x, y)
+}
+
+fn gen_cell(out: &mut String, code: &[Vec<Hangul>], x: usize, y: usize) {
+    use Cho::*;
+    use Jung::*;
+    use Jong::*;
+
+    let h = &code[y][x];
+    writeln!(out, "{}: /* {} */", label(x, y), h.c).unwrap();
+    out.push_str("    comp = 0;\n");
+
+    let binop = |out: &mut String, expr: &str| {
+        out.push_str("    a = aheui_pop(cur);\n");
+        out.push_str("    b = aheui_pop(cur);\n");
+        writeln!(out, "    aheui_push(cur, {});", expr).unwrap();
+    };
+
+    match h.cho {
+        cㄴ => binop(out, "aheui_div(a, b, 0)"),
+        cㄷ => binop(out, "(int32_t)((uint32_t)a + (uint32_t)b)"),
+        cㄸ => binop(out, "(int32_t)((uint32_t)a * (uint32_t)b)"),
+        cㄹ => binop(out, "aheui_div(a, b, 1)"),
+        cㅁ => {
+            out.push_str("    a = aheui_pop(cur);\n");
+            match h.jong {
+                jㅇ => out.push_str("    aheui_putint(a);\n"),
+                jㅎ => out.push_str("    aheui_putchar(a);\n"),
+                _ => {},
+            }
+        },
+        cㅂ => {
+            match h.jong {
+                jㅇ => out.push_str("    aheui_push(cur, aheui_getint());\n"),
+                jㅎ => out.push_str("    aheui_push(cur, aheui_getchar());\n"),
+                _ => writeln!(out, "    aheui_push(cur, {});", h.jong.val()).unwrap(),
+            }
+        },
+        cㅃ => out.push_str("    aheui_dup(cur);\n"),
+        cㅅ => writeln!(out, "    cur = {};", h.jong as usize).unwrap(),
+        cㅆ => writeln!(out, "    aheui_push({}, aheui_pop(cur));", h.jong as usize).unwrap(),
+        cㅈ => binop(out, "(uint32_t)a <= (uint32_t)b"),
+        cㅊ => out.push_str("    comp = aheui_pop(cur) == 0;\n"),
+        cㅌ => binop(out, "(int32_t)((uint32_t)b - (uint32_t)a)"),
+        cㅍ => out.push_str("    aheui_swap(cur);\n"),
+        cㅎ => {
+            out.push_str("    return aheui_halt(cur);\n");
+            return;
+        },
+        cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+    }
+
+    match h.jung {
+        ㅏ | ㅓ | ㅗ | ㅜ | ㅑ | ㅕ | ㅛ | ㅠ => {
+            let flow = Flow::from_jung(h.jung).unwrap();
+            let (nx, ny) = move_pos(code, x, y, h.jung, flow);
+            let (rx, ry) = move_pos(code, x, y, h.jung, flow.reverse());
+            writeln!(out, "    flow = {};", flow as usize).unwrap();
+            writeln!(out, "    if (comp) goto {};", label(rx, ry)).unwrap();
+            writeln!(out, "    goto {};", label(nx, ny)).unwrap();
+        },
+        _ => {
+            let j = match h.jung {
+                ㅣ => 1,
+                ㅡ => 2,
+                ㅢ => 3,
+                _ => 0,
+            };
+            writeln!(out, "    flow = aheui_fl[{}][flow];", j).unwrap();
+
+            let flows = [Flow::Left, Flow::Right, Flow::Up, Flow::Down];
+            if h.cho == cㅊ {
+                // a zero reverses the move but not the flow; flows pair up
+                // as left and right, up and down
+                out.push_str("    switch (comp ? flow ^ 1 : flow) {\n");
+            } else {
+                out.push_str("    switch (flow) {\n");
+            }
+            for (i, f) in flows.iter().enumerate() {
+                let (nx, ny) = next_pos(code, x, y, *f);
+                if i < 3 {
+                    writeln!(out, "    case {}: goto {};", i, label(nx, ny)).unwrap();
+                } else {
+                    writeln!(out, "    default: goto {};", label(nx, ny)).unwrap();
+                }
+            }
+            out.push_str("    }\n");
+        },
+    }
+}
+
+pub fn gen_c(code: &[Vec<Hangul>], in_fn: &str, fn_name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "/* generated by aheui-llvm from {} */", in_fn.replace("*/", "* /")).unwrap();
+    writeln!(out, "#define AHEUI_ERROR_EXIT {}", ERROR_EXIT).unwrap();
+    out.push_str(PRELUDE);
+
+    writeln!(out, "\nint32_t {}(void) {{", fn_name).unwrap();
+    out.push_str("    int8_t flow = 3;\n");
+    out.push_str("    int8_t cur = 0;\n");
+    out.push_str("    int comp = 0;\n");
+    out.push_str("    int32_t a, b;\n");
+    out.push_str("    (void)comp; (void)a; (void)b;\n");
+    writeln!(out, "    goto {};", label(0, 0)).unwrap();
+
+    for (y, line) in code.iter().enumerate() {
+        for x in 0..line.len() {
+            gen_cell(&mut out, code, x, y);
+        }
+    }
+    out.push_str("}\n");
+
+    writeln!(out, "\n#ifndef AHEUI_NO_MAIN").unwrap();
+    out.push_str("int main(void) {\n");
+    writeln!(out, "    int32_t ret = {}();", fn_name).unwrap();
+    out.push_str("    fflush(stdout);\n");
+    out.push_str("    return ret;\n");
+    out.push_str("}\n");
+    out.push_str("#endif\n");
+    out
+}
+
+#[test]
+fn test_c_conformance() {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::process::Command;
+    use super::{conformance_cases, parse_code};
+
+    let dir = env::temp_dir();
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let src = gen_c(&parse_code(&code), &name, "aheui_main");
+        let c_fn = dir.join(format!("aheui_c_test_{}.c", name));
+        let exe_fn = dir.join(format!("aheui_c_test_{}", name));
+        File::create(&c_fn).unwrap().write_all(src.as_bytes()).unwrap();
+
+        let status = Command::new("cc").arg("-O1").arg("-o").arg(&exe_fn).arg(&c_fn)
+                                       .status().unwrap();
+        assert!(status.success(), "cc failed for {}", name);
+
+        let output = Command::new(&exe_fn).output().unwrap();
+        assert!(String::from_utf8(output.stdout).unwrap() == expected,
+                "unexpected output from {}", name);
+    }
+}
+
+#[cfg(test)]
+pub fn compile_and_run(name: &str, code: &str, input: &str) -> (String, i32) {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use super::parse_code;
+
+    let dir = env::temp_dir();
+    let src = gen_c(&parse_code(code), name, "aheui_main");
+    let c_fn = dir.join(format!("aheui_c_io_{}.c", name));
+    let exe_fn = dir.join(format!("aheui_c_io_{}", name));
+    File::create(&c_fn).unwrap().write_all(src.as_bytes()).unwrap();
+    let status = Command::new("cc").arg("-o").arg(&exe_fn).arg(&c_fn).status().unwrap();
+    assert!(status.success(), "cc failed for {}", name);
+
+    let mut child = Command::new(&exe_fn).stdin(Stdio::piped()).stdout(Stdio::piped())
+                                         .stderr(Stdio::piped()).spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), output.status.code().unwrap())
+}
+
+#[test]
+fn test_c_io() {
+    let cases: Vec<(&str, &str, &str, &str, i32)> = vec!(
+        ("int", "방망희", "  -42\n", "-42", 0),
+        ("char", "밯맣희", "한글\n", "한", 0),
+        ("lines", "밯맣밯맣희", "가나\n다\n", "가다", 0),
+        ("exit", "밯희", "A", "", 65),
+        ("eof", "밯망방망희", "", "-1-1", 0),
+        ("bad", "방망희", "4two\n", "", ERROR_EXIT),
+        ("empty", "망희", "", "", ERROR_EXIT),
+        // ㅢ sends the flow back up to read again until a zero moves it on
+        ("choose", "붕\n츼\n붕\n뭉\n희", "5\n7\n0\n9\n", "9", 0),
+    );
+    for &(name, code, input, expected, status) in cases.iter() {
+        let (out, ret) = compile_and_run(name, code, input);
+        assert!(out == expected, "unexpected output from {}", name);
+        assert!(ret == status, "unexpected status from {}", name);
+    }
+}
@@ -0,0 +1,106 @@
+// `aheui coverage`: turns the states recorded by a program compiled with
+// --coverage into lcov. Rows are lines, and every ㅊ cell gets a pair of
+// branch records (block = column): 0 for going on, 1 for reversing.
+
+use std::collections::HashMap;
+
+use super::{Cho, Hangul};
+
+pub const DEFAULT_PATH: &'static str = "aheui.cov";
+
+// entries moving left, right, up and down, then ㅊ going on and reversing
+pub type States = HashMap<(usize, usize), [u64; 6]>;
+
+pub fn parse(text: &str) -> Result<States, String> {
+    let mut states = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let nums: Vec<u64> = words.iter().filter_map(|w| w.parse().ok()).collect();
+        if words.len() != 8 || nums.len() != 8 {
+            return Err(format!("line {}: expected `x y left right up down on reversed`", i + 1));
+        }
+        let slots = states.entry((nums[0] as usize, nums[1] as usize)).or_insert([0; 6]);
+        for (slot, n) in slots.iter_mut().zip(nums[2..].iter()) {
+            *slot += *n;
+        }
+    }
+    Ok(states)
+}
+
+fn entries(states: &States, x: usize, y: usize) -> u64 {
+    match states.get(&(x, y)) {
+        Some(s) => s[..4].iter().fold(0, |a, &b| a + b),
+        None => 0,
+    }
+}
+
+pub fn lcov(code: &[Vec<Hangul>], source: &str, states: &States) -> String {
+    let mut out = format!("TN:\nSF:{}\n", source);
+
+    let (mut found, mut hit) = (0, 0);
+    for (y, line) in code.iter().enumerate() {
+        for (x, _) in line.iter().enumerate().filter(|&(_, h)| h.cho == Cho::cㅊ) {
+            let slots = states.get(&(x, y)).cloned().unwrap_or([0; 6]);
+            for b in 0..2 {
+                let taken = if entries(states, x, y) == 0 {
+                    "-".to_string()
+                } else {
+                    slots[4 + b].to_string()
+                };
+                out.push_str(&format!("BRDA:{},{},{},{}\n", y + 1, x, b, taken));
+                found += 1;
+                if slots[4 + b] > 0 {
+                    hit += 1;
+                }
+            }
+        }
+    }
+    out.push_str(&format!("BRF:{}\nBRH:{}\n", found, hit));
+
+    // rows without a single syllable are prose, not code
+    let (mut found, mut hit) = (0, 0);
+    for (y, line) in code.iter().enumerate() {
+        if line.iter().all(|h| h.cho == Cho::cNone) {
+            continue;
+        }
+        let count = (0..line.len()).fold(0, |a, x| a + entries(states, x, y));
+        out.push_str(&format!("DA:{},{}\n", y + 1, count));
+        found += 1;
+        if count > 0 {
+            hit += 1;
+        }
+    }
+    out.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", found, hit));
+    out
+}
+
+#[test]
+fn test_coverage() {
+    use super::parse_code;
+
+    let states = parse("0 0 0 0 0 1 0 0\n1 0 0 1 0 0 1 0\n\n2 0 0 1 0 0 0 0\n").unwrap();
+    assert!(states[&(1, 0)] == [0, 1, 0, 0, 1, 0]);
+    assert!(parse("0 0 1\n").is_err());
+
+    let code = parse_code("박차희\nabc\n희");
+    assert!(lcov(&code, "t.aheui", &states) == "\
+TN:
+SF:t.aheui
+BRDA:1,1,0,1
+BRDA:1,1,1,0
+BRF:2
+BRH:1
+DA:1,3
+DA:3,0
+LF:2
+LH:1
+end_of_record
+");
+
+    // a ㅊ cell never reached has no branch data at all
+    let states = parse("0 0 0 0 0 1 0 0\n").unwrap();
+    assert!(lcov(&code, "t.aheui", &states).contains("BRDA:1,1,0,-\nBRDA:1,1,1,-\n"));
+}
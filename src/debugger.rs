@@ -0,0 +1,221 @@
+// Debug runtime for programs compiled with --trace. runtime_debug.rs links
+// this hook as `aheui_trace` in place of rt's, and `jit --debug` resolves it
+// the same way. Commands are read from the controlling terminal so that the
+// program keeps its stdin; everything the debugger says goes to stderr.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write, stderr};
+use std::process;
+
+use rt::{self, AheuiRt};
+
+const STORAGES: usize = 27;
+
+const HELP: &'static str = "\
+break X Y, break C  stop at cell (X, Y), or at every cell C
+delete              remove all breakpoints
+step                run one cell (also an empty line)
+continue            run to the next breakpoint
+print               show every storage, the selected one and the direction
+set N [V...]        replace storage N, bottom to top (front to back for 21)
+quit                exit the program
+";
+
+#[derive(PartialEq, Eq, Debug)]
+enum Break {
+    At(i32, i32),
+    Char(char),
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Command {
+    Break(Break),
+    Delete,
+    Step,
+    Continue,
+    Print,
+    Set(usize, Vec<i32>),
+    Quit,
+    Help,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let cmd = match words.first() {
+        Some(w) => *w,
+        None => return Ok(Command::Step),
+    };
+    let args = &words[1..];
+    match cmd {
+        "b" | "break" => match args.len() {
+            1 if args[0].chars().count() == 1 => {
+                Ok(Command::Break(Break::Char(args[0].chars().next().unwrap())))
+            },
+            2 => match (args[0].parse::<i32>(), args[1].parse::<i32>()) {
+                (Ok(x), Ok(y)) => Ok(Command::Break(Break::At(x, y))),
+                _ => Err("usage: break X Y".to_string()),
+            },
+            _ => Err("usage: break X Y, or break C".to_string()),
+        },
+        "d" | "delete" => Ok(Command::Delete),
+        "s" | "step" => Ok(Command::Step),
+        "c" | "continue" => Ok(Command::Continue),
+        "p" | "print" => Ok(Command::Print),
+        "set" => {
+            let n = match args.first().map(|a| a.parse::<usize>()) {
+                Some(Ok(n)) if n < STORAGES => n,
+                _ => return Err(format!("usage: set N [V...] with N below {}", STORAGES)),
+            };
+            let mut values = Vec::new();
+            for a in args[1..].iter() {
+                match a.parse() {
+                    Ok(v) => values.push(v),
+                    Err(_) => return Err(format!("not a number: {}", a)),
+                }
+            }
+            Ok(Command::Set(n, values))
+        },
+        "q" | "quit" => Ok(Command::Quit),
+        "h" | "help" => Ok(Command::Help),
+        _ => Err(format!("unknown command `{}`; try help", cmd)),
+    }
+}
+
+pub fn format_state(storages: &[Vec<i32>], flow: i8, idx: i8) -> String {
+    let mut out = format!("moving {}, storage {} selected\n", rt::dir_name(flow), idx);
+    for (i, s) in storages.iter().enumerate() {
+        let mark = if i == idx as usize { '*' } else { ' ' };
+        let kind = if i == 21 { "queue" } else { "stack" };
+        let values: Vec<String> = s.iter().map(|v| v.to_string()).collect();
+        out.push_str(&format!("{}{:>3} {} [{}]\n", mark, i, kind, values.join(" ")));
+    }
+    out
+}
+
+struct Debugger {
+    breaks: Vec<Break>,
+    stepping: bool,
+    tty: Option<BufReader<File>>,
+}
+
+impl Debugger {
+    fn new() -> Debugger {
+        Debugger {
+            breaks: Vec::new(),
+            // stop before the first cell
+            stepping: true,
+            tty: None,
+        }
+    }
+
+    fn hits(&self, x: i32, y: i32, c: char) -> bool {
+        self.stepping || self.breaks.iter().any(|b| match *b {
+            Break::At(bx, by) => (bx, by) == (x, y),
+            Break::Char(bc) => bc == c,
+        })
+    }
+
+    // None when there is no terminal to read from; this runs inside
+    // generated code, so it reports errors rather than panicking
+    fn read_line(&mut self) -> Option<String> {
+        if self.tty.is_none() {
+            match File::open("/dev/tty") {
+                Ok(f) => self.tty = Some(BufReader::new(f)),
+                Err(e) => {
+                    let _ = writeln!(stderr(), "debugger: cannot open /dev/tty: {}", e);
+                    return None;
+                },
+            }
+        }
+        let mut line = String::new();
+        match self.tty.as_mut().unwrap().read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(line),
+            Err(e) => {
+                let _ = writeln!(stderr(), "debugger: {}", e);
+                None
+            },
+        }
+    }
+
+    fn prompt(&mut self, ctx: *mut AheuiRt, x: i32, y: i32, c: char, flow: i8, idx: i8) {
+        // the program's output so far comes before the prompt
+        rt::flush(ctx);
+        let mut err = stderr();
+        let _ = writeln!(err, "({}, {}) {} moving {}", x, y, c, rt::dir_name(flow));
+        loop {
+            let _ = write!(err, "(aheui) ");
+            let line = match self.read_line() {
+                Some(line) => line,
+                None => {
+                    // no terminal, or it went away; let the program finish
+                    self.stepping = false;
+                    self.breaks.clear();
+                    return;
+                },
+            };
+            match parse_command(&line) {
+                Ok(Command::Break(b)) => self.breaks.push(b),
+                Ok(Command::Delete) => self.breaks.clear(),
+                Ok(Command::Step) => {
+                    self.stepping = true;
+                    return;
+                },
+                Ok(Command::Continue) => {
+                    self.stepping = false;
+                    return;
+                },
+                Ok(Command::Print) => {
+                    let _ = write!(err, "{}", format_state(&rt::storages(ctx), flow, idx));
+                },
+                Ok(Command::Set(n, values)) => rt::set_storage(ctx, n, values),
+                Ok(Command::Quit) => process::exit(1),
+                Ok(Command::Help) => {
+                    let _ = write!(err, "{}", HELP);
+                },
+                Err(e) => {
+                    let _ = writeln!(err, "{}", e);
+                },
+            }
+        }
+    }
+}
+
+thread_local!(static KEY_DBG: RefCell<Debugger> = RefCell::new(Debugger::new()));
+
+pub extern "C" fn aheui_trace(ctx: *mut AheuiRt, x: i32, y: i32, c: char, flow: i8,
+                              idx: i8) {
+    KEY_DBG.with(|cell| {
+        let mut dbg = cell.borrow_mut();
+        if dbg.hits(x, y, c) {
+            dbg.prompt(ctx, x, y, c, flow, idx);
+        }
+    })
+}
+
+#[test]
+fn test_debugger() {
+    assert!(parse_command("break 3 4") == Ok(Command::Break(Break::At(3, 4))));
+    assert!(parse_command("b 희") == Ok(Command::Break(Break::Char('희'))));
+    assert!(parse_command("b 3 x").is_err());
+    assert!(parse_command("\n") == Ok(Command::Step));
+    assert!(parse_command("set 21 1 -2 3") == Ok(Command::Set(21, vec!(1, -2, 3))));
+    assert!(parse_command("set 27 1").is_err());
+    assert!(parse_command("jump").is_err());
+
+    let mut dbg = Debugger::new();
+    assert!(dbg.hits(0, 0, '밤'));
+    dbg.stepping = false;
+    dbg.breaks.push(Break::At(1, 0));
+    dbg.breaks.push(Break::Char('희'));
+    assert!(!dbg.hits(0, 0, '밤'));
+    assert!(dbg.hits(1, 0, '밤') && dbg.hits(5, 5, '희'));
+
+    let mut storages = vec!(Vec::new(); STORAGES);
+    storages[0] = vec!(1, 2);
+    let state = format_state(&storages, 3, 0);
+    assert!(state.starts_with("moving down, storage 0 selected\n*  0 stack [1 2]\n   1 stack []\n"));
+    assert!(state.contains("\n  21 queue []\n"));
+    assert!(state.lines().count() == 1 + STORAGES);
+}
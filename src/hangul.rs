@@ -0,0 +1,231 @@
+// Hangul decoding and the code grid, shared by the compiler and the
+// `aheui!` macro plugin (aheui-macros).
+
+use num;
+
+#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
+pub enum Cho {
+    cㄱ, cㄲ, cㄴ, cㄷ, cㄸ, cㄹ, cㅁ, cㅂ,
+    cㅃ, cㅅ, cㅆ, cㅇ, cㅈ, cㅉ, cㅊ, cㅋ,
+    cㅌ, cㅍ, cㅎ, cNone
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
+pub enum Jung {
+    ㅏ, ㅐ, ㅑ, ㅒ, ㅓ, ㅔ, ㅕ, ㅖ,
+    ㅗ, ㅘ, ㅙ, ㅚ, ㅛ, ㅜ, ㅝ, ㅞ,
+    ㅟ, ㅠ, ㅡ, ㅢ, ㅣ, juNone
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
+pub enum Jong {
+    joNone,
+    jㄱ, jㄲ, jㄳ, jㄴ, jㄵ, jㄶ, jㄷ, jㄹ,
+    jㄺ, jㄻ, jㄼ, jㄽ, jㄾ, jㄿ, jㅀ, jㅁ,
+    jㅂ, jㅄ, jㅅ, jㅆ, jㅇ, jㅈ, jㅊ, jㅋ,
+    jㅌ, jㅍ, jㅎ
+}
+
+impl Jong {
+    pub fn val(&self) -> usize {
+        if *self == Jong::jㅇ || *self == Jong::jㅎ {
+            panic!("Jong::val(ㅇ or ㅎ)");
+        }
+
+        let map = [
+            0,
+            2, 4, 4, 2, 5, 5, 3, 5,
+            7, 9, 9, 7, 9, 9, 8, 4,
+            4, 6, 2, 4, 0, 3, 4, 3,
+            4, 4, 0,
+        ];
+        map[*self as usize]
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Flow {
+    Left,
+    Right,
+    Up,
+    Down,
+}
+
+impl Flow {
+    pub fn reverse(&self) -> Flow {
+        match *self {
+            Flow::Left => Flow::Right,
+            Flow::Right => Flow::Left,
+            Flow::Up => Flow::Down,
+            Flow::Down => Flow::Up,
+        }
+    }
+
+    pub fn from_jung(jung: Jung) -> Option<Flow> {
+        use self::Jung::*;
+
+        match jung {
+            ㅏ | ㅑ => Some(Flow::Right),
+            ㅓ | ㅕ => Some(Flow::Left),
+            ㅗ | ㅛ => Some(Flow::Up),
+            ㅜ | ㅠ => Some(Flow::Down),
+            _ => None,
+        }
+    }
+
+    // the flow after a non-directional vowel: ㅣ, ㅡ and ㅢ bounce, the rest keep it
+    pub fn reflect(&self, jung: Jung) -> Flow {
+        use self::Jung::*;
+
+        match (jung, *self) {
+            (ㅣ, Flow::Left) | (ㅣ, Flow::Right) => self.reverse(),
+            (ㅡ, Flow::Up) | (ㅡ, Flow::Down) => self.reverse(),
+            (ㅢ, _) => self.reverse(),
+            _ => *self,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq)]
+pub struct Hangul {
+    pub cho: Cho,
+    pub jung: Jung,
+    pub jong: Jong,
+    pub c: char,
+}
+
+impl Hangul {
+    pub fn none() -> Hangul {
+        Hangul {
+            cho: Cho::cNone,
+            jung: Jung::juNone,
+            jong: Jong::joNone,
+            c: '?',
+        }
+    }
+
+    pub fn from_char(c: char) -> Hangul {
+        let u = c as usize;
+        let ga = '가' as usize;
+        let hih = '힣' as usize;
+        if u < ga || u > hih {
+            return Hangul::none();
+        }
+        let u = u - ga;
+        let cho = u / 28 / 21;
+        let jung = (u / 28) % 21;
+        let jong = u % 28;
+        Hangul {
+            cho:  num::FromPrimitive::from_usize(cho).unwrap(),
+            jung: num::FromPrimitive::from_usize(jung).unwrap(),
+            jong: num::FromPrimitive::from_usize(jong).unwrap(),
+            c: c,
+        }
+    }
+}
+
+pub fn next_pos<T>(b: &[Vec<T>], x: usize, y: usize, flow: Flow) -> (usize, usize) {
+    match flow {
+        Flow::Left | Flow::Right => {
+            let len = b[y].len();
+            let next_x = if flow == Flow::Left {
+                (len + x - 1) % len
+            } else {
+                (x + 1) % len
+            };
+            return (next_x, y);
+        }
+        Flow::Up | Flow::Down => {
+            if flow == Flow::Down {
+                let ly = y + 1;
+                let it = b.iter().enumerate().skip(ly);
+                let it = it.chain(b.iter().enumerate().take(ly));
+                for (cur_y, line) in it {
+                    if x < line.len() {
+                        return (x, cur_y);
+                    }
+                }
+                panic!("Failed to find next position");
+            } else {
+                let ly = b.len() - y;
+                let it = b.iter().rev().enumerate().skip(ly);
+                let it = it.chain(b.iter().rev().enumerate().take(ly));
+                for (cur_y, line) in it {
+                    if x < line.len() {
+                        return (x, b.len() - 1 - cur_y);
+                    }
+                }
+                panic!("Failed to find next position");
+            }
+        }
+    }
+}
+
+// ㅑ, ㅕ, ㅛ and ㅠ move two cells at once
+pub fn move_pos<T>(b: &[Vec<T>], x: usize, y: usize, jung: Jung, flow: Flow) -> (usize, usize) {
+    use self::Jung::*;
+
+    match jung {
+        ㅑ | ㅕ | ㅛ | ㅠ => {
+            let (nx, ny) = next_pos(b, x, y, flow);
+            next_pos(b, nx, ny, flow)
+        },
+        _ => next_pos(b, x, y, flow),
+    }
+}
+
+pub fn parse_code(code: &str) -> Vec<Vec<Hangul>> {
+    code.lines().map(|line| {
+        line.chars().map(Hangul::from_char).collect::<Vec<Hangul>>()
+    }).collect()
+}
+
+// `*.aheui` programs under `dir` paired with their expected stdout (`*.out`)
+#[cfg(test)]
+pub fn read_cases(dir: &::std::path::Path) -> Vec<(String, String, String)> {
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    let mut cases = Vec::new();
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path.extension().and_then(|e| e.to_str()) != Some("aheui") {
+            continue;
+        }
+        let name = path.file_stem().unwrap().to_str().unwrap().to_string();
+        let mut code = String::new();
+        File::open(&path).unwrap().read_to_string(&mut code).unwrap();
+        let mut expected = String::new();
+        File::open(&path.with_extension("out")).unwrap()
+                                              .read_to_string(&mut expected).unwrap();
+        cases.push((name, code, expected));
+    }
+    cases.sort();
+    cases
+}
+
+#[test]
+fn test_hangul() {
+    use self::Cho::*;
+    use self::Jung::*;
+    use self::Jong::*;
+    let 가 = Hangul { cho: cㄱ, jung: ㅏ, jong: joNone, c: '가' };
+    assert!(Hangul::from_char('가') == 가);
+
+    let 아 = Hangul { cho: cㅇ, jung: ㅏ, jong: joNone, c: '아' };
+    assert!(Hangul::from_char('아') == 아);
+
+    let 힣 = Hangul { cho: cㅎ, jung: ㅣ, jong: jㅎ, c: '힣' };
+    assert!(Hangul::from_char('힣') == 힣);
+
+    assert!(Hangul::from_char('A') == Hangul::none());
+    assert!(Hangul::from_char('☆') == Hangul::none());
+}
+
+#[test]
+fn test_jong() {
+    use self::Jong::*;
+    assert!(joNone.val() == 0);
+    assert!(jㄱ.val() == 2);
+    assert!(jㄿ.val() == 9);
+}
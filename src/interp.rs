@@ -0,0 +1,199 @@
+// Direct interpreter over the parsed grid. Unlike the VM it keeps its own
+// storages and reports errors instead of panicking, so that interactive
+// tools can show and rewind the whole machine state.
+
+use vm::Io;
+use rt::EXTENSION;
+use super::{Jong, Hangul, Flow, next_pos, move_pos};
+
+// 26 stacks and the queue; 27 is the unsupported extension
+pub const STORAGES: usize = 27;
+pub const QUEUE: usize = 21;
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct Machine<'a> {
+    pub code: &'a [Vec<Hangul>],
+    pub x: usize,
+    pub y: usize,
+    pub flow: Flow,
+    pub cur: usize,
+    pub storages: Vec<Vec<i32>>,
+    pub steps: u64,
+    pub halted: Option<i32>,
+}
+
+impl<'a> Machine<'a> {
+    pub fn new(code: &'a [Vec<Hangul>]) -> Machine<'a> {
+        Machine {
+            code: code,
+            x: 0,
+            y: 0,
+            flow: Flow::Down,
+            cur: Jong::joNone as usize,
+            storages: vec!(Vec::new(); STORAGES),
+            steps: 0,
+            halted: None,
+        }
+    }
+
+    pub fn cell(&self) -> &'a Hangul {
+        &self.code[self.y][self.x]
+    }
+
+    fn pop(&mut self) -> Result<i32, String> {
+        let s = &mut self.storages[self.cur];
+        if s.is_empty() {
+            return Err(format!("storage {} is empty", self.cur));
+        }
+        Ok(if self.cur == QUEUE { s.remove(0) } else { s.pop().unwrap() })
+    }
+
+    fn need(&self, n: usize) -> Result<(), String> {
+        if self.storages[self.cur].len() < n {
+            return Err(format!("storage {} has fewer than {} values", self.cur, n));
+        }
+        Ok(())
+    }
+
+    // runs the current cell and moves on
+    pub fn step<I: Io>(&mut self, io: &mut I) -> Result<(), String> {
+        use Cho::*;
+        use Jong::*;
+
+        if self.halted.is_some() {
+            return Err("the program has halted".to_string());
+        }
+        let h = self.cell();
+        let (x, y) = (self.x, self.y);
+        let dir = Flow::from_jung(h.jung);
+        if self.cur == STORAGES {
+            return Err(EXTENSION.to_string());
+        }
+
+        let mut reverse = false;
+        match h.cho {
+            cㄴ | cㄷ | cㄸ | cㄹ | cㅈ | cㅌ => {
+                try!(self.need(2));
+                let a = try!(self.pop());
+                let b = try!(self.pop());
+                let v = match h.cho {
+                    cㄴ | cㄹ if a == 0 => return Err("division by zero".to_string()),
+                    cㄴ => (b as u32 / a as u32) as i32,
+                    cㄷ => a.wrapping_add(b),
+                    cㄸ => a.wrapping_mul(b),
+                    cㄹ => (b as u32 % a as u32) as i32,
+                    cㅈ => ((a as u32) <= (b as u32)) as i32,
+                    _ => b.wrapping_sub(a),
+                };
+                self.storages[self.cur].push(v);
+            },
+            cㅁ => {
+                let v = try!(self.pop());
+                match h.jong {
+                    jㅇ => io.putint(v),
+                    jㅎ => io.putchar(v),
+                    _ => {},
+                }
+            },
+            cㅂ => {
+                let v = match h.jong {
+                    jㅇ => io.getint(),
+                    jㅎ => io.getchar(),
+                    _ => h.jong.val() as i32,
+                };
+                self.storages[self.cur].push(v);
+            },
+            cㅃ => {
+                try!(self.need(1));
+                let s = &mut self.storages[self.cur];
+                if self.cur == QUEUE {
+                    let v = s[0];
+                    s.insert(0, v);
+                } else {
+                    let v = s[s.len() - 1];
+                    s.push(v);
+                }
+            },
+            cㅍ => {
+                try!(self.need(2));
+                let s = &mut self.storages[self.cur];
+                let n = s.len();
+                if self.cur == QUEUE {
+                    s.swap(0, 1);
+                } else {
+                    s.swap(n - 2, n - 1);
+                }
+            },
+            cㅅ => self.cur = h.jong as usize,
+            cㅆ => {
+                let to = h.jong as usize;
+                if to == STORAGES {
+                    return Err(EXTENSION.to_string());
+                }
+                let v = try!(self.pop());
+                self.storages[to].push(v);
+            },
+            cㅊ => reverse = try!(self.pop()) == 0,
+            cㅎ => {
+                let s = &mut self.storages[self.cur];
+                let v = match s.is_empty() {
+                    true => 0,
+                    false if self.cur == QUEUE => s.remove(0),
+                    false => s.pop().unwrap(),
+                };
+                self.steps += 1;
+                self.halted = Some(v);
+                return Ok(());
+            },
+            cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+        }
+
+        let (nx, ny) = match dir {
+            Some(f) => {
+                self.flow = f;
+                // a reversed move keeps the unreversed flow
+                move_pos(self.code, x, y, h.jung, if reverse { f.reverse() } else { f })
+            },
+            None => {
+                let f = self.flow.reflect(h.jung);
+                self.flow = f;
+                next_pos(self.code, x, y, if reverse { f.reverse() } else { f })
+            },
+        };
+        self.x = nx;
+        self.y = ny;
+        self.steps += 1;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_interp() {
+    use vm::BufIo;
+    use super::{conformance_cases, parse_code};
+
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let code = parse_code(&code);
+        let mut m = Machine::new(&code);
+        let mut io = BufIo { input: Vec::new(), output: String::new() };
+        while m.halted.is_none() {
+            m.step(&mut io).unwrap();
+        }
+        assert!(io.output == expected, "unexpected output from {}", name);
+    }
+
+    let code = parse_code("밤밣따희");
+    let mut m = Machine::new(&code);
+    let mut io = BufIo { input: Vec::new(), output: String::new() };
+    m.step(&mut io).unwrap();
+    assert!((m.x, m.y, m.steps) == (1, 0, 1) && m.storages[0] == vec!(4));
+    m.step(&mut io).unwrap();
+    m.step(&mut io).unwrap();
+    assert!(m.storages[0] == vec!(32));
+    m.step(&mut io).unwrap();
+    assert!(m.halted == Some(32));
+    assert!(m.step(&mut io).is_err());
+
+    let code = parse_code("따");
+    assert!(Machine::new(&code).step(&mut io).is_err());
+}
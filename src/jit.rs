@@ -0,0 +1,326 @@
+// In-process execution of a generated module through LLVM's ORC JIT.
+//
+// The module produced by `Aheui::gen_llvm` only declares the `aheui_*`
+// runtime functions. Instead of linking runtime.rs as a separate object,
+// the symbol resolver below hands out the addresses of the same functions
+// compiled into this binary (see `rt`).
+
+use std::ffi::{CStr, CString};
+use std::io::{Write, stderr};
+use std::mem;
+use std::process;
+use std::ptr;
+
+use libc::{c_char, c_void};
+
+use rustc::lib::llvm::ModuleRef;
+
+use debugger;
+use rt;
+use Aheui;
+
+#[allow(missing_copy_implementations)]
+pub enum Target_opaque {}
+pub type TargetRef = *mut Target_opaque;
+
+#[allow(missing_copy_implementations)]
+pub enum TargetMachine_opaque {}
+pub type TargetMachineRef = *mut TargetMachine_opaque;
+
+#[allow(missing_copy_implementations)]
+pub enum OrcJITStack_opaque {}
+pub type OrcJITStackRef = *mut OrcJITStack_opaque;
+
+pub type OrcModuleHandle = u32;
+pub type OrcTargetAddress = u64;
+pub type OrcSymbolResolverFn = extern "C" fn(*const c_char, *mut c_void) -> u64;
+
+// LLVMCodeGenOptLevel, LLVMRelocMode, LLVMCodeModel
+const CODEGEN_LEVEL_AGGRESSIVE: u32 = 3;
+const RELOC_DEFAULT: u32 = 0;
+const CODE_MODEL_JIT_DEFAULT: u32 = 1;
+
+// LLVM_InitializeNativeTarget is inline in the C headers, so the host's
+// own initializers are picked here
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+extern {
+    fn LLVMInitializeX86TargetInfo();
+    fn LLVMInitializeX86Target();
+    fn LLVMInitializeX86TargetMC();
+    fn LLVMInitializeX86AsmPrinter();
+}
+
+#[cfg(target_arch = "aarch64")]
+extern {
+    fn LLVMInitializeAArch64TargetInfo();
+    fn LLVMInitializeAArch64Target();
+    fn LLVMInitializeAArch64TargetMC();
+    fn LLVMInitializeAArch64AsmPrinter();
+}
+
+#[cfg(target_arch = "arm")]
+extern {
+    fn LLVMInitializeARMTargetInfo();
+    fn LLVMInitializeARMTarget();
+    fn LLVMInitializeARMTargetMC();
+    fn LLVMInitializeARMAsmPrinter();
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+unsafe fn init_native_target() -> bool {
+    LLVMInitializeX86TargetInfo();
+    LLVMInitializeX86Target();
+    LLVMInitializeX86TargetMC();
+    LLVMInitializeX86AsmPrinter();
+    true
+}
+
+#[cfg(target_arch = "aarch64")]
+unsafe fn init_native_target() -> bool {
+    LLVMInitializeAArch64TargetInfo();
+    LLVMInitializeAArch64Target();
+    LLVMInitializeAArch64TargetMC();
+    LLVMInitializeAArch64AsmPrinter();
+    true
+}
+
+#[cfg(target_arch = "arm")]
+unsafe fn init_native_target() -> bool {
+    LLVMInitializeARMTargetInfo();
+    LLVMInitializeARMTarget();
+    LLVMInitializeARMTargetMC();
+    LLVMInitializeARMAsmPrinter();
+    true
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64",
+              target_arch = "arm")))]
+unsafe fn init_native_target() -> bool {
+    false
+}
+
+extern {
+    fn LLVMGetDefaultTargetTriple() -> *mut c_char;
+    fn LLVMGetTargetFromTriple(triple: *const c_char, t: *mut TargetRef,
+                               err: *mut *mut c_char) -> i32;
+    fn LLVMCreateTargetMachine(t: TargetRef, triple: *const c_char,
+                               cpu: *const c_char, features: *const c_char,
+                               level: u32, reloc: u32,
+                               code_model: u32) -> TargetMachineRef;
+    fn LLVMDisposeMessage(msg: *mut c_char);
+
+    fn LLVMOrcCreateInstance(tm: TargetMachineRef) -> OrcJITStackRef;
+    fn LLVMOrcAddEagerlyCompiledIR(jit: OrcJITStackRef, md: ModuleRef,
+                                   resolver: OrcSymbolResolverFn,
+                                   ctx: *mut c_void) -> OrcModuleHandle;
+    fn LLVMOrcGetMangledSymbol(jit: OrcJITStackRef, mangled: *mut *mut c_char,
+                               sym: *const c_char);
+    fn LLVMOrcDisposeMangledSymbol(mangled: *mut c_char);
+    fn LLVMOrcGetSymbolAddress(jit: OrcJITStackRef,
+                               sym: *const c_char) -> OrcTargetAddress;
+    fn LLVMOrcDisposeInstance(jit: OrcJITStackRef);
+}
+
+fn rt_symbol(name: &str) -> Option<usize> {
+    let addr = match name {
+        "aheui_getchar" => rt::aheui_getchar as usize,
+        "aheui_putchar" => rt::aheui_putchar as usize,
+        "aheui_getint" => rt::aheui_getint as usize,
+        "aheui_putint" => rt::aheui_putint as usize,
+        "aheui_trace" => rt::aheui_trace as usize,
+        "aheui_push" => rt::aheui_push as usize,
+        "aheui_pop" => rt::aheui_pop as usize,
+        "aheui_dup" => rt::aheui_dup as usize,
+        "aheui_swap" => rt::aheui_swap as usize,
+        "aheui_halt" => rt::aheui_halt as usize,
+        "aheui_profile_counts" => rt::aheui_profile_counts as usize,
+        "aheui_coverage_counts" => rt::aheui_coverage_counts as usize,
+        "aheui_profile_write" => rt::aheui_profile_write as usize,
+        "aheui_coverage_write" => rt::aheui_coverage_write as usize,
+        "aheui_step_limit" => rt::aheui_step_limit as usize,
+        "aheui_step_exceeded" => rt::aheui_step_exceeded as usize,
+        "aheui_hang_check" => rt::aheui_hang_check as usize,
+        "aheui_cell_record" => rt::aheui_cell_record as usize,
+        "aheui_failed" => rt::aheui_failed as usize,
+        "aheui_error_exit" => rt::aheui_error_exit as usize,
+        "aheui_divisor" => rt::aheui_divisor as usize,
+        "aheui_stats_start" => rt::aheui_stats_start as usize,
+        "aheui_stat" => rt::aheui_stat as usize,
+        "aheui_poll" => rt::aheui_poll as usize,
+        "aheui_signal" => &rt::aheui_signal as *const _ as usize,
+        _ => return None,
+    };
+    Some(addr)
+}
+
+// `ctx` is null, or the `aheui_trace` to use in place of rt's
+extern "C" fn resolve_symbol(name: *const c_char, ctx: *mut c_void) -> u64 {
+    let name = unsafe { CStr::from_ptr(name) };
+    let name = String::from_utf8_lossy(name.to_bytes());
+    // Darwin prefixes global symbols with an underscore.
+    let unmangled = if cfg!(target_os = "macos") {
+        name.trim_left_matches('_')
+    } else {
+        &name[..]
+    };
+    if unmangled == "aheui_trace" && !ctx.is_null() {
+        return ctx as u64;
+    }
+    // called from LLVM, which a panic must not unwind into
+    match rt_symbol(unmangled) {
+        Some(addr) => addr as u64,
+        None => {
+            let _ = writeln!(stderr(), "jit: unresolved symbol `{}`", name);
+            process::abort();
+        },
+    }
+}
+
+fn host_target_machine() -> TargetMachineRef {
+    unsafe {
+        if !init_native_target() {
+            panic!("jit: no JIT support for this architecture; use --emit=c or llc instead");
+        }
+
+        let triple = LLVMGetDefaultTargetTriple();
+        let mut target = ptr::null_mut();
+        let mut err = ptr::null_mut();
+        if LLVMGetTargetFromTriple(triple, &mut target, &mut err) != 0 {
+            let msg = String::from_utf8_lossy(CStr::from_ptr(err).to_bytes()).into_owned();
+            LLVMDisposeMessage(err);
+            panic!("jit: {}", msg);
+        }
+
+        let empty = CString::new("").unwrap();
+        let tm = LLVMCreateTargetMachine(
+            target, triple, empty.as_ptr(), empty.as_ptr(),
+            CODEGEN_LEVEL_AGGRESSIVE, RELOC_DEFAULT, CODE_MODEL_JIT_DEFAULT
+        );
+        LLVMDisposeMessage(triple);
+        tm
+    }
+}
+
+// an ORC JIT stack for the host; modules added to it can call into `rt`
+pub struct Jit {
+    stack: OrcJITStackRef,
+    trace: *mut c_void,
+}
+
+impl Jit {
+    pub fn new() -> Jit {
+        let tm = host_target_machine();
+        Jit {
+            stack: unsafe { LLVMOrcCreateInstance(tm) },
+            trace: ptr::null_mut(),
+        }
+    }
+
+    // --trace hooks of modules added from now on stop in the debugger
+    pub fn debug(&mut self) {
+        self.trace = debugger::aheui_trace as usize as *mut c_void;
+    }
+
+    // the JIT stack takes ownership of the module
+    pub fn add(&mut self, md: ModuleRef) {
+        unsafe {
+            LLVMOrcAddEagerlyCompiledIR(self.stack, md, resolve_symbol, self.trace);
+        }
+    }
+
+    pub fn symbol(&self, name: &str) -> usize {
+        let name = CString::new(name).unwrap();
+        let addr = unsafe {
+            let mut mangled = ptr::null_mut();
+            LLVMOrcGetMangledSymbol(self.stack, &mut mangled, name.as_ptr());
+            let addr = LLVMOrcGetSymbolAddress(self.stack, mangled);
+            LLVMOrcDisposeMangledSymbol(mangled);
+            addr
+        };
+        if addr == 0 {
+            panic!("jit: symbol {:?} not found", name);
+        }
+        addr as usize
+    }
+}
+
+impl Drop for Jit {
+    fn drop(&mut self) {
+        unsafe { LLVMOrcDisposeInstance(self.stack); }
+    }
+}
+
+pub type EntryFn = extern "C" fn(*mut rt::AheuiRt) -> i32;
+
+// compiles the module in memory and calls `fn_name` with a fresh context,
+// returning the exit code
+pub fn run(aheui: Aheui, fn_name: &str, debug: bool) -> i32 {
+    let mut jit = Jit::new();
+    if debug {
+        jit.debug();
+    }
+    jit.add(aheui.md);
+    let entry: EntryFn = unsafe { mem::transmute(jit.symbol(fn_name)) };
+    let ctx = rt::aheui_ctx_new();
+    let ret = entry(ctx);
+    rt::aheui_ctx_free(ctx);
+    ret
+}
+
+// like run, with the program reading `input` and its output kept
+pub fn run_captured(aheui: Aheui, fn_name: &str, input: &[u8]) -> (Vec<u8>, i32) {
+    let mut jit = Jit::new();
+    jit.add(aheui.md);
+    let entry: EntryFn = unsafe { mem::transmute(jit.symbol(fn_name)) };
+    rt::run_captured(entry, input)
+}
+
+#[test]
+fn test_jit_exit_code() {
+    use {mir, parse_code};
+
+    let cases = vec!(
+        ("희", 0),
+        ("밤희", 4),
+        ("반받다희", 5),
+        ("상반받희", 2),
+    );
+    for (code, expected) in cases.into_iter() {
+        let aheui = Aheui::new(mir::lower(&parse_code(code)), "jit_test", "aheui_main",
+                               Default::default());
+        aheui.gen_llvm();
+        assert!(run(aheui, "aheui_main", false) == expected);
+    }
+}
+
+#[test]
+fn test_jit_captured() {
+    use {mir, parse_code};
+
+    let aheui = Aheui::new(mir::lower(&parse_code("밯빠맣맣방망희")), "jit_test", "aheui_main",
+                           Default::default());
+    aheui.gen_llvm();
+    let (out, code) = run_captured(aheui, "aheui_main", "밤\n42\n".as_bytes());
+    assert!(out == "밤밤42".as_bytes() && code == 0);
+}
+
+#[test]
+fn test_jit_concurrent() {
+    use std::thread;
+    use {mir, parse_code};
+
+    // counts down from 9, printing as it goes
+    let code = "밞빠망반반나타빠추\n어오어어어어어어어\n어어어어어어어어희";
+    let aheui = Aheui::new(mir::lower(&parse_code(code)), "jit_test", "aheui_main",
+                           Default::default());
+    aheui.gen_llvm();
+    let mut jit = Jit::new();
+    jit.add(aheui.md);
+    let entry: EntryFn = unsafe { mem::transmute(jit.symbol("aheui_main")) };
+    let runs: Vec<_> = (0..4).map(|_| {
+        thread::spawn(move || rt::run_captured(entry, &[]))
+    }).collect();
+    for run in runs.into_iter() {
+        assert!(run.join().unwrap() == ("987654321".as_bytes().to_vec(), 0));
+    }
+}
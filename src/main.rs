@@ -6,360 +6,463 @@ extern crate getopts;
 extern crate libc;
 extern crate rustc;
 extern crate num;
+#[macro_use] extern crate log;
 
 use std::path::Path;
 use std::fs::File;
-use std::boxed::Box;
 use std::ffi::CString;
+use std::cell::Cell;
 
 use libc::{c_uint, c_ulonglong};
 
 use rustc::lib::llvm::{ContextRef, BuilderRef, BasicBlockRef, ValueRef};
 use rustc::lib::llvm::{ModuleRef, TypeRef};
-use rustc::lib::llvm::{IntULE, IntEQ};
+use rustc::lib::llvm::{IntULE, IntUGT, IntEQ, IntNE};
 use rustc::lib::llvm::True;
 use rustc::lib::llvm;
 
-#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
-pub enum Cho {
-    cㄱ, cㄲ, cㄴ, cㄷ, cㄸ, cㄹ, cㅁ, cㅂ,
-    cㅃ, cㅅ, cㅆ, cㅇ, cㅈ, cㅉ, cㅊ, cㅋ,
-    cㅌ, cㅍ, cㅎ, cNone
+pub mod rt;
+mod jit;
+pub mod hangul;
+mod c;
+mod rust;
+mod bytecode;
+mod vm;
+mod tier;
+mod mir;
+mod profile;
+mod coverage;
+mod debugger;
+mod interp;
+mod tui;
+mod replay;
+mod tracediff;
+mod wasm;
+#[cfg(test)]
+mod wasm_interp;
+
+pub use hangul::{Cho, Jung, Jong, Flow, Hangul, next_pos, move_pos, parse_code};
+use mir::{Inst, Term, Reflect};
+
+// runtime hooks compiled into the generated code on request
+#[derive(Default)]
+struct Instrument {
+    trace: bool,
+    profile: bool,
+    coverage: bool,
+    max_steps: Option<u64>,
+    hangs: bool,
+    stats: bool,
 }
 
-#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
-pub enum Jung {
-    ㅏ, ㅐ, ㅑ, ㅒ, ㅓ, ㅔ, ㅕ, ㅖ,
-    ㅗ, ㅘ, ㅙ, ㅚ, ㅛ, ㅜ, ㅝ, ㅞ,
-    ㅟ, ㅠ, ㅡ, ㅢ, ㅣ, juNone
-}
-
-#[derive(PartialEq, Eq, Clone, Copy, NumFromPrimitive)]
-pub enum Jong {
-    joNone,
-    jㄱ, jㄲ, jㄳ, jㄴ, jㄵ, jㄶ, jㄷ, jㄹ,
-    jㄺ, jㄻ, jㄼ, jㄽ, jㄾ, jㄿ, jㅀ, jㅁ,
-    jㅂ, jㅄ, jㅅ, jㅆ, jㅇ, jㅈ, jㅊ, jㅋ,
-    jㅌ, jㅍ, jㅎ
-}
-
-impl Jong {
-    fn val(&self) -> usize {
-        if *self == Jong::jㅇ || *self == Jong::jㅎ {
-            panic!("Jong::val(ㅇ or ㅎ)");
-        }
-
-        let map = [
-            0,
-            2, 4, 4, 2, 5, 5, 3, 5,
-            7, 9, 9, 7, 9, 9, 8, 4,
-            4, 6, 2, 4, 0, 3, 4, 3,
-            4, 4, 0,
-        ];
-        map[*self as usize]
-    }
-}
-
-#[derive(PartialEq, Eq, Clone, Copy)]
-pub enum Flow {
-    Left,
-    Right,
-    Up,
-    Down,
-}
-
-impl Flow {
-    fn reverse(&self) -> Flow {
-        match *self {
-            Flow::Left => Flow::Right,
-            Flow::Right => Flow::Left,
-            Flow::Up => Flow::Down,
-            Flow::Down => Flow::Up,
-        }
-    }
-
-    fn from_jung(jung: Jung) -> Option<Flow> {
-        use Jung::*;
-
-        match jung {
-            ㅏ | ㅑ => Some(Flow::Right),
-            ㅓ | ㅕ => Some(Flow::Left),
-            ㅗ | ㅛ => Some(Flow::Up),
-            ㅜ | ㅠ => Some(Flow::Down),
-            _ => None,
-        }
+// what aheui_stat counts an instruction as
+fn stat_kind(inst: Inst) -> i32 {
+    match inst {
+        Inst::Add | Inst::Sub | Inst::Mul | Inst::Div | Inst::Rem | Inst::Compare => {
+            rt::STAT_ARITHMETIC
+        },
+        Inst::Push(_) | Inst::Pop | Inst::Dup | Inst::Swap => rt::STAT_STORAGE,
+        Inst::Select(_) | Inst::Move(_) => rt::STAT_SWITCH,
+        Inst::GetInt | Inst::GetChar | Inst::PutInt | Inst::PutChar => rt::STAT_IO,
     }
 }
 
-#[derive(PartialEq, Eq)]
-struct Hangul {
-    cho: Cho,
-    jung: Jung,
-    jong: Jong,
-    c: char,
-}
-
-impl Hangul {
-    fn none() -> Hangul {
-        Hangul {
-            cho: Cho::cNone,
-            jung: Jung::juNone,
-            jong: Jong::joNone,
-            c: '?',
-        }
-    }
-
-    fn from_char(c: char) -> Hangul {
-        let u = c as usize;
-        let ga = '가' as usize;
-        let hih = '힣' as usize;
-        if u < ga || u > hih {
-            return Hangul::none();
-        }
-        let u = u - ga;
-        let cho = u / 28 / 21;
-        let jung = (u / 28) % 21;
-        let jong = u % 28;
-        Hangul {
-            cho:  num::FromPrimitive::from_usize(cho).unwrap(),
-            jung: num::FromPrimitive::from_usize(jung).unwrap(),
-            jong: num::FromPrimitive::from_usize(jong).unwrap(),
-            c: c,
-        }
-    }
-}
+// a coverage record per block: entries moving left, right, up and down,
+// then for ㅊ the times it went on and the times it reversed
+const COVERAGE_SLOTS: usize = 6;
 
 struct AheuiBlock {
-    h: Hangul,
-    x: usize,
-    y: usize,
+    id: usize,
+    block: mir::Block,
     bld: BuilderRef,
     bb: BasicBlockRef,
+    // records this block as the failing one on the way to the error exit;
+    // made on the first check that needs it
+    fail_bb: Cell<Option<BasicBlockRef>>,
 }
 
 impl AheuiBlock {
     fn new(
-        h: Hangul, x: usize, y: usize, cx: ContextRef,
+        id: usize, block: mir::Block, cx: ContextRef,
         bld: BuilderRef, main_fn: ValueRef
     ) -> AheuiBlock {
-        let name = format!("aheui_bb_{}_{}", x, y);
+        let name = format!("aheui_bb_{}_{}", block.x, block.y);
         let this_bb = Aheui::append_bb(cx, main_fn, &name);
         AheuiBlock {
-            h: h,
-            x: x,
-            y: y,
+            id: id,
+            block: block,
             bld: bld,
             bb: this_bb,
+            fail_bb: Cell::new(None),
         }
     }
 
-    fn aheui_trace(&self, a: &Aheui) {
-        let (x, y) = (self.x, self.y);
+    // x, y and the character, for runtime calls that name the cell
+    fn cell_args(&self, a: &Aheui) -> (ValueRef, ValueRef, ValueRef) {
+        let (x, y) = (self.block.x, self.block.y);
         let (x, y) = (x as c_ulonglong, y as c_ulonglong);
         let (x, y) = unsafe {
             let x = llvm::LLVMConstInt(a.ty.i32_ty, x, 0);
             let y = llvm::LLVMConstInt(a.ty.i32_ty, y, 0);
             (x, y)
         };
-        let h = a.b.get_hangul(self.x, self.y);
-        let c = h.c as c_ulonglong;
+        let c = self.block.c as c_ulonglong;
         let c = unsafe { llvm::LLVMConstInt(a.ty.i32_ty, c, 0) };
-        let args = [x, y, c];
-        a.call_rt(a.rt.tr, &args, "");
+        (x, y, c)
     }
 
-    fn gen_bb(&self, a: &Aheui) {
-        use Cho::*;
-        use Jung::*;
-        use Jong::*;
+    // also used for aheui_hang_check, which takes the same arguments
+    fn aheui_trace(&self, a: &Aheui, f: ValueRef) {
+        let (x, y, c) = self.cell_args(a);
+        let fl = a.load(a.fl, "fl");
+        let cur = a.load(a.cur, "cur");
+        let args = [x, y, c, fl, cur];
+        a.call_rt(f, &args, "");
+    }
 
+    // steps += 1, leaving through the error exit past the limit
+    fn aheui_budget(&self, a: &Aheui, steps: ValueRef, limit: ValueRef) {
+        let (bx, by) = (self.block.x, self.block.y);
+        let over = Aheui::append_bb(a.cx, a.mf, &format!("aheui_over_{}_{}", bx, by));
+        let cont = Aheui::append_bb(a.cx, a.mf, &format!("aheui_cell_{}_{}", bx, by));
         unsafe {
-            llvm::LLVMPositionBuilderAtEnd(self.bld, self.bb);
+            let str_tmp = CString::new("steps").unwrap().as_ptr();
+            let v = a.load(steps, "steps");
+            let one = llvm::LLVMConstInt(a.ty.i64_ty, 1, 0);
+            let v = llvm::LLVMBuildAdd(a.bld, v, one, str_tmp);
+            llvm::LLVMBuildStore(a.bld, v, steps);
+            let limit = a.load(limit, "limit");
+            let cmp = llvm::LLVMBuildICmp(a.bld, IntUGT as c_uint, v, limit, str_tmp);
+            llvm::LLVMBuildCondBr(a.bld, cmp, over, cont);
+
+            llvm::LLVMPositionBuilderAtEnd(a.bld, over);
+            self.record_cell(a);
+            a.call_rt(a.rt.se, &[limit], "");
+            llvm::LLVMBuildBr(a.bld, a.err_bb);
+
+            llvm::LLVMPositionBuilderAtEnd(a.bld, cont);
         }
-        self.aheui_trace(a);
+    }
 
-        let f = 0;
-        let comp_init = unsafe { llvm::LLVMConstInt(a.ty.i1_ty, f, 0) };
-        unsafe { llvm::LLVMBuildStore(a.bld, comp_init, a.comp); }
+    // acts on a signal the runtime has caught. Done where loops go back, so
+    // that a loop which never calls the runtime can still be stopped.
+    fn aheui_poll(&self, a: &Aheui) {
+        let (bx, by) = (self.block.x, self.block.y);
+        let sig = Aheui::append_bb(a.cx, a.mf, &format!("aheui_signal_{}_{}", bx, by));
+        let cont = Aheui::append_bb(a.cx, a.mf, &format!("aheui_polled_{}_{}", bx, by));
+        unsafe {
+            let str_tmp = CString::new("signal").unwrap().as_ptr();
+            // the handler stores it, which the loop must see
+            let v = llvm::LLVMBuildLoad(a.bld, a.rt.sg, str_tmp);
+            llvm::LLVMSetVolatile(v, True);
+            let zero = llvm::LLVMConstInt(llvm::LLVMTypeOf(v), 0, 0);
+            let cmp = llvm::LLVMBuildICmp(a.bld, IntNE as c_uint, v, zero, str_tmp);
+            llvm::LLVMBuildCondBr(a.bld, cmp, sig, cont);
+
+            llvm::LLVMPositionBuilderAtEnd(a.bld, sig);
+            // for the dump
+            self.record_cell(a);
+            a.call_rt(a.rt.sp, &[], "");
+            self.check_failed(a);
+            llvm::LLVMBuildBr(a.bld, cont);
+
+            llvm::LLVMPositionBuilderAtEnd(a.bld, cont);
+        }
+    }
 
+    // counts[i] += 1
+    fn aheui_count(&self, a: &Aheui, counts: ValueRef, i: ValueRef) {
+        unsafe {
+            let str_tmp = CString::new("count").unwrap().as_ptr();
+            let idx = [i];
+            let p = llvm::LLVMBuildGEP(a.bld, counts, idx.as_ptr(), 1, str_tmp);
+            let v = a.load(p, "count");
+            let one = llvm::LLVMConstInt(a.ty.i64_ty, 1, 0);
+            let v = llvm::LLVMBuildAdd(a.bld, v, one, str_tmp);
+            llvm::LLVMBuildStore(a.bld, v, p);
+        }
+    }
+
+    fn gen_inst(&self, a: &Aheui, inst: Inst) {
+        // reloaded per instruction, since a select may precede
         let cur = a.load(a.cur, "cur");
 
         let str_ret = CString::new("ret").unwrap().as_ptr();
         let str_cmp = CString::new("cmp").unwrap().as_ptr();
 
-        match self.h.cho {
-            cㄴ => {
+        match inst {
+            Inst::Div | Inst::Add | Inst::Mul | Inst::Rem | Inst::Compare | Inst::Sub => {
                 let v1 = a.call_rt(a.rt.po, &[cur], "");
                 let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let ret = unsafe { llvm::LLVMBuildUDiv(a.bld, v2, v1, str_ret) };
-                a.call_rt(a.rt.pu, &[cur, ret], "");
-            },
-            cㄷ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let ret = unsafe { llvm::LLVMBuildAdd(a.bld, v1, v2, str_ret) };
-                a.call_rt(a.rt.pu, &[cur, ret], "");
-            },
-            cㄸ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let ret = unsafe { llvm::LLVMBuildMul(a.bld, v1, v2, str_ret) };
-                a.call_rt(a.rt.pu, &[cur, ret], "");
-            },
-            cㄹ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let ret = unsafe { llvm::LLVMBuildURem(a.bld, v2, v1, str_ret) };
-                a.call_rt(a.rt.pu, &[cur, ret], "");
-            },
-            cㅁ => {
-                let ret = a.call_rt(a.rt.po, &[cur], "");
-                match self.h.jong {
-                    jㅇ => {
-                        a.call_rt(a.rt.pi, &[ret], "");
-                    },
-                    jㅎ => {
-                        a.call_rt(a.rt.pc, &[ret], "");
-                    },
-                    _ => {},
-                }
-            },
-            cㅂ => {
-                let ret = match self.h.jong {
-                    jㅇ => {
-                        a.call_rt(a.rt.gi, &[], "ret")
-                    },
-                    jㅎ => {
-                        a.call_rt(a.rt.gc, &[], "ret")
-                    },
-                    _ => {
-                        let j = self.h.jong.val() as c_ulonglong;
-                        unsafe { llvm::LLVMConstInt(a.ty.i32_ty, j, 0) }
+                let v1 = match inst {
+                    Inst::Div | Inst::Rem => a.call_rt(a.rt.dv, &[v1], ""),
+                    _ => v1,
+                };
+                let ret = unsafe {
+                    match inst {
+                        Inst::Div => llvm::LLVMBuildUDiv(a.bld, v2, v1, str_ret),
+                        Inst::Add => llvm::LLVMBuildAdd(a.bld, v1, v2, str_ret),
+                        Inst::Mul => llvm::LLVMBuildMul(a.bld, v1, v2, str_ret),
+                        Inst::Rem => llvm::LLVMBuildURem(a.bld, v2, v1, str_ret),
+                        Inst::Compare => {
+                            let op = IntULE as c_uint;
+                            let cmp = llvm::LLVMBuildICmp(a.bld, op, v1, v2, str_cmp);
+                            llvm::LLVMBuildZExt(a.bld, cmp, a.ty.i32_ty, str_ret)
+                        },
+                        _ => llvm::LLVMBuildSub(a.bld, v2, v1, str_ret),
                     }
                 };
                 a.call_rt(a.rt.pu, &[cur, ret], "");
             },
-            cㅃ => {
-                a.call_rt(a.rt.du, &[cur], "");
+            Inst::Pop => {
+                a.call_rt(a.rt.po, &[cur], "");
             },
-            cㅅ => {
-                let j = self.h.jong as c_ulonglong;
-                let ncur = unsafe { llvm::LLVMConstInt(a.ty.i8_ty, j, 0) };
-                unsafe { llvm::LLVMBuildStore(a.bld, ncur, a.cur); }
+            Inst::PutInt => {
+                let ret = a.call_rt(a.rt.po, &[cur], "");
+                // nothing is printed for a failed pop
+                self.check_failed(a);
+                a.call_rt(a.rt.pi, &[ret], "");
             },
-            cㅆ => {
-                let v = a.call_rt(a.rt.po, &[cur], "");
-                let j = self.h.jong as c_ulonglong;
-                let ncur = unsafe { llvm::LLVMConstInt(a.ty.i8_ty, j, 0) };
-                a.call_rt(a.rt.pu, &[ncur, v], "");
+            Inst::PutChar => {
+                let ret = a.call_rt(a.rt.po, &[cur], "");
+                self.check_failed(a);
+                a.call_rt(a.rt.pc, &[ret], "");
             },
-            cㅈ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let op = IntULE as c_uint;
-                let cmp = unsafe { llvm::LLVMBuildICmp(a.bld, op, v1, v2, str_cmp) };
-                let ret = unsafe { llvm::LLVMBuildZExt(a.bld, cmp, a.ty.i32_ty, str_ret) };
+            Inst::Push(v) => {
+                let ret = unsafe { llvm::LLVMConstInt(a.ty.i32_ty, v as c_ulonglong, 0) };
                 a.call_rt(a.rt.pu, &[cur, ret], "");
             },
-            cㅊ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = unsafe { llvm::LLVMConstInt(a.ty.i32_ty, 0, 0) };
-                let op = IntEQ as c_uint;
-                let cmp = unsafe { llvm::LLVMBuildICmp(a.bld, op, v1, v2, str_cmp) };
-                unsafe {
-                    llvm::LLVMBuildStore(a.bld, cmp, a.comp);
-                }
+            Inst::GetInt => {
+                let ret = a.call_rt(a.rt.gi, &[], "ret");
+                a.call_rt(a.rt.pu, &[cur, ret], "");
             },
-            cㅌ => {
-                let v1 = a.call_rt(a.rt.po, &[cur], "");
-                let v2 = a.call_rt(a.rt.po, &[cur], "");
-                let ret = unsafe { llvm::LLVMBuildSub(a.bld, v2, v1, str_ret) };
+            Inst::GetChar => {
+                let ret = a.call_rt(a.rt.gc, &[], "ret");
                 a.call_rt(a.rt.pu, &[cur, ret], "");
             },
-            cㅍ => {
+            Inst::Dup => {
+                a.call_rt(a.rt.du, &[cur], "");
+            },
+            Inst::Swap => {
                 a.call_rt(a.rt.sw, &[cur], "");
             },
-            cㅎ => {
-                unsafe {
-                    llvm::LLVMBuildRetVoid(self.bld);
-                }
-                return;
+            Inst::Select(s) => {
+                let ncur = unsafe { llvm::LLVMConstInt(a.ty.i8_ty, s as c_ulonglong, 0) };
+                unsafe { llvm::LLVMBuildStore(a.bld, ncur, a.cur); }
             },
-            cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {
-                // nothing
+            Inst::Move(s) => {
+                let v = a.call_rt(a.rt.po, &[cur], "");
+                let ncur = unsafe { llvm::LLVMConstInt(a.ty.i8_ty, s as c_ulonglong, 0) };
+                a.call_rt(a.rt.pu, &[ncur, v], "");
             },
         }
+    }
 
-        let comp = a.load(a.comp, "comp_v");
-        match self.h.jung {
-            ㅏ | ㅓ | ㅗ | ㅜ | ㅑ | ㅕ | ㅛ | ㅠ => {
-                let flow = Flow::from_jung(self.h.jung).unwrap();
+    // tells the runtime which cell this is, for error messages and dumps;
+    // only on the way out of the normal path, which never needs it
+    fn record_cell(&self, a: &Aheui) {
+        unsafe {
+            let str_tmp = CString::new("cell").unwrap().as_ptr();
+            let idx = [a.const_i32(0), a.const_i32(0)];
+            let p = llvm::LLVMBuildGEP(a.bld, a.cell, idx.as_ptr(), 2, str_tmp);
+            llvm::LLVMBuildStore(a.bld, a.const_i32(self.id), p);
+        }
+    }
 
-                let next_pos = |flow| {
-                    match self.h.jung {
-                        ㅏ | ㅓ | ㅗ | ㅜ => a.next_pos(self.x, self.y, flow),
-                        ㅑ | ㅕ | ㅛ | ㅠ => {
-                            let (nx, ny) = a.next_pos(self.x, self.y, flow);
-                            a.next_pos(nx, ny, flow)
-                        },
-                        _ => panic!("???"),
-                    }
-                };
-                let (nx, ny) = next_pos(flow);
-                let (rx, ry) = next_pos(flow.reverse());
-                let dest_bb = a.b.get_bb(nx, ny);
-                let r_dest_bb = a.b.get_bb(rx, ry);
+    // on to the common error exit if a runtime call has failed
+    fn check_failed(&self, a: &Aheui) {
+        let (bx, by) = (self.block.x, self.block.y);
+        let cont = Aheui::append_bb(a.cx, a.mf, &format!("aheui_ok_{}_{}", bx, by));
+        let fail = match self.fail_bb.get() {
+            Some(bb) => bb,
+            None => Aheui::append_bb(a.cx, a.mf, &format!("aheui_fail_{}_{}", bx, by)),
+        };
+        unsafe {
+            let str_tmp = CString::new("failed").unwrap().as_ptr();
+            let idx = [a.const_i32(0), a.const_i32(1)];
+            let p = llvm::LLVMBuildGEP(a.bld, a.cell, idx.as_ptr(), 2, str_tmp);
+            let failed = a.load(p, "failed");
+            let str_cmp = CString::new("cmp").unwrap().as_ptr();
+            let cmp = llvm::LLVMBuildICmp(a.bld, IntNE as c_uint, failed, a.const_i32(0), str_cmp);
+            llvm::LLVMBuildCondBr(a.bld, cmp, fail, cont);
+            if self.fail_bb.get().is_none() {
+                self.fail_bb.set(Some(fail));
+                llvm::LLVMPositionBuilderAtEnd(a.bld, fail);
+                self.record_cell(a);
+                llvm::LLVMBuildBr(a.bld, a.err_bb);
+            }
+            llvm::LLVMPositionBuilderAtEnd(a.bld, cont);
+        }
+    }
+
+    // pops the value branch and choose look at and counts the way taken;
+    // true for a zero
+    fn pop_zero(&self, a: &Aheui) -> ValueRef {
+        let cur = a.load(a.cur, "cur");
+        let v1 = a.call_rt(a.rt.po, &[cur], "");
+        let v2 = unsafe { llvm::LLVMConstInt(a.ty.i32_ty, 0, 0) };
+        let op = IntEQ as c_uint;
+        let str_cmp = CString::new("cmp").unwrap().as_ptr();
+        let cmp = unsafe { llvm::LLVMBuildICmp(a.bld, op, v1, v2, str_cmp) };
+        self.check_failed(a);
+
+        if let Some(counts) = a.coverage {
+            let base = self.id * COVERAGE_SLOTS;
+            let i = unsafe {
+                let str_tmp = CString::new("slot").unwrap().as_ptr();
+                let on = a.const_i32(base + 4);
+                let rev = a.const_i32(base + 5);
+                llvm::LLVMBuildSelect(a.bld, cmp, rev, on, str_tmp)
+            };
+            self.aheui_count(a, counts, i);
+        }
+        if a.instr.stats {
+            let kind = unsafe {
+                let str_tmp = CString::new("kind").unwrap().as_ptr();
+                let rev = a.const_i32(rt::STAT_REVERSED as usize);
+                let on = a.const_i32(rt::STAT_BRANCH as usize);
+                llvm::LLVMBuildSelect(a.bld, cmp, rev, on, str_tmp)
+            };
+            a.call_rt(a.rt.st, &[kind], "");
+        }
+        cmp
+    }
 
-                let fl = flow as c_ulonglong;
+    // stores and returns the flow after a non-directional vowel
+    fn reflect_flow(&self, a: &Aheui, reflect: Reflect) -> ValueRef {
+        let j = a.nfs[reflect as usize];
+
+        let v = a.load(a.fl, "aheui_flow_orig");
+        unsafe {
+            let str_tmp = CString::new("tmp").unwrap().as_ptr();
+            let z = 0 as c_ulonglong;
+            let c0 = llvm::LLVMConstInt(a.ty.i8_ty, z, 0);
+            let tmp = vec!(c0, v);
+            let nv = llvm::LLVMBuildGEP(a.bld, j, tmp.as_ptr(), 2, str_tmp);
+            let nv = a.load(nv, "aheui_flow_nv");
+            llvm::LLVMBuildStore(a.bld, nv, a.fl);
+            nv
+        }
+    }
+
+    // to the target for the direction `v`
+    fn switch_flow(&self, a: &Aheui, v: ValueRef, targets: &[usize; 4]) {
+        let nps: Vec<_> = targets.iter().map(|t| a.get_bb(*t)).collect();
+
+        let sw = unsafe {
+            llvm::LLVMBuildSwitch(a.bld, v, nps[3], 3 as c_uint)
+        };
+        for (i, nbb) in nps.iter().take(3).enumerate() {
+            unsafe {
+                let j = i as c_ulonglong;
+                let c = llvm::LLVMConstInt(a.ty.i8_ty, j, 0);
+                llvm::LLVMAddCase(sw, c, *nbb);
+            }
+        }
+    }
+
+    fn store_flow(&self, a: &Aheui, flow: Flow) {
+        unsafe {
+            let fl_i8 = llvm::LLVMConstInt(a.ty.i8_ty, flow as c_ulonglong, 0);
+            llvm::LLVMBuildStore(a.bld, fl_i8, a.fl);
+        }
+    }
+
+    fn gen_bb(&self, a: &Aheui) {
+        unsafe {
+            llvm::LLVMPositionBuilderAtEnd(self.bld, self.bb);
+        }
+        if a.loops[self.id] {
+            self.aheui_poll(a);
+        }
+        if a.instr.trace {
+            self.aheui_trace(a, a.rt.tr);
+        }
+        if let Some((steps, limit)) = a.steps {
+            self.aheui_budget(a, steps, limit);
+        }
+        if a.instr.hangs {
+            self.aheui_trace(a, a.rt.hc);
+            self.check_failed(a);
+        }
+        if let Some(counts) = a.profile {
+            self.aheui_count(a, counts, a.const_i32(self.id));
+        }
+        if a.instr.stats {
+            a.call_rt(a.rt.st, &[a.const_i32(rt::STAT_STEP as usize)], "");
+        }
+        if let Some(counts) = a.coverage {
+            let fl = a.load(a.fl, "fl");
+            let i = unsafe {
+                let str_tmp = CString::new("slot").unwrap().as_ptr();
+                let fl = llvm::LLVMBuildZExt(a.bld, fl, a.ty.i32_ty, str_tmp);
+                let base = a.const_i32(self.id * COVERAGE_SLOTS);
+                llvm::LLVMBuildAdd(a.bld, base, fl, str_tmp)
+            };
+            self.aheui_count(a, counts, i);
+        }
+
+        for inst in self.block.insts.iter() {
+            if a.instr.stats {
+                a.call_rt(a.rt.st, &[a.const_i32(stat_kind(*inst) as usize)], "");
+            }
+            self.gen_inst(a, *inst);
+        }
+        // branch, choose and halt call the runtime once more and check after that
+        let calls = self.block.insts.iter().any(|i| match *i {
+            Inst::Select(_) => false,
+            _ => true,
+        });
+        let checked_later = match self.block.term {
+            Term::Branch(..) | Term::Choose(..) | Term::Halt => true,
+            _ => false,
+        };
+        if calls && !checked_later {
+            self.check_failed(a);
+        }
+
+        match self.block.term {
+            Term::Jump(flow, target) => {
+                self.store_flow(a, flow);
                 unsafe {
-                    let fl_i8 = llvm::LLVMConstInt(a.ty.i8_ty, fl, 0);
-                    llvm::LLVMBuildStore(a.bld, fl_i8, a.fl);
+                    llvm::LLVMBuildBr(a.bld, a.get_bb(target));
                 }
+            },
+            Term::Branch(flow, target, zero) => {
+                let cmp = self.pop_zero(a);
+                self.store_flow(a, flow);
                 unsafe {
-                    llvm::LLVMBuildCondBr(a.bld, comp, r_dest_bb, dest_bb);
+                    llvm::LLVMBuildCondBr(a.bld, cmp, a.get_bb(zero), a.get_bb(target));
                 };
             },
-            _ => {
-                // TODO: flow rework
-                if self.h.cho == cㅊ {
-                    unimplemented!()
-                }
-
-                let j = match self.h.jung {
-                    ㅣ => a.nfs[1],
-                    ㅡ => a.nfs[2],
-                    ㅢ => a.nfs[3],
-                    _  => a.nfs[0],
+            Term::Switch(reflect, ref targets) => {
+                let nv = self.reflect_flow(a, reflect);
+                self.switch_flow(a, nv, targets);
+            },
+            Term::Choose(reflect, ref targets) => {
+                let cmp = self.pop_zero(a);
+                let nv = self.reflect_flow(a, reflect);
+                // a zero reverses the move but not the flow; flows pair up as
+                // left and right, up and down
+                let mv = unsafe {
+                    let str_tmp = CString::new("aheui_flow_mv").unwrap().as_ptr();
+                    let rev = llvm::LLVMBuildZExt(a.bld, cmp, a.ty.i8_ty, str_tmp);
+                    llvm::LLVMBuildXor(a.bld, nv, rev, str_tmp)
                 };
-
-                let v = a.load(a.fl, "aheui_flow_orig");
-                unsafe {
-                    let str_tmp = CString::new("tmp").unwrap().as_ptr();
-                    let z = 0 as c_ulonglong;
-                    let c0 = llvm::LLVMConstInt(a.ty.i8_ty, z, 0);
-                    let tmp = vec!(c0, v);
-                    let nv = llvm::LLVMBuildGEP(a.bld, j, tmp.as_ptr(), 2, str_tmp);
-                    let nv = a.load(nv, "aheui_flow_nv");
-                    llvm::LLVMBuildStore(a.bld, nv, a.fl);
+                self.switch_flow(a, mv, targets);
+            },
+            Term::Halt => {
+                if a.profile.is_some() {
+                    a.write_counts(a.rt.pw);
                 }
-
-                let nps: Vec<_> = vec!(
-                    a.next_pos(self.x, self.y, Flow::Left),
-                    a.next_pos(self.x, self.y, Flow::Right),
-                    a.next_pos(self.x, self.y, Flow::Up),
-                    a.next_pos(self.x, self.y, Flow::Down),
-                ).into_iter().map(|(nx, ny)| a.b.get_bb(nx, ny)).collect();
-
-                let r = a.load(a.fl, "aheui_flow_v");
-                let sw = unsafe {
-                    llvm::LLVMBuildSwitch(a.bld, r, nps[3], 3 as c_uint)
-                };
-                for (i, nbb) in nps.iter().take(3).enumerate() {
-                    unsafe {
-                        let j = i as c_ulonglong;
-                        let c = llvm::LLVMConstInt(a.ty.i8_ty, j, 0);
-                        llvm::LLVMAddCase(sw, c, *nbb);
-                    }
+                if a.coverage.is_some() {
+                    a.write_counts(a.rt.cw);
+                }
+                let cur = a.load(a.cur, "cur");
+                let ret = a.call_rt(a.rt.ha, &[cur], "ret");
+                self.check_failed(a);
+                unsafe {
+                    llvm::LLVMBuildRet(self.bld, ret);
                 }
             },
         }
@@ -376,41 +479,84 @@ struct AheuiRt {
     po: ValueRef,
     du: ValueRef,
     sw: ValueRef,
-}
-
-type AheuiMapImpl = Vec<Vec<Box<AheuiBlock>>>;
-
-trait AheuiMap {
-    fn get_bb(&self, x: usize, y: usize) -> BasicBlockRef;
-    fn get_hangul<'a>(&'a self, x: usize, y: usize) -> &'a Hangul;
-}
-
-impl AheuiMap for AheuiMapImpl {
-    fn get_bb(&self, x: usize, y: usize) -> BasicBlockRef {
-        self[y][x].bb
-    }
-
-    fn get_hangul<'a>(&'a self, x: usize, y: usize) -> &'a Hangul {
-        &self[y][x].h
-    }
+    ha: ValueRef,
+    pa: ValueRef,
+    ca: ValueRef,
+    pw: ValueRef,
+    cw: ValueRef,
+    sl: ValueRef,
+    se: ValueRef,
+    hc: ValueRef,
+    cr: ValueRef,
+    ee: ValueRef,
+    dv: ValueRef,
+    ss: ValueRef,
+    st: ValueRef,
+    sp: ValueRef,
+    // rt::aheui_signal
+    sg: ValueRef,
 }
 
 struct Types {
-    i1_ty: TypeRef,
     i8_ty: TypeRef,
     i32_ty: TypeRef,
+    i64_ty: TypeRef,
 }
 
 struct Aheui {
-    b: AheuiMapImpl,
+    b: Vec<AheuiBlock>,
+    cx: ContextRef,
+    mf: ValueRef,
     bld: BuilderRef,
     md: ModuleRef,
     rt: AheuiRt,
+    // the entry function's runtime context
+    ctx: ValueRef,
     fl: ValueRef,
     cur: ValueRef,
-    comp: ValueRef,
     nfs: Vec<ValueRef>,
     ty: Types,
+    instr: Instrument,
+    // the block running and whether a runtime call has failed; see
+    // rt::CellRecord
+    cell: ValueRef,
+    // returns aheui_error_exit()
+    err_bb: BasicBlockRef,
+    // the (x, y) of every block, for the runtime to label counters with
+    pos: Option<ValueRef>,
+    // counters per block for --profile, COVERAGE_SLOTS per block for
+    // --coverage; the runtime keeps them in the context
+    profile: Option<ValueRef>,
+    coverage: Option<ValueRef>,
+    // the cells run so far and the limit for --max-steps
+    steps: Option<(ValueRef, ValueRef)>,
+    // blocks entered from themselves or a later block; every loop has one,
+    // and signals are polled there
+    loops: Vec<bool>,
+}
+
+fn declare_fn(md: ModuleRef, name: &str, ty: TypeRef) -> ValueRef {
+    let name = CString::new(name).unwrap().as_ptr();
+    unsafe { llvm::LLVMGetOrInsertFunction(md, name, ty) }
+}
+
+fn fn_ty(rt: TypeRef, par: &[TypeRef]) -> TypeRef {
+    unsafe {
+        llvm::LLVMFunctionType(
+            rt, par.as_ptr(),
+            par.len() as libc::c_uint, 0
+        )
+    }
+}
+
+// the counters of this run, from the runtime; `n` is the number of blocks
+fn new_counters(bld: BuilderRef, f: ValueRef, ctx: ValueRef, ty: TypeRef, n: usize,
+                name: &str) -> ValueRef {
+    let name = CString::new(name).unwrap().as_ptr();
+    unsafe {
+        let args = [ctx, llvm::LLVMConstInt(ty, n as c_ulonglong, 0)];
+        llvm::LLVMBuildCall(bld, f, args.as_ptr(), 2, name)
+    }
 }
 
 fn new_var(bld: BuilderRef, v: u8, ty: TypeRef, name: &str) -> ValueRef {
@@ -424,40 +570,21 @@ fn new_var(bld: BuilderRef, v: u8, ty: TypeRef, name: &str) -> ValueRef {
 }
 
 impl Aheui {
-    fn next_pos(&self, x: usize, y: usize, flow: Flow) -> (usize, usize) {
-        match flow {
-            Flow::Left | Flow::Right => {
-                let len = self.b[y].len();
-                let next_x = if flow == Flow::Left {
-                    (len + x - 1) % len
-                } else {
-                    (x + 1) % len
-                };
-                return (next_x, y);
-            }
-            Flow::Up | Flow::Down => {
-                if flow == Flow::Down {
-                    let ly = y + 1;
-                    let it = self.b.iter().enumerate().skip(ly);
-                    let it = it.chain(self.b.iter().enumerate().take(ly));
-                    for (cur_y, line) in it {
-                        if x < line.len() {
-                            return (x, cur_y);
-                        }
-                    }
-                    panic!("Failed to find next position");
-                } else {
-                    let ly = self.b.len() - y;
-                    let it = self.b.iter().rev().enumerate().skip(ly);
-                    let it = it.chain(self.b.iter().rev().enumerate().take(ly));
-                    for (cur_y, line) in it {
-                        if x < line.len() {
-                            return (x, self.b.len() - 1 - cur_y);
-                        }
-                    }
-                    panic!("Failed to find next position");
-                }
-            }
+    fn get_bb(&self, id: usize) -> BasicBlockRef {
+        self.b[id].bb
+    }
+
+    fn const_i32(&self, v: usize) -> ValueRef {
+        unsafe { llvm::LLVMConstInt(self.ty.i32_ty, v as c_ulonglong, 0) }
+    }
+
+    // hands a counter array to the runtime along with the block positions
+    fn write_counts(&self, f: ValueRef) {
+        unsafe {
+            let str_tmp = CString::new("tmp").unwrap().as_ptr();
+            let idx = [self.const_i32(0), self.const_i32(0)];
+            let pos = llvm::LLVMBuildGEP(self.bld, self.pos.unwrap(), idx.as_ptr(), 2, str_tmp);
+            self.call_rt(f, &[pos], "");
         }
     }
 
@@ -466,10 +593,13 @@ impl Aheui {
         unsafe { llvm::LLVMBuildLoad(self.bld, val, name) }
     }
 
+    // every runtime function takes the context first
     fn call_rt(&self, f: ValueRef, args: &[ValueRef], n: &str) -> ValueRef {
         let n = CString::new(n).unwrap().as_ptr();
+        let mut all = vec!(self.ctx);
+        all.extend(args.iter().cloned());
         unsafe {
-            llvm::LLVMBuildCall(self.bld, f, args.as_ptr(), args.len() as libc::c_uint, n)
+            llvm::LLVMBuildCall(self.bld, f, all.as_ptr(), all.len() as libc::c_uint, n)
         }
     }
 
@@ -478,7 +608,7 @@ impl Aheui {
         unsafe { llvm::LLVMAppendBasicBlockInContext(cx, f, name) }
     }
 
-    fn new(h: Vec<Vec<Hangul>>, md_name: &str, fn_name: &str) -> Aheui {
+    fn new(mir: mir::Mir, md_name: &str, fn_name: &str, instr: Instrument) -> Aheui {
         let md_name = CString::new(md_name).unwrap().as_ptr();
         let fn_name = CString::new(fn_name).unwrap().as_ptr();
 
@@ -486,65 +616,120 @@ impl Aheui {
         let md = unsafe { llvm::LLVMModuleCreateWithNameInContext(md_name, cx) };
         let bld = unsafe { llvm::LLVMCreateBuilderInContext(cx) };
 
-        let i1_ty = unsafe { llvm::LLVMInt1TypeInContext(cx) };
         let i8_ty = unsafe { llvm::LLVMInt8TypeInContext(cx) };
         let i32_ty = unsafe { llvm::LLVMInt32TypeInContext(cx) };
+        let i64_ty = unsafe { llvm::LLVMInt64TypeInContext(cx) };
         let void_ty = unsafe { llvm::LLVMVoidTypeInContext(cx) };
 
-        fn declare_fn(md: ModuleRef, name: &str, ty: TypeRef) -> ValueRef {
-            let name = CString::new(name).unwrap().as_ptr();
-            unsafe { llvm::LLVMGetOrInsertFunction(md, name, ty) }
-        }
-
-        fn fn_ty(rt: TypeRef, par: &[TypeRef]) -> TypeRef {
-            unsafe {
-                llvm::LLVMFunctionType(
-                    rt, par.as_ptr(),
-                    par.len() as libc::c_uint, 0
-                )
-            }
-        }
-
-        let main_ty = fn_ty(void_ty, &[]);
+        // the entry function takes the runtime context and hands it on to
+        // every runtime call, so that runs do not share storages
+        let ctx_ty = unsafe { llvm::LLVMPointerType(i8_ty, 0) };
+        let main_ty = fn_ty(i32_ty, &[ctx_ty]);
         let mf = unsafe { llvm::LLVMAddFunction(md, fn_name, main_ty) };
+        let ctx = unsafe { llvm::LLVMGetParam(mf, 0) };
+        let rt_ty = |ret: TypeRef, par: &[TypeRef]| {
+            let mut all = vec!(ctx_ty);
+            all.extend(par.iter().cloned());
+            fn_ty(ret, &all)
+        };
 
         // declare runtime functions
-        // extern "C" fn aheui_getchar() -> char
-        let gc_fn_ty = fn_ty(i32_ty, &[]);
+        // extern "C" fn aheui_getchar(ctx: *mut AheuiRt) -> i32
+        let gc_fn_ty = rt_ty(i32_ty, &[]);
         let gc_fn = declare_fn(md, "aheui_getchar", gc_fn_ty);
 
-        // extern "C" fn aheui_putchar(c: char)
-        let pc_fn_ty = fn_ty(void_ty, &[i32_ty]);
+        // extern "C" fn aheui_putchar(ctx: *mut AheuiRt, c: i32)
+        let pc_fn_ty = rt_ty(void_ty, &[i32_ty]);
         let pc_fn = declare_fn(md, "aheui_putchar", pc_fn_ty);
 
-        // extern "C" fn aheui_getint() -> i32
-        let gi_fn_ty = fn_ty(i32_ty, &[]);
+        // extern "C" fn aheui_getint(ctx: *mut AheuiRt) -> i32
+        let gi_fn_ty = rt_ty(i32_ty, &[]);
         let gi_fn = declare_fn(md, "aheui_getint", gi_fn_ty);
 
-        // extern "C" fn aheui_putint(i: i32)
-        let pi_fn_ty = fn_ty(void_ty, &[i32_ty]);
+        // extern "C" fn aheui_putint(ctx: *mut AheuiRt, i: i32)
+        let pi_fn_ty = rt_ty(void_ty, &[i32_ty]);
         let pi_fn = declare_fn(md, "aheui_putint", pi_fn_ty);
 
-        // extern "C" fn aheui_trace(x: i32, y: i32, c: char)
-        let tr_fn_ty = fn_ty(void_ty, &[i32_ty, i32_ty, i32_ty]);
+        // extern "C" fn aheui_trace(ctx: *mut AheuiRt, x: i32, y: i32, c: char, flow: i8, idx: i8)
+        let tr_fn_ty = rt_ty(void_ty, &[i32_ty, i32_ty, i32_ty, i8_ty, i8_ty]);
         let tr_fn = declare_fn(md, "aheui_trace", tr_fn_ty);
 
-        // extern "C" fn aheui_push(idx: i8, v: i32)
-        let pu_fn_ty = fn_ty(void_ty, &[i8_ty, i32_ty]);
+        // extern "C" fn aheui_push(ctx: *mut AheuiRt, idx: i8, v: i32)
+        let pu_fn_ty = rt_ty(void_ty, &[i8_ty, i32_ty]);
         let pu_fn = declare_fn(md, "aheui_push", pu_fn_ty);
 
-        // extern "C" fn aheui_pop(idx: i8) -> i32
-        let po_fn_ty = fn_ty(i32_ty, &[i8_ty]);
+        // extern "C" fn aheui_pop(ctx: *mut AheuiRt, idx: i8) -> i32
+        let po_fn_ty = rt_ty(i32_ty, &[i8_ty]);
         let po_fn = declare_fn(md, "aheui_pop", po_fn_ty);
 
-        // extern "C" fn aheui_dup(idx: i8)
-        let du_fn_ty = fn_ty(void_ty, &[i8_ty]);
+        // extern "C" fn aheui_dup(ctx: *mut AheuiRt, idx: i8)
+        let du_fn_ty = rt_ty(void_ty, &[i8_ty]);
         let du_fn = declare_fn(md, "aheui_dup", du_fn_ty);
 
-        // extern "C" fn aheui_swap(idx: i8)
-        let sw_fn_ty = fn_ty(void_ty, &[i8_ty]);
+        // extern "C" fn aheui_swap(ctx: *mut AheuiRt, idx: i8)
+        let sw_fn_ty = rt_ty(void_ty, &[i8_ty]);
         let sw_fn = declare_fn(md, "aheui_swap", sw_fn_ty);
 
+        // extern "C" fn aheui_halt(ctx: *mut AheuiRt, idx: i8) -> i32
+        let ha_fn_ty = rt_ty(i32_ty, &[i8_ty]);
+        let ha_fn = declare_fn(md, "aheui_halt", ha_fn_ty);
+
+        // extern "C" fn aheui_profile_counts(ctx: *mut AheuiRt, blocks: u32) -> *mut u64
+        let pa_fn_ty = unsafe { rt_ty(llvm::LLVMPointerType(i64_ty, 0), &[i32_ty]) };
+        let pa_fn = declare_fn(md, "aheui_profile_counts", pa_fn_ty);
+
+        // extern "C" fn aheui_coverage_counts(ctx: *mut AheuiRt, blocks: u32) -> *mut u64
+        let ca_fn = declare_fn(md, "aheui_coverage_counts", pa_fn_ty);
+
+        // extern "C" fn aheui_profile_write(ctx: *mut AheuiRt, pos: *const u32)
+        let pw_fn_ty = unsafe { rt_ty(void_ty, &[llvm::LLVMPointerType(i32_ty, 0)]) };
+        let pw_fn = declare_fn(md, "aheui_profile_write", pw_fn_ty);
+
+        // extern "C" fn aheui_coverage_write(ctx: *mut AheuiRt, pos: *const u32)
+        let cw_fn = declare_fn(md, "aheui_coverage_write", pw_fn_ty);
+
+        // extern "C" fn aheui_step_limit(ctx: *mut AheuiRt, n: i64) -> i64
+        let sl_fn_ty = rt_ty(i64_ty, &[i64_ty]);
+        let sl_fn = declare_fn(md, "aheui_step_limit", sl_fn_ty);
+
+        // extern "C" fn aheui_step_exceeded(ctx: *mut AheuiRt, limit: i64)
+        let se_fn_ty = rt_ty(void_ty, &[i64_ty]);
+        let se_fn = declare_fn(md, "aheui_step_exceeded", se_fn_ty);
+
+        // extern "C" fn aheui_hang_check(ctx: *mut AheuiRt, x: i32, y: i32, c: char, flow: i8, idx: i8)
+        let hc_fn = declare_fn(md, "aheui_hang_check", tr_fn_ty);
+
+        // extern "C" fn aheui_cell_record(ctx: *mut AheuiRt, state: *mut i32, cells: *const i32,
+        //                                   flow: *const i8, idx: *const i8)
+        let cr_fn_ty = unsafe {
+            let i8p_ty = llvm::LLVMPointerType(i8_ty, 0);
+            let i32p_ty = llvm::LLVMPointerType(i32_ty, 0);
+            rt_ty(void_ty, &[i32p_ty, i32p_ty, i8p_ty, i8p_ty])
+        };
+        let cr_fn = declare_fn(md, "aheui_cell_record", cr_fn_ty);
+
+        // extern "C" fn aheui_error_exit(ctx: *mut AheuiRt) -> i32
+        let ee_fn = declare_fn(md, "aheui_error_exit", rt_ty(i32_ty, &[]));
+
+        // extern "C" fn aheui_divisor(ctx: *mut AheuiRt, v: i32) -> i32
+        let dv_fn = declare_fn(md, "aheui_divisor", rt_ty(i32_ty, &[i32_ty]));
+
+        // extern "C" fn aheui_stats_start(ctx: *mut AheuiRt)
+        let ss_fn = declare_fn(md, "aheui_stats_start", rt_ty(void_ty, &[]));
+
+        // extern "C" fn aheui_stat(ctx: *mut AheuiRt, kind: i32)
+        let st_fn = declare_fn(md, "aheui_stat", rt_ty(void_ty, &[i32_ty]));
+
+        // extern "C" fn aheui_poll(ctx: *mut AheuiRt)
+        let sp_fn = declare_fn(md, "aheui_poll", rt_ty(void_ty, &[]));
+
+        // static aheui_signal: AtomicUsize
+        let sg = unsafe {
+            let usize_ty = if cfg!(target_pointer_width = "64") { i64_ty } else { i32_ty };
+            let name = CString::new("aheui_signal").unwrap().as_ptr();
+            llvm::LLVMAddGlobal(md, usize_ty, name)
+        };
+
         let rt = AheuiRt {
             gc: gc_fn,
             pc: pc_fn,
@@ -555,27 +740,98 @@ impl Aheui {
             po: po_fn,
             du: du_fn,
             sw: sw_fn,
+            ha: ha_fn,
+            pa: pa_fn,
+            ca: ca_fn,
+            pw: pw_fn,
+            cw: cw_fn,
+            sl: sl_fn,
+            se: se_fn,
+            hc: hc_fn,
+            cr: cr_fn,
+            ee: ee_fn,
+            dv: dv_fn,
+            ss: ss_fn,
+            st: st_fn,
+            sp: sp_fn,
+            sg: sg,
         };
 
         let main_bb = Aheui::append_bb(cx, mf, "aheui_top");
 
-        let mut b = Vec::new();
-        for (y, line) in h.into_iter().enumerate() {
-            let mut bl = Vec::new();
-            for (x, hangul) in line.into_iter().enumerate() {
-                let block = Box::new(AheuiBlock::new(hangul, x, y, cx, bld, mf));
-                bl.push(block);
+        // filled in by gen_llvm
+        let err_bb = Aheui::append_bb(cx, mf, "aheui_error");
+
+        let mut loops = vec!(false; mir.blocks.len());
+        for (id, block) in mir.blocks.iter().enumerate() {
+            for t in block.term.targets().into_iter().filter(|&t| t <= id) {
+                loops[t] = true;
             }
-            b.push(bl);
         }
 
+        let b: Vec<_> = mir.blocks.into_iter().enumerate().map(|(id, block)| {
+            AheuiBlock::new(id, block, cx, bld, mf)
+        }).collect();
+
         unsafe {
             llvm::LLVMPositionBuilderAtEnd(bld, main_bb);
         }
 
         let fl = new_var(bld, Flow::Down as u8, i8_ty, "aheui_flow");
         let cur = new_var(bld, Jong::joNone as u8, i8_ty, "aheui_cur");
-        let comp = new_var(bld, 0, i1_ty, "aheui_comp");
+        // per run, like the context
+        let cell = unsafe {
+            let cell_ty = llvm::LLVMRustArrayType(i32_ty, 2);
+            let str_cell = CString::new("aheui_cell").unwrap().as_ptr();
+            llvm::LLVMBuildAlloca(bld, cell_ty, str_cell)
+        };
+        // the (x, y, character) of every block, which the runtime looks the
+        // running one up in
+        let cells = unsafe {
+            let mut cs = Vec::new();
+            for block in b.iter() {
+                cs.push(llvm::LLVMConstInt(i32_ty, block.block.x as c_ulonglong, 0));
+                cs.push(llvm::LLVMConstInt(i32_ty, block.block.y as c_ulonglong, 0));
+                cs.push(llvm::LLVMConstInt(i32_ty, block.block.c as c_ulonglong, 0));
+            }
+            let cells_ty = llvm::LLVMRustArrayType(i32_ty, cs.len() as u64);
+            let name = CString::new("aheui_cells").unwrap().as_ptr();
+            let cells = llvm::LLVMAddGlobal(md, cells_ty, name);
+            llvm::LLVMSetGlobalConstant(cells, True);
+            let c = llvm::LLVMConstArray(i32_ty, cs.as_ptr(), cs.len() as c_uint);
+            llvm::LLVMSetInitializer(cells, c);
+            cells
+        };
+        unsafe {
+            let str_tmp = CString::new("cell").unwrap().as_ptr();
+            let zero = llvm::LLVMConstInt(i32_ty, 0, 0);
+            let one = llvm::LLVMConstInt(i32_ty, 1, 0);
+            let none = llvm::LLVMConstInt(i32_ty, -1i64 as c_ulonglong, 0);
+            let idx = [zero, zero];
+            let p = llvm::LLVMBuildGEP(bld, cell, idx.as_ptr(), 2, str_tmp);
+            llvm::LLVMBuildStore(bld, none, p);
+            let failed = [zero, one];
+            let f = llvm::LLVMBuildGEP(bld, cell, failed.as_ptr(), 2, str_tmp);
+            llvm::LLVMBuildStore(bld, zero, f);
+            let cs = llvm::LLVMBuildGEP(bld, cells, idx.as_ptr(), 2, str_tmp);
+            let str_none = CString::new("").unwrap().as_ptr();
+            let args = [ctx, p, cs, fl, cur];
+            llvm::LLVMBuildCall(bld, cr_fn, args.as_ptr(), 5, str_none);
+            if instr.stats {
+                llvm::LLVMBuildCall(bld, ss_fn, [ctx].as_ptr(), 1, str_none);
+            }
+        }
+        let steps = instr.max_steps.map(|n| {
+            let steps = new_var(bld, 0, i64_ty, "aheui_steps");
+            let limit = new_var(bld, 0, i64_ty, "aheui_step_limit");
+            unsafe {
+                let str_tmp = CString::new("limit").unwrap().as_ptr();
+                let n = [ctx, llvm::LLVMConstInt(i64_ty, n as c_ulonglong, 0)];
+                let v = llvm::LLVMBuildCall(bld, sl_fn, n.as_ptr(), 2, str_tmp);
+                llvm::LLVMBuildStore(bld, v, limit);
+            }
+            (steps, limit)
+        });
 
         let i8_arr_ty = unsafe { llvm::LLVMRustArrayType(i8_ty, 4) };
         let nfs = unsafe {
@@ -599,33 +855,97 @@ impl Aheui {
             }).collect()
         };
 
-        let start_bb = b.get_bb(0, 0);
+        let pos = if instr.profile || instr.coverage {
+            unsafe {
+                let mut ps = Vec::new();
+                for block in b.iter() {
+                    ps.push(llvm::LLVMConstInt(i32_ty, block.block.x as c_ulonglong, 0));
+                    ps.push(llvm::LLVMConstInt(i32_ty, block.block.y as c_ulonglong, 0));
+                }
+                let pos_ty = llvm::LLVMRustArrayType(i32_ty, ps.len() as u64);
+                let name = CString::new("aheui_block_pos").unwrap().as_ptr();
+                let pos = llvm::LLVMAddGlobal(md, pos_ty, name);
+                llvm::LLVMSetGlobalConstant(pos, True);
+                let c = llvm::LLVMConstArray(i32_ty, ps.as_ptr(), ps.len() as c_uint);
+                llvm::LLVMSetInitializer(pos, c);
+                Some(pos)
+            }
+        } else {
+            None
+        };
+        let profile = if instr.profile {
+            Some(new_counters(bld, pa_fn, ctx, i32_ty, b.len(), "aheui_profile_counts"))
+        } else {
+            None
+        };
+        let coverage = if instr.coverage {
+            Some(new_counters(bld, ca_fn, ctx, i32_ty, b.len(), "aheui_coverage_counts"))
+        } else {
+            None
+        };
+
+        // the context may have failed already, on a mistake in the environment
+        let start_bb = b[0].bb;
         unsafe {
-            llvm::LLVMBuildBr(bld, start_bb);
+            let str_tmp = CString::new("failed").unwrap().as_ptr();
+            let zero = llvm::LLVMConstInt(i32_ty, 0, 0);
+            let one = llvm::LLVMConstInt(i32_ty, 1, 0);
+            let idx = [zero, one];
+            let p = llvm::LLVMBuildGEP(bld, cell, idx.as_ptr(), 2, str_tmp);
+            let failed = llvm::LLVMBuildLoad(bld, p, str_tmp);
+            let cmp = llvm::LLVMBuildICmp(bld, IntNE as c_uint, failed, zero, str_tmp);
+            llvm::LLVMBuildCondBr(bld, cmp, err_bb, start_bb);
         }
 
         Aheui {
             b: b,
+            cx: cx,
+            mf: mf,
             bld: bld,
             md: md,
             rt: rt,
+            ctx: ctx,
             fl: fl,
             cur: cur,
-            comp: comp,
             nfs: nfs,
             ty: Types {
-                i1_ty: i1_ty,
                 i8_ty: i8_ty,
                 i32_ty: i32_ty,
+                i64_ty: i64_ty,
             },
+            instr: instr,
+            cell: cell,
+            err_bb: err_bb,
+            pos: pos,
+            profile: profile,
+            coverage: coverage,
+            steps: steps,
+            loops: loops,
+        }
+    }
+
+    // runs stopped by an error or a limit write their profile and coverage
+    // too, the same as on ㅎ
+    fn gen_err(&self) {
+        unsafe {
+            llvm::LLVMPositionBuilderAtEnd(self.bld, self.err_bb);
+        }
+        if self.profile.is_some() {
+            self.write_counts(self.rt.pw);
+        }
+        if self.coverage.is_some() {
+            self.write_counts(self.rt.cw);
+        }
+        let ret = self.call_rt(self.rt.ee, &[], "ret");
+        unsafe {
+            llvm::LLVMBuildRet(self.bld, ret);
         }
     }
 
     fn gen_llvm(&self) {
-        for bl in self.b.iter() {
-            for b in bl.iter() {
-                b.gen_bb(self);
-            }
+        self.gen_err();
+        for b in self.b.iter() {
+            b.gen_bb(self);
         }
     }
 
@@ -638,22 +958,77 @@ impl Aheui {
     }
 }
 
+fn read_bytes(in_fn: &str) -> Vec<u8> {
+    use std::io::Read;
+
+    let mut reader = File::open(&Path::new(in_fn)).unwrap();
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes).unwrap();
+    bytes
+}
+
+fn read_code(in_fn: &str) -> Vec<Vec<Hangul>> {
+    use std::io::Read;
+
+    let path = Path::new(in_fn);
+    let mut reader = File::open(&path).unwrap();
+    let mut code = String::new();
+    reader.read_to_string(&mut code).unwrap();
+    parse_code(&code)
+}
+
+fn write_output(out_fn: &str, s: &str) {
+    write_output_bytes(out_fn, s.as_bytes());
+}
+
+fn write_output_bytes(out_fn: &str, bytes: &[u8]) {
+    use std::io::Write;
+
+    let mut f = File::create(&Path::new(out_fn)).unwrap();
+    f.write_all(bytes).unwrap();
+}
+
 fn print_usage(prog: &str) {
-    println!("Usage: {} [OPTIONS] INPUT", prog);
+    println!("Usage: {} [compile] [OPTIONS] INPUT (source or .mir)", prog);
+    println!("       {} jit [OPTIONS] INPUT", prog);
+    println!("       {} run [--tiered] INPUT (.ahbc bytecode or source)", prog);
+    println!("       {} profile INPUT [PROFILE] (default to aheui.prof)", prog);
+    println!("       {} coverage [-o LCOV] INPUT [COVERAGE] (default to aheui.cov)", prog);
+    println!("       {} tui [--input FILE] INPUT", prog);
+    println!("       {} record [-o RECORDING] [--trace] INPUT (default to aheui.rec)", prog);
+    println!("       {} replay INPUT [RECORDING] (default to aheui.rec)", prog);
+    println!("       {} trace-diff TRACE TRACE", prog);
     println!("Options:");
-    println!("\t-o [OUTPUT] (default to [INPUT].ll)");
+    println!("\t-o [OUTPUT] (default to [INPUT].[ll|c|rs|wasm|wat|ahbc|mir])");
     println!("\t-m [OUTPUT] (default to aheui_main)");
+    println!("\t--emit=[llvm-ir|c|rust|wasm|wat|bytecode|mir] (default to llvm-ir)");
+    println!("\t--trace (llvm-ir, jit and record; JSON Lines to $AHEUI_TRACE or stderr)");
+    println!("\t--profile (llvm-ir and jit; counts to $AHEUI_PROFILE or aheui.prof)");
+    println!("\t--coverage (llvm-ir and jit; states to $AHEUI_COVERAGE or aheui.cov)");
+    println!("\t--debug (jit; stop at a debugger prompt, see runtime_debug.rs for llvm-ir)");
+    println!("\t--max-steps [N] (llvm-ir and jit; exit after N cells, or $AHEUI_MAX_STEPS)");
+    println!("\t--detect-hangs (llvm-ir and jit; exit when a state repeats without I/O)");
+    println!("\t--stats (llvm-ir and jit; counts to stderr at exit with $AHEUI_STATS=1)");
+    println!("\t--input [FILE] (tui; what the program reads, default to nothing)");
     println!("\t-h");
 }
 
 fn main() {
-    use std::io::Read;
-
     let args: Vec<String> = std::env::args().collect();
 
     let opts = vec!(
         getopts::optopt("o", "", "", ""),
         getopts::optopt("m", "", "", ""),
+        getopts::optopt("", "emit", "", ""),
+        getopts::optopt("", "input", "", ""),
+        getopts::optflag("", "tiered", ""),
+        getopts::optflag("", "trace", ""),
+        getopts::optflag("", "profile", ""),
+        getopts::optflag("", "coverage", ""),
+        getopts::optflag("", "debug", ""),
+        getopts::optopt("", "max-steps", "", ""),
+        getopts::optflag("", "detect-hangs", ""),
+        getopts::optflag("", "stats", ""),
         getopts::optflag("h", "help", ""),
     );
     let matches = match getopts::getopts(&args[1..], opts.as_slice()) {
@@ -666,14 +1041,39 @@ fn main() {
         return;
     }
 
-    let in_fn: &str = if !matches.free.is_empty() {
-        &matches.free[0]
+    let mut free = matches.free.clone();
+    let cmds = ["compile", "jit", "run", "profile", "coverage", "tui", "record", "replay",
+                "trace-diff"];
+    let has_cmd = free.len() > 1 && cmds.contains(&&free[0][..]);
+    let cmd = if has_cmd {
+        free.remove(0)
+    } else {
+        "compile".to_string()
+    };
+
+    let in_fn: &str = if !free.is_empty() {
+        &free[0]
     } else {
         print_usage(&args[0]);
         return;
     };
 
-    let out_fn = in_fn.to_string() + ".ll";
+    let emit = match matches.opt_str("emit") {
+        Some(e) => e,
+        None => "llvm-ir".to_string(),
+    };
+    let ext = match &emit[..] {
+        "llvm-ir" => "ll",
+        "c" => "c",
+        "rust" => "rs",
+        "wasm" => "wasm",
+        "wat" => "wat",
+        "bytecode" => "ahbc",
+        "mir" => "mir",
+        _ => panic!("unknown --emit target: {}", emit),
+    };
+
+    let out_fn = in_fn.to_string() + "." + ext;
     let out_fn = match matches.opt_str("o") {
         Some(o) => o,
         None => out_fn,
@@ -684,44 +1084,250 @@ fn main() {
         None => "aheui_main".to_string(),
     };
 
-    let path = Path::new(in_fn);
-    let mut reader = File::open(&path).unwrap();
-    let mut code = String::new();
-    reader.read_to_string(&mut code).unwrap();
-    let code_iter = code.lines().map(|line| {
-        line.chars().map(Hangul::from_char).collect::<Vec<Hangul>>()
+    if cmd == "run" {
+        let bytes = read_bytes(in_fn);
+        let prog = if bytecode::is_bytecode(&bytes) {
+            match bytecode::decode(&bytes) {
+                Ok(prog) => prog,
+                Err(e) => panic!("{}: {}", in_fn, e),
+            }
+        } else {
+            let s = match String::from_utf8(bytes) {
+                Ok(s) => s,
+                Err(_) => panic!("{}: neither bytecode nor UTF-8 source", in_fn),
+            };
+            match bytecode::compile(&parse_code(&s)) {
+                Ok(prog) => prog,
+                Err(e) => panic!("{}: {}", in_fn, e),
+            }
+        };
+        let ctx = rt::aheui_ctx_new();
+        let ret = if matches.opt_present("tiered") {
+            tier::run(ctx, &prog, &mut vm::RtIo(ctx))
+        } else {
+            vm::run(ctx, &prog, &mut vm::RtIo(ctx))
+        };
+        rt::aheui_ctx_free(ctx);
+        std::process::exit(ret);
+    }
+
+    if cmd == "profile" {
+        let code = read_code(in_fn);
+        let prof_fn = if free.len() > 1 { &free[1][..] } else { profile::DEFAULT_PATH };
+        let text = match String::from_utf8(read_bytes(prof_fn)) {
+            Ok(s) => s,
+            Err(_) => panic!("{}: not UTF-8", prof_fn),
+        };
+        let counts = match profile::parse(&text) {
+            Ok(counts) => counts,
+            Err(e) => panic!("{}: {}", prof_fn, e),
+        };
+        print!("{}", profile::report(&code, &counts));
+        return;
+    }
+
+    if cmd == "coverage" {
+        let code = read_code(in_fn);
+        let cov_fn = if free.len() > 1 { &free[1][..] } else { coverage::DEFAULT_PATH };
+        let text = match String::from_utf8(read_bytes(cov_fn)) {
+            Ok(s) => s,
+            Err(_) => panic!("{}: not UTF-8", cov_fn),
+        };
+        let states = match coverage::parse(&text) {
+            Ok(states) => states,
+            Err(e) => panic!("{}: {}", cov_fn, e),
+        };
+        let lcov = coverage::lcov(&code, in_fn, &states);
+        match matches.opt_str("o") {
+            Some(o) => write_output(&o, &lcov),
+            None => print!("{}", lcov),
+        }
+        return;
+    }
+
+    if cmd == "tui" {
+        let code = read_code(in_fn);
+        let input = match matches.opt_str("input") {
+            Some(f) => match String::from_utf8(read_bytes(&f)) {
+                Ok(s) => s,
+                Err(_) => panic!("{}: not UTF-8", f),
+            },
+            None => String::new(),
+        };
+        tui::run(&code, &input);
+        return;
+    }
+
+    if cmd == "record" {
+        let code = read_code(in_fn);
+        // the runtime only does the I/O here; interp.rs keeps the storages
+        let ctx = rt::aheui_ctx_new();
+        let (steps, end) = if matches.opt_present("trace") {
+            let mut trace = rt::open_trace();
+            replay::record(&code, &mut vm::RtIo(ctx), Some(&mut *trace))
+        } else {
+            replay::record(&code, &mut vm::RtIo(ctx), None)
+        };
+        rt::aheui_ctx_free(ctx);
+        let rec_fn = match matches.opt_str("o") {
+            Some(o) => o,
+            None => replay::DEFAULT_PATH.to_string(),
+        };
+        write_output(&rec_fn, &replay::write(&steps, &end));
+        match end.trim_left_matches("halt ").parse() {
+            Ok(ret) => std::process::exit(ret),
+            Err(_) => panic!("{}: {}", in_fn, end),
+        }
+    }
+
+    if cmd == "replay" {
+        let code = read_code(in_fn);
+        let rec_fn = if free.len() > 1 { &free[1][..] } else { replay::DEFAULT_PATH };
+        let text = match String::from_utf8(read_bytes(rec_fn)) {
+            Ok(s) => s,
+            Err(_) => panic!("{}: not UTF-8", rec_fn),
+        };
+        let (steps, end) = match replay::parse(&text) {
+            Ok(rec) => rec,
+            Err(e) => panic!("{}: {}", rec_fn, e),
+        };
+        replay::run(&code, steps, end);
+        return;
+    }
+
+    if cmd == "trace-diff" {
+        if free.len() < 2 {
+            print_usage(&args[0]);
+            return;
+        }
+        let mut traces = Vec::new();
+        for f in free[..2].iter() {
+            let text = match String::from_utf8(read_bytes(f)) {
+                Ok(s) => s,
+                Err(_) => panic!("{}: not UTF-8", f),
+            };
+            match tracediff::parse(&text) {
+                Ok(t) => traces.push(t),
+                Err(e) => panic!("{}: {}", f, e),
+            }
+        }
+        match tracediff::report(&traces[0], &traces[1]) {
+            Some(r) => {
+                print!("{}", r);
+                std::process::exit(1);
+            },
+            None => return,
+        }
+    }
+
+    let mir = if in_fn.ends_with(".mir") {
+        // e.g. an edited --emit=mir dump; only the LLVM backend reads MIR
+        if cmd == "compile" && emit != "llvm-ir" {
+            panic!("--emit={} needs Aheui source, not MIR", emit);
+        }
+        let text = match String::from_utf8(read_bytes(in_fn)) {
+            Ok(s) => s,
+            Err(_) => panic!("{}: not UTF-8", in_fn),
+        };
+        match mir::parse(&text) {
+            Ok(mir) => mir,
+            Err(e) => panic!("{}: {}", in_fn, e),
+        }
+    } else {
+        let code = read_code(in_fn);
+        if cmd == "compile" {
+            match &emit[..] {
+                "c" => {
+                    write_output(&out_fn, &c::gen_c(&code, in_fn, &fn_name));
+                    return;
+                },
+                "rust" => {
+                    write_output(&out_fn, &rust::gen_rust(&code, in_fn, &fn_name));
+                    return;
+                },
+                "wasm" => {
+                    write_output_bytes(&out_fn, &wasm::gen_wasm(&code, &fn_name).encode());
+                    return;
+                },
+                "wat" => {
+                    write_output(&out_fn, &wasm::gen_wasm(&code, &fn_name).to_wat());
+                    return;
+                },
+                "bytecode" => {
+                    let prog = match bytecode::compile(&code) {
+                        Ok(prog) => prog,
+                        Err(e) => panic!("{}: {}", in_fn, e),
+                    };
+                    write_output_bytes(&out_fn, &bytecode::encode(&prog));
+                    return;
+                },
+                "mir" => {
+                    write_output(&out_fn, &mir::lower(&code).to_string());
+                    return;
+                },
+                _ => {},
+            }
+        }
+        mir::lower(&code)
+    };
+
+    let max_steps = matches.opt_str("max-steps").map(|n| match n.parse() {
+        Ok(n) => n,
+        Err(_) => panic!("--max-steps needs a number, not {}", n),
     });
-    let code = code_iter.collect();
-    let aheui = Aheui::new(code, in_fn, &fn_name);
+    let instr = Instrument {
+        trace: matches.opt_present("trace") || matches.opt_present("debug"),
+        profile: matches.opt_present("profile"),
+        coverage: matches.opt_present("coverage"),
+        max_steps: max_steps,
+        hangs: matches.opt_present("detect-hangs"),
+        stats: matches.opt_present("stats"),
+    };
+    let aheui = Aheui::new(mir, in_fn, &fn_name, instr);
     aheui.gen_llvm();
 
+    if cmd == "jit" {
+        // the program has the process to itself, as with runtime.rs
+        rt::install_signals();
+        let ret = jit::run(aheui, &fn_name, matches.opt_present("debug"));
+        std::process::exit(ret);
+    }
+
     aheui.print_module(&out_fn);
 }
 
-#[test]
-fn test_hangul() {
-    use Cho::*;
-    use Jung::*;
-    use Jong::*;
-    let 가 = Hangul { cho: cㄱ, jung: ㅏ, jong: joNone, c: '가' };
-    assert!(Hangul::from_char('가') == 가);
-
-    let 아 = Hangul { cho: cㅇ, jung: ㅏ, jong: joNone, c: '아' };
-    assert!(Hangul::from_char('아') == 아);
-
-    let 힣 = Hangul { cho: cㅎ, jung: ㅣ, jong: jㅎ, c: '힣' };
-    assert!(Hangul::from_char('힣') == 힣);
-
-    assert!(Hangul::from_char('A') == Hangul::none());
-    assert!(Hangul::from_char('☆') == Hangul::none());
+// programs under tests/conformance paired with their expected stdout
+#[cfg(test)]
+fn conformance_cases() -> Vec<(String, String, String)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("conformance");
+    hangul::read_cases(&dir)
 }
 
+// every backend reads stdin by the rules of rt.rs and prints nothing else
 #[test]
-fn test_jong() {
-    use Jong::*;
-    assert!(joNone.val() == 0);
-    assert!(jㄱ.val() == 2);
-    assert!(jㄿ.val() == 9);
+fn test_backends_input() {
+    let cases: Vec<(&str, &str, &str)> = vec!(
+        ("int", "방망방망희", " 12\n-3 \n"),
+        ("char", "밯맣밯망희", "한글\nA"),
+        ("eof", "밯망방망희", ""),
+        ("bad", "방망희", "4two\n"),
+        ("exit", "방희", "7"),
+    );
+    for &(name, code, input) in cases.iter() {
+        let prog = bytecode::compile(&parse_code(code)).unwrap();
+        let (out, ret) = rt::capture(input.as_bytes(), |ctx| {
+            vm::run(ctx, &prog, &mut vm::RtIo(ctx))
+        });
+        let expected = (String::from_utf8(out).unwrap(), ret);
+
+        let name = format!("input_{}", name);
+        assert!(c::compile_and_run(&name, code, input) == expected, "c differs for {}", name);
+        assert!(rust::compile_and_run(&name, code, input) == expected,
+                "rust differs for {}", name);
+        let module = wasm::gen_wasm(&parse_code(code), "aheui_main");
+        let (out, ret) = wasm_interp::run_start(&module, input.as_bytes()).unwrap();
+        assert!((String::from_utf8(out).unwrap(), ret) == expected, "wasm differs for {}", name);
+    }
 }
 
 #[test]
@@ -734,25 +1340,24 @@ fn test_next_pos() {
     let map: Vec<_> = map.iter().map(|x| {
         x.chars().map(Hangul::from_char).collect::<Vec<Hangul>>()
     }).collect();
-    let map = Aheui::new(map, "dummy", "dummy_main");
-
-    assert!(map.next_pos(0, 0, Flow::Left) == (4, 0));
-    assert!(map.next_pos(0, 0, Flow::Right) == (1, 0));
-    assert!(map.next_pos(0, 0, Flow::Up) == (0, 2));
-    assert!(map.next_pos(0, 0, Flow::Down) == (0, 1));
-
-    assert!(map.next_pos(4, 0, Flow::Left) == (3, 0));
-    assert!(map.next_pos(4, 0, Flow::Right) == (0, 0));
-    assert!(map.next_pos(4, 0, Flow::Up) == (4, 0));
-    assert!(map.next_pos(4, 0, Flow::Down) == (4, 0));
-
-    assert!(map.next_pos(3, 1, Flow::Left) == (2, 1));
-    assert!(map.next_pos(3, 1, Flow::Right) == (0, 1));
-    assert!(map.next_pos(3, 1, Flow::Up) == (3, 0));
-    assert!(map.next_pos(3, 1, Flow::Down) == (3, 0));
-
-    assert!(map.next_pos(2, 2, Flow::Left) == (1, 2));
-    assert!(map.next_pos(2, 2, Flow::Right) == (0, 2));
-    assert!(map.next_pos(2, 2, Flow::Up) == (2, 1));
-    assert!(map.next_pos(2, 2, Flow::Down) == (2, 0));
+
+    assert!(next_pos(&map, 0, 0, Flow::Left) == (4, 0));
+    assert!(next_pos(&map, 0, 0, Flow::Right) == (1, 0));
+    assert!(next_pos(&map, 0, 0, Flow::Up) == (0, 2));
+    assert!(next_pos(&map, 0, 0, Flow::Down) == (0, 1));
+
+    assert!(next_pos(&map, 4, 0, Flow::Left) == (3, 0));
+    assert!(next_pos(&map, 4, 0, Flow::Right) == (0, 0));
+    assert!(next_pos(&map, 4, 0, Flow::Up) == (4, 0));
+    assert!(next_pos(&map, 4, 0, Flow::Down) == (4, 0));
+
+    assert!(next_pos(&map, 3, 1, Flow::Left) == (2, 1));
+    assert!(next_pos(&map, 3, 1, Flow::Right) == (0, 1));
+    assert!(next_pos(&map, 3, 1, Flow::Up) == (3, 0));
+    assert!(next_pos(&map, 3, 1, Flow::Down) == (3, 0));
+
+    assert!(next_pos(&map, 2, 2, Flow::Left) == (1, 2));
+    assert!(next_pos(&map, 2, 2, Flow::Right) == (0, 2));
+    assert!(next_pos(&map, 2, 2, Flow::Up) == (2, 1));
+    assert!(next_pos(&map, 2, 2, Flow::Down) == (2, 0));
 }
@@ -0,0 +1,489 @@
+// Mid-level IR between the grid and the LLVM backend. Every cell becomes a
+// block (numbered row by row) holding its operations on the selected
+// storage, followed by a terminator saying how control leaves it.
+//
+// Text format (`--emit=mir`), one block per paragraph; `;` starts a comment:
+//
+//     bb0 (0, 0) '밤'
+//         push 4
+//         jump right bb1
+//     bb1 (1, 0) '희'
+//         halt
+
+use std::fmt;
+
+use super::{Hangul, Flow, next_pos, move_pos};
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Inst {
+    Push(i32),
+    Pop,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Compare,
+    Dup,
+    Swap,
+    Select(u8),
+    Move(u8),
+    GetInt,
+    GetChar,
+    PutInt,
+    PutChar,
+}
+
+// How a non-directional vowel changes the flow before `Switch` picks the
+// target for it; the order matches the fl0..fl3 tables of the LLVM backend.
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Reflect {
+    Keep,
+    Horizontal,
+    Vertical,
+    Both,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum Term {
+    Jump(Flow, usize),
+    // pops a value; goes to the first block unless it is zero
+    Branch(Flow, usize, usize),
+    // targets for left, right, up and down
+    Switch(Reflect, [usize; 4]),
+    // pops a value and picks a target like Switch; a zero reverses the move,
+    // but not the flow
+    Choose(Reflect, [usize; 4]),
+    Halt,
+}
+
+impl Term {
+    pub fn targets(&self) -> Vec<usize> {
+        match *self {
+            Term::Jump(_, t) => vec!(t),
+            Term::Branch(_, t, zero) => vec!(t, zero),
+            Term::Switch(_, ts) | Term::Choose(_, ts) => ts.to_vec(),
+            Term::Halt => vec!(),
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct Block {
+    pub x: usize,
+    pub y: usize,
+    pub c: char,
+    pub insts: Vec<Inst>,
+    pub term: Term,
+}
+
+#[derive(PartialEq, Eq, Clone)]
+pub struct Mir {
+    pub blocks: Vec<Block>,
+}
+
+const FLOWS: [Flow; 4] = [Flow::Left, Flow::Right, Flow::Up, Flow::Down];
+
+pub fn flow_name(flow: Flow) -> &'static str {
+    match flow {
+        Flow::Left => "left",
+        Flow::Right => "right",
+        Flow::Up => "up",
+        Flow::Down => "down",
+    }
+}
+
+pub fn reflect_name(r: Reflect) -> &'static str {
+    match r {
+        Reflect::Keep => "keep",
+        Reflect::Horizontal => "horizontal",
+        Reflect::Vertical => "vertical",
+        Reflect::Both => "both",
+    }
+}
+
+fn lower_cell(code: &[Vec<Hangul>], offsets: &[usize], x: usize, y: usize) -> Block {
+    use Cho::*;
+    use Jung::*;
+    use Jong::*;
+
+    let h = &code[y][x];
+    let id = |(x, y): (usize, usize)| offsets[y] + x;
+    let mut insts = Vec::new();
+
+    match h.cho {
+        cㄴ => insts.push(Inst::Div),
+        cㄷ => insts.push(Inst::Add),
+        cㄸ => insts.push(Inst::Mul),
+        cㄹ => insts.push(Inst::Rem),
+        cㅁ => insts.push(match h.jong {
+            jㅇ => Inst::PutInt,
+            jㅎ => Inst::PutChar,
+            _ => Inst::Pop,
+        }),
+        cㅂ => insts.push(match h.jong {
+            jㅇ => Inst::GetInt,
+            jㅎ => Inst::GetChar,
+            _ => Inst::Push(h.jong.val() as i32),
+        }),
+        cㅃ => insts.push(Inst::Dup),
+        cㅅ => insts.push(Inst::Select(h.jong as u8)),
+        cㅆ => insts.push(Inst::Move(h.jong as u8)),
+        cㅈ => insts.push(Inst::Compare),
+        cㅌ => insts.push(Inst::Sub),
+        cㅍ => insts.push(Inst::Swap),
+        cㅊ | cㅎ | cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+    }
+
+    let term = match (h.cho, Flow::from_jung(h.jung)) {
+        (cㅎ, _) => Term::Halt,
+        (cㅊ, Some(flow)) => {
+            let next = id(move_pos(code, x, y, h.jung, flow));
+            let rev = id(move_pos(code, x, y, h.jung, flow.reverse()));
+            Term::Branch(flow, next, rev)
+        },
+        (_, Some(flow)) => Term::Jump(flow, id(move_pos(code, x, y, h.jung, flow))),
+        (cho, None) => {
+            let r = match h.jung {
+                ㅣ => Reflect::Horizontal,
+                ㅡ => Reflect::Vertical,
+                ㅢ => Reflect::Both,
+                _ => Reflect::Keep,
+            };
+            let mut targets = [0; 4];
+            for (i, f) in FLOWS.iter().enumerate() {
+                targets[i] = id(next_pos(code, x, y, *f));
+            }
+            match cho {
+                cㅊ => Term::Choose(r, targets),
+                _ => Term::Switch(r, targets),
+            }
+        },
+    };
+
+    Block { x: x, y: y, c: h.c, insts: insts, term: term }
+}
+
+pub fn lower(code: &[Vec<Hangul>]) -> Mir {
+    let mut offsets = Vec::new();
+    let mut n = 0;
+    for line in code.iter() {
+        offsets.push(n);
+        n += line.len();
+    }
+
+    let mut blocks = Vec::new();
+    for (y, line) in code.iter().enumerate() {
+        for x in 0..line.len() {
+            blocks.push(lower_cell(code, &offsets, x, y));
+        }
+    }
+    Mir { blocks: blocks }
+}
+
+fn escape_char(c: char) -> String {
+    match c {
+        '\'' => "\\'".to_string(),
+        '\\' => "\\\\".to_string(),
+        '\t' => "\\t".to_string(),
+        '\r' => "\\r".to_string(),
+        '\n' => "\\n".to_string(),
+        c if c.is_control() => format!("\\u{{{:x}}}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Inst::Push(v) => write!(f, "push {}", v),
+            Inst::Pop => write!(f, "pop"),
+            Inst::Add => write!(f, "add"),
+            Inst::Sub => write!(f, "sub"),
+            Inst::Mul => write!(f, "mul"),
+            Inst::Div => write!(f, "div"),
+            Inst::Rem => write!(f, "rem"),
+            Inst::Compare => write!(f, "cmp"),
+            Inst::Dup => write!(f, "dup"),
+            Inst::Swap => write!(f, "swap"),
+            Inst::Select(s) => write!(f, "select {}", s),
+            Inst::Move(s) => write!(f, "move {}", s),
+            Inst::GetInt => write!(f, "getint"),
+            Inst::GetChar => write!(f, "getchar"),
+            Inst::PutInt => write!(f, "putint"),
+            Inst::PutChar => write!(f, "putchar"),
+        }
+    }
+}
+
+impl fmt::Display for Term {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Term::Jump(flow, t) => write!(f, "jump {} bb{}", flow_name(flow), t),
+            Term::Branch(flow, t, z) => write!(f, "branch {} bb{} bb{}", flow_name(flow), t, z),
+            Term::Switch(r, ref ts) => {
+                write!(f, "switch {} bb{} bb{} bb{} bb{}", reflect_name(r), ts[0], ts[1], ts[2], ts[3])
+            },
+            Term::Choose(r, ref ts) => {
+                write!(f, "choose {} bb{} bb{} bb{} bb{}", reflect_name(r), ts[0], ts[1], ts[2], ts[3])
+            },
+            Term::Halt => write!(f, "halt"),
+        }
+    }
+}
+
+impl fmt::Display for Mir {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, b) in self.blocks.iter().enumerate() {
+            try!(writeln!(f, "bb{} ({}, {}) '{}'", i, b.x, b.y, escape_char(b.c)));
+            for inst in b.insts.iter() {
+                try!(writeln!(f, "    {}", inst));
+            }
+            try!(writeln!(f, "    {}", b.term));
+        }
+        Ok(())
+    }
+}
+
+fn parse_block_id(s: &str, n: usize) -> Result<usize, String> {
+    if !s.starts_with("bb") {
+        return Err(format!("expected a block, found `{}`", s));
+    }
+    match s[2..].parse::<usize>() {
+        Ok(id) if id < n => Ok(id),
+        Ok(id) => Err(format!("bb{} does not exist", id)),
+        Err(_) => Err(format!("expected a block, found `{}`", s)),
+    }
+}
+
+fn parse_num<T: ::std::str::FromStr>(s: Option<&str>) -> Result<T, String> {
+    match s {
+        Some(s) => s.parse().map_err(|_| format!("expected a number, found `{}`", s)),
+        None => Err("missing operand".to_string()),
+    }
+}
+
+fn parse_flow(s: Option<&str>) -> Result<Flow, String> {
+    match FLOWS.iter().find(|f| Some(flow_name(**f)) == s) {
+        Some(f) => Ok(*f),
+        None => Err(format!("expected a direction, found `{}`", s.unwrap_or(""))),
+    }
+}
+
+fn parse_char(s: &str) -> Result<char, String> {
+    let err = || format!("bad character literal `{}`", s);
+    if s.len() < 3 || !s.starts_with("'") || !s.ends_with("'") {
+        return Err(err());
+    }
+    let inner = &s[1..s.len() - 1];
+    let mut chars = inner.chars();
+    let c = match (chars.next(), chars.next()) {
+        (Some('\\'), Some(e)) => {
+            let rest = chars.as_str();
+            match e {
+                '\'' | '\\' if rest.is_empty() => e,
+                't' if rest.is_empty() => '\t',
+                'r' if rest.is_empty() => '\r',
+                'n' if rest.is_empty() => '\n',
+                'u' if rest.starts_with("{") && rest.ends_with("}") => {
+                    let code = match u32::from_str_radix(&rest[1..rest.len() - 1], 16) {
+                        Ok(code) => code,
+                        Err(_) => return Err(err()),
+                    };
+                    match ::std::char::from_u32(code) {
+                        Some(c) => c,
+                        None => return Err(err()),
+                    }
+                },
+                _ => return Err(err()),
+            }
+        },
+        (Some(c), None) => c,
+        _ => return Err(err()),
+    };
+    Ok(c)
+}
+
+// the header of a block: `bbN (x, y) 'c'`
+fn parse_header(line: &str, id: usize) -> Result<(usize, usize, char), String> {
+    let name = format!("bb{} ", id);
+    if !line.starts_with(&name) {
+        return Err(format!("expected bb{}", id));
+    }
+    let rest = line[name.len()..].trim();
+    let close = match rest.find(')') {
+        Some(i) if rest.starts_with("(") => i,
+        _ => return Err("expected a position".to_string()),
+    };
+    let pos: Vec<&str> = rest[1..close].split(',').map(|s| s.trim()).collect();
+    if pos.len() != 2 {
+        return Err("expected a position".to_string());
+    }
+    let x = try!(parse_num(Some(pos[0])));
+    let y = try!(parse_num(Some(pos[1])));
+    let c = try!(parse_char(rest[close + 1..].trim()));
+    Ok((x, y, c))
+}
+
+// `;` starts a comment unless it is the character of a block
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            ';' if !quoted => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+fn parse_line(words: &[&str], n: usize) -> Result<Result<Inst, Term>, String> {
+    let arg = |i: usize| words.get(i).map(|s| *s);
+    let block = |i: usize| match arg(i) {
+        Some(s) => parse_block_id(s, n),
+        None => Err("missing block".to_string()),
+    };
+    let inst = match words[0] {
+        "push" => Inst::Push(try!(parse_num(arg(1)))),
+        "pop" => Inst::Pop,
+        "add" => Inst::Add,
+        "sub" => Inst::Sub,
+        "mul" => Inst::Mul,
+        "div" => Inst::Div,
+        "rem" => Inst::Rem,
+        "cmp" => Inst::Compare,
+        "dup" => Inst::Dup,
+        "swap" => Inst::Swap,
+        "select" => Inst::Select(try!(parse_num(arg(1)))),
+        "move" => Inst::Move(try!(parse_num(arg(1)))),
+        "getint" => Inst::GetInt,
+        "getchar" => Inst::GetChar,
+        "putint" => Inst::PutInt,
+        "putchar" => Inst::PutChar,
+        "jump" => return Ok(Err(Term::Jump(try!(parse_flow(arg(1))), try!(block(2))))),
+        "branch" => {
+            let flow = try!(parse_flow(arg(1)));
+            return Ok(Err(Term::Branch(flow, try!(block(2)), try!(block(3)))));
+        },
+        "switch" | "choose" => {
+            let r = match arg(1) {
+                Some("keep") => Reflect::Keep,
+                Some("horizontal") => Reflect::Horizontal,
+                Some("vertical") => Reflect::Vertical,
+                Some("both") => Reflect::Both,
+                _ => return Err("expected keep, horizontal, vertical or both".to_string()),
+            };
+            let ts = [try!(block(2)), try!(block(3)), try!(block(4)), try!(block(5))];
+            return Ok(Err(match words[0] {
+                "switch" => Term::Switch(r, ts),
+                _ => Term::Choose(r, ts),
+            }));
+        },
+        "halt" => return Ok(Err(Term::Halt)),
+        w => return Err(format!("unknown instruction `{}`", w)),
+    };
+    match inst {
+        Inst::Select(s) | Inst::Move(s) if s > 27 => Err(format!("no storage {}", s)),
+        _ => Ok(Ok(inst)),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Mir, String> {
+    let lines: Vec<(usize, &str)> = text.lines().enumerate().map(|(i, l)| {
+        (i + 1, strip_comment(l).trim())
+    }).filter(|&(_, l)| !l.is_empty()).collect();
+
+    let n = lines.iter().filter(|&&(_, l)| l.starts_with("bb")).count();
+    let mut blocks = Vec::new();
+    let mut cur: Option<(usize, usize, char, Vec<Inst>)> = None;
+    for &(lineno, line) in lines.iter() {
+        let res = match cur.take() {
+            None => match parse_header(line, blocks.len()) {
+                Ok((x, y, c)) => {
+                    cur = Some((x, y, c, Vec::new()));
+                    Ok(())
+                },
+                Err(e) => Err(e),
+            },
+            Some((x, y, c, mut insts)) => {
+                let words: Vec<&str> = line.split_whitespace().collect();
+                match parse_line(&words, n) {
+                    Ok(Ok(inst)) => {
+                        insts.push(inst);
+                        cur = Some((x, y, c, insts));
+                        Ok(())
+                    },
+                    Ok(Err(term)) => {
+                        blocks.push(Block { x: x, y: y, c: c, insts: insts, term: term });
+                        Ok(())
+                    },
+                    Err(e) => Err(e),
+                }
+            },
+        };
+        if let Err(e) = res {
+            return Err(format!("line {}: {}", lineno, e));
+        }
+    }
+    if cur.is_some() {
+        return Err("unterminated block at the end".to_string());
+    }
+    if blocks.is_empty() {
+        return Err("no blocks".to_string());
+    }
+    Ok(Mir { blocks: blocks })
+}
+
+#[test]
+fn test_mir_lower() {
+    use super::parse_code;
+
+    let mir = lower(&parse_code("밤희\n이"));
+    assert!(mir.blocks.len() == 3);
+    assert!(mir.blocks[0].insts == vec!(Inst::Push(4)));
+    assert!(mir.blocks[0].term == Term::Jump(Flow::Right, 1));
+    assert!(mir.blocks[1].term == Term::Halt);
+    assert!(mir.blocks[2].term == Term::Switch(Reflect::Horizontal, [2, 2, 0, 0]));
+    assert!(mir.to_string() == "\
+bb0 (0, 0) '밤'
+    push 4
+    jump right bb1
+bb1 (1, 0) '희'
+    halt
+bb2 (0, 1) '이'
+    switch horizontal bb2 bb2 bb0 bb0
+");
+
+    // 치 pops before it turns; the targets are by the direction of the move
+    let mir = lower(&parse_code("바치희"));
+    assert!(mir.blocks[1].term == Term::Choose(Reflect::Horizontal, [0, 2, 1, 1]));
+    assert!(mir.blocks[1].term.targets() == vec!(0, 2, 1, 1));
+}
+
+#[test]
+fn test_mir_parse() {
+    use super::{conformance_cases, parse_code};
+
+    for (name, code, _) in conformance_cases().into_iter() {
+        let mir = lower(&parse_code(&code));
+        assert!(parse(&mir.to_string()) == Ok(mir), "round trip failed for {}", name);
+    }
+
+    let mir = parse("bb0 (0, 0) ';' ; comment\n  select 21\n  branch up bb0 bb0\n").unwrap();
+    assert!(mir.blocks[0].c == ';');
+    assert!(mir.blocks[0].insts == vec!(Inst::Select(21)));
+    assert!(mir.blocks[0].term == Term::Branch(Flow::Up, 0, 0));
+
+    let mir = parse("bb0 (0, 0) '치'\n  choose both bb0 bb0 bb0 bb0\n").unwrap();
+    assert!(mir.blocks[0].term == Term::Choose(Reflect::Both, [0, 0, 0, 0]));
+
+    assert!(parse("bb0 (0, 0) 'a'\n    jump right bb1\n").is_err());
+    assert!(parse("bb0 (0, 0) 'a'\n    push\n    halt\n").is_err());
+    assert!(parse("bb0 (0, 0) 'a'\n    select 28\n    halt\n").is_err());
+    assert!(parse("bb0 (0, 0) 'a'\n    pop\n").is_err());
+    assert!(parse("bb1 (0, 0) 'a'\n    halt\n").is_err());
+}
@@ -0,0 +1,112 @@
+// `aheui profile`: renders the profile written by a program compiled with
+// --profile. The profile has one `x y count` line per executed cell.
+
+use std::collections::HashMap;
+
+use mir::{self, Term};
+use super::Hangul;
+
+pub const DEFAULT_PATH: &'static str = "aheui.prof";
+
+// blue, cyan, green, yellow, red from cold to hot
+const COLORS: [&'static str; 5] = ["34", "36", "32", "33", "31"];
+
+const TOP: usize = 20;
+
+pub fn parse(text: &str) -> Result<HashMap<(usize, usize), u64>, String> {
+    let mut counts = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words.is_empty() {
+            continue;
+        }
+        let nums: Vec<u64> = words.iter().filter_map(|w| w.parse().ok()).collect();
+        if words.len() != 3 || nums.len() != 3 {
+            return Err(format!("line {}: expected `x y count`", i + 1));
+        }
+        *counts.entry((nums[0] as usize, nums[1] as usize)).or_insert(0) += nums[2];
+    }
+    Ok(counts)
+}
+
+// on a log scale, so that a few very hot cells do not wash out the rest
+fn color(count: u64, max: u64) -> &'static str {
+    if count == 0 {
+        return "2";
+    }
+    let scale = (max as f64 + 1.0).ln();
+    let i = ((count as f64).ln() / scale * COLORS.len() as f64) as usize;
+    COLORS[if i < COLORS.len() { i } else { COLORS.len() - 1 }]
+}
+
+pub fn heat_map(code: &[Vec<Hangul>], counts: &HashMap<(usize, usize), u64>) -> String {
+    let max = counts.values().cloned().max().unwrap_or(0);
+    let mut out = String::new();
+    for (y, line) in code.iter().enumerate() {
+        for (x, h) in line.iter().enumerate() {
+            let count = counts.get(&(x, y)).cloned().unwrap_or(0);
+            out.push_str(&format!("\x1b[{}m{}\x1b[0m", color(count, max), h.c));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+fn describe(block: &mir::Block) -> String {
+    let mut ops: Vec<String> = block.insts.iter().map(|i| i.to_string()).collect();
+    ops.push(match block.term {
+        Term::Jump(flow, _) => format!("jump {}", mir::flow_name(flow)),
+        Term::Branch(flow, _, _) => format!("branch {}", mir::flow_name(flow)),
+        Term::Switch(r, _) => format!("switch {}", mir::reflect_name(r)),
+        Term::Choose(r, _) => format!("choose {}", mir::reflect_name(r)),
+        Term::Halt => "halt".to_string(),
+    });
+    ops.join("; ")
+}
+
+pub fn hot_cells(m: &mir::Mir, counts: &HashMap<(usize, usize), u64>, top: usize) -> String {
+    let blocks: HashMap<(usize, usize), &mir::Block> = m.blocks.iter().map(|b| {
+        ((b.x, b.y), b)
+    }).collect();
+
+    let mut cells: Vec<((usize, usize), u64)> = counts.iter().map(|(&p, &c)| (p, c)).collect();
+    // hottest first, then in reading order
+    cells.sort_by(|a, b| (b.1, (a.0).1, (a.0).0).cmp(&(a.1, (b.0).1, (b.0).0)));
+
+    let mut out = format!("{:>10}  {:<10}  {}\n", "count", "cell", "instruction");
+    for &((x, y), count) in cells.iter().take(top) {
+        let (c, ops) = match blocks.get(&(x, y)) {
+            Some(b) => (b.c, describe(b)),
+            None => (' ', "?".to_string()),
+        };
+        let cell = format!("({}, {})", x, y);
+        out.push_str(&format!("{:>10}  {:<10}  {} {}\n", count, cell, c, ops));
+    }
+    out
+}
+
+pub fn report(code: &[Vec<Hangul>], counts: &HashMap<(usize, usize), u64>) -> String {
+    format!("{}\n{}", heat_map(code, counts), hot_cells(&mir::lower(code), counts, TOP))
+}
+
+#[test]
+fn test_profile() {
+    use super::parse_code;
+
+    let counts = parse("0 0 1\n1 0 1\n\n2 0 9\n1 0 1\n").unwrap();
+    assert!(counts.len() == 3 && counts[&(1, 0)] == 2);
+    assert!(parse("0 0\n").is_err());
+    assert!(parse("0 0 x\n").is_err());
+
+    let code = parse_code("밤빠따희");
+    let map = heat_map(&code, &counts);
+    assert!(map.starts_with("\x1b[34m밤\x1b[0m\x1b[36m빠\x1b[0m"));
+    assert!(map.contains("\x1b[31m따\x1b[0m"));
+    assert!(map.contains("\x1b[2m희\x1b[0m"));
+
+    let table = hot_cells(&mir::lower(&code), &counts, 2);
+    let lines: Vec<&str> = table.lines().collect();
+    assert!(lines.len() == 3);
+    assert!(lines[1].ends_with("(2, 0)      따 mul; jump right"));
+    assert!(lines[2].ends_with("(1, 0)      빠 dup; jump right"));
+}
@@ -0,0 +1,405 @@
+// `aheui record` runs a program on interp.rs and writes every step down;
+// `aheui replay` walks that recording forwards and backwards. A recording is
+// text: a header line, then one line per step
+//
+//     x y flow cur N:AT:REMOVED:ADDED ...
+//
+// with the cell that ran, the direction and storage after it, and for every
+// storage it changed the values replaced at index AT (comma separated, bottom
+// to top or front to back). The last line tells how the run ended.
+
+use std::io::{self, BufRead, Write};
+
+use debugger::format_state;
+use interp::{Machine, STORAGES};
+use rt;
+use vm::Io;
+use super::{Cho, Flow, Hangul};
+
+pub const DEFAULT_PATH: &'static str = "aheui.rec";
+const HEADER: &'static str = "aheui-rec";
+
+const HELP: &'static str = "\
+step [N]            run N cells forwards (also an empty line)
+back [N]            go N cells backwards
+goto N              go to step N
+change N            go back to the last step that changed storage N
+visit X Y           go back to the last time cell (X, Y) ran
+print               show every storage, the selected one and the direction
+quit                leave the replay
+";
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Delta {
+    pub storage: usize,
+    pub at: usize,
+    pub removed: Vec<i32>,
+    pub added: Vec<i32>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Step {
+    pub x: usize,
+    pub y: usize,
+    pub flow: i8,
+    pub cur: usize,
+    pub deltas: Vec<Delta>,
+}
+
+// the part of `after` that differs from `before`, trimmed from both ends
+fn diff(storage: usize, before: &[i32], after: &[i32]) -> Option<Delta> {
+    let mut p = 0;
+    while p < before.len() && p < after.len() && before[p] == after[p] {
+        p += 1;
+    }
+    let mut q = 0;
+    while q < before.len() - p && q < after.len() - p &&
+          before[before.len() - 1 - q] == after[after.len() - 1 - q] {
+        q += 1;
+    }
+    if p + q == before.len() && p + q == after.len() {
+        return None;
+    }
+    Some(Delta {
+        storage: storage,
+        at: p,
+        removed: before[p..before.len() - q].to_vec(),
+        added: after[p..after.len() - q].to_vec(),
+    })
+}
+
+fn apply(storages: &mut [Vec<i32>], d: &Delta, forward: bool) {
+    let (old, new) = if forward { (&d.removed, &d.added) } else { (&d.added, &d.removed) };
+    let s = &mut storages[d.storage];
+    let tail = s.split_off(d.at + old.len());
+    s.truncate(d.at);
+    s.extend(new.iter().cloned());
+    s.extend(tail);
+}
+
+// runs the program to its end; the second value is the last line. With a
+// trace, also writes what --trace would for every cell.
+pub fn record<I: Io>(code: &[Vec<Hangul>], io: &mut I, mut trace: Option<&mut Write>)
+                     -> (Vec<Step>, String) {
+    let mut m = Machine::new(code);
+    let mut steps = Vec::new();
+    loop {
+        if let Some(ret) = m.halted {
+            return (steps, format!("halt {}", ret));
+        }
+        let (x, y) = (m.x, m.y);
+        if let Some(ref mut t) = trace {
+            let r = rt::trace_record(m.steps + 1, x as i32, y as i32, m.cell().c, m.flow as i8,
+                                     m.cur as i8, &m.storages);
+            writeln!(t, "{}", r).unwrap();
+        }
+        // a step only touches the selected storage and the target of ㅆ
+        let mut touched = vec!(m.cur);
+        if m.cell().cho == Cho::cㅆ {
+            touched.push(m.cell().jong as usize);
+        }
+        touched.retain(|&i| i < STORAGES);
+        let before: Vec<Vec<i32>> = touched.iter().map(|&i| m.storages[i].clone()).collect();
+
+        if let Err(e) = m.step(io) {
+            return (steps, format!("error {} {} {}", x, y, e));
+        }
+        let mut deltas = Vec::new();
+        for (&i, b) in touched.iter().zip(before.iter()) {
+            if let Some(d) = diff(i, b, &m.storages[i]) {
+                deltas.push(d);
+            }
+        }
+        steps.push(Step { x: x, y: y, flow: m.flow as i8, cur: m.cur, deltas: deltas });
+    }
+}
+
+fn join(values: &[i32]) -> String {
+    let values: Vec<String> = values.iter().map(|v| v.to_string()).collect();
+    values.join(",")
+}
+
+pub fn write(steps: &[Step], end: &str) -> String {
+    let mut out = format!("{}\n", HEADER);
+    for s in steps.iter() {
+        out.push_str(&format!("{} {} {} {}", s.x, s.y, s.flow, s.cur));
+        for d in s.deltas.iter() {
+            out.push_str(&format!(" {}:{}:{}:{}", d.storage, d.at, join(&d.removed), join(&d.added)));
+        }
+        out.push('\n');
+    }
+    out.push_str(end);
+    out.push('\n');
+    out
+}
+
+fn parse_values(s: &str) -> Result<Vec<i32>, String> {
+    let mut values = Vec::new();
+    for v in s.split(',').filter(|v| !v.is_empty()) {
+        match v.parse() {
+            Ok(v) => values.push(v),
+            Err(_) => return Err(format!("not a number: {}", v)),
+        }
+    }
+    Ok(values)
+}
+
+fn parse_delta(s: &str) -> Result<Delta, String> {
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 4 {
+        return Err(format!("expected `N:AT:REMOVED:ADDED`, found `{}`", s));
+    }
+    let storage = match parts[0].parse::<usize>() {
+        Ok(n) if n < STORAGES => n,
+        _ => return Err(format!("bad storage in `{}`", s)),
+    };
+    let at = match parts[1].parse() {
+        Ok(at) => at,
+        Err(_) => return Err(format!("bad index in `{}`", s)),
+    };
+    Ok(Delta {
+        storage: storage,
+        at: at,
+        removed: try!(parse_values(parts[2])),
+        added: try!(parse_values(parts[3])),
+    })
+}
+
+pub fn parse(text: &str) -> Result<(Vec<Step>, String), String> {
+    let mut lines = text.lines().enumerate();
+    match lines.next() {
+        Some((_, line)) if line == HEADER => {},
+        _ => return Err("not an aheui recording".to_string()),
+    }
+    let mut steps = Vec::new();
+    for (i, line) in lines {
+        if line.starts_with("halt ") || line.starts_with("error ") {
+            return Ok((steps, line.to_string()));
+        }
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let nums: Vec<i64> = words.iter().take(4).filter_map(|w| w.parse().ok()).collect();
+        if nums.len() != 4 || nums.iter().any(|&n| n < 0) || nums[2] > 3 {
+            return Err(format!("line {}: expected `x y flow cur` and deltas", i + 1));
+        }
+        let mut deltas = Vec::new();
+        for w in words[4..].iter() {
+            match parse_delta(w) {
+                Ok(d) => deltas.push(d),
+                Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+            }
+        }
+        steps.push(Step {
+            x: nums[0] as usize,
+            y: nums[1] as usize,
+            flow: nums[2] as i8,
+            cur: nums[3] as usize,
+            deltas: deltas,
+        });
+    }
+    Err("the recording has no end line".to_string())
+}
+
+pub struct Replay {
+    pub steps: Vec<Step>,
+    pub end: String,
+    // the number of steps run so far
+    pub pos: usize,
+    pub storages: Vec<Vec<i32>>,
+}
+
+impl Replay {
+    pub fn new(steps: Vec<Step>, end: String) -> Replay {
+        Replay { steps: steps, end: end, pos: 0, storages: vec!(Vec::new(); STORAGES) }
+    }
+
+    pub fn forward(&mut self) -> bool {
+        if self.pos == self.steps.len() {
+            return false;
+        }
+        for d in self.steps[self.pos].deltas.iter() {
+            apply(&mut self.storages, d, true);
+        }
+        self.pos += 1;
+        true
+    }
+
+    pub fn back(&mut self) -> bool {
+        if self.pos == 0 {
+            return false;
+        }
+        self.pos -= 1;
+        for d in self.steps[self.pos].deltas.iter().rev() {
+            apply(&mut self.storages, d, false);
+        }
+        true
+    }
+
+    pub fn goto(&mut self, pos: usize) {
+        while self.pos < pos && self.forward() {}
+        while self.pos > pos && self.back() {}
+    }
+
+    // the cell about to run, if any
+    pub fn cell(&self) -> Option<(usize, usize)> {
+        self.steps.get(self.pos).map(|s| (s.x, s.y))
+    }
+
+    pub fn flow(&self) -> i8 {
+        if self.pos == 0 { Flow::Down as i8 } else { self.steps[self.pos - 1].flow }
+    }
+
+    pub fn cur(&self) -> usize {
+        if self.pos == 0 { 0 } else { self.steps[self.pos - 1].cur }
+    }
+
+    // both stop right before the step they find
+    pub fn last_change(&mut self, storage: usize) -> bool {
+        let found = self.steps[..self.pos].iter().rposition(|s| {
+            s.deltas.iter().any(|d| d.storage == storage)
+        });
+        match found {
+            Some(i) => { self.goto(i); true },
+            None => false,
+        }
+    }
+
+    pub fn last_visit(&mut self, x: usize, y: usize) -> bool {
+        let found = self.steps[..self.pos].iter().rposition(|s| (s.x, s.y) == (x, y));
+        match found {
+            Some(i) => { self.goto(i); true },
+            None => false,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Command {
+    Forward(usize),
+    Back(usize),
+    Goto(usize),
+    Change(usize),
+    Visit(usize, usize),
+    Print,
+    Quit,
+    Help,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let cmd = match words.first() {
+        Some(w) => *w,
+        None => return Ok(Command::Forward(1)),
+    };
+    let mut nums = Vec::new();
+    for a in words[1..].iter() {
+        match a.parse::<usize>() {
+            Ok(n) => nums.push(n),
+            Err(_) => return Err(format!("not a number: {}", a)),
+        }
+    }
+    match (cmd, nums.len()) {
+        ("s", 0) | ("step", 0) => Ok(Command::Forward(1)),
+        ("s", 1) | ("step", 1) => Ok(Command::Forward(nums[0])),
+        ("b", 0) | ("back", 0) => Ok(Command::Back(1)),
+        ("b", 1) | ("back", 1) => Ok(Command::Back(nums[0])),
+        ("g", 1) | ("goto", 1) => Ok(Command::Goto(nums[0])),
+        ("c", 1) | ("change", 1) if nums[0] < STORAGES => Ok(Command::Change(nums[0])),
+        ("v", 2) | ("visit", 2) => Ok(Command::Visit(nums[0], nums[1])),
+        ("p", 0) | ("print", 0) => Ok(Command::Print),
+        ("q", 0) | ("quit", 0) => Ok(Command::Quit),
+        ("h", 0) | ("help", 0) => Ok(Command::Help),
+        _ => Err(format!("bad command `{}`; try help", line.trim())),
+    }
+}
+
+fn describe(code: &[Vec<Hangul>], r: &Replay) -> String {
+    let at = format!("step {}/{}", r.pos, r.steps.len());
+    match r.cell() {
+        Some((x, y)) => {
+            let c = code.get(y).and_then(|l| l.get(x)).map(|h| h.c).unwrap_or('?');
+            format!("{} ({}, {}) {} moving {}", at, x, y, c, rt::dir_name(r.flow()))
+        },
+        None => format!("{} {}", at, r.end),
+    }
+}
+
+pub fn run(code: &[Vec<Hangul>], steps: Vec<Step>, end: String) {
+    let mut r = Replay::new(steps, end);
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        println!("{}", describe(code, &r));
+        print!("(replay) ");
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return,
+        };
+        match parse_command(&line) {
+            Ok(Command::Forward(n)) => { let pos = r.pos + n; r.goto(pos); },
+            Ok(Command::Back(n)) => { let pos = r.pos.saturating_sub(n); r.goto(pos); },
+            Ok(Command::Goto(n)) => r.goto(n),
+            Ok(Command::Change(n)) => {
+                if !r.last_change(n) {
+                    println!("storage {} has not changed before step {}", n, r.pos);
+                }
+            },
+            Ok(Command::Visit(x, y)) => {
+                if !r.last_visit(x, y) {
+                    println!("({}, {}) has not run before step {}", x, y, r.pos);
+                }
+            },
+            Ok(Command::Print) => print!("{}", format_state(&r.storages, r.flow(), r.cur() as i8)),
+            Ok(Command::Quit) => return,
+            Ok(Command::Help) => print!("{}", HELP),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+#[test]
+fn test_replay() {
+    use vm::BufIo;
+    use super::parse_code;
+
+    assert!(diff(0, &[1, 2], &[1, 2]).is_none());
+    let d = diff(21, &[1, 2, 3], &[2, 3, 4]).unwrap();
+    assert!(d == Delta { storage: 21, at: 0, removed: vec!(1, 2, 3), added: vec!(2, 3, 4) });
+    let d = diff(21, &[1, 2, 3], &[2, 3]).unwrap();
+    assert!((d.at, d.removed.clone(), d.added.len()) == (0, vec!(1), 0));
+    let mut storages = vec!(Vec::new(); STORAGES);
+    storages[21] = vec!(2, 3);
+    apply(&mut storages, &d, false);
+    assert!(storages[21] == vec!(1, 2, 3));
+
+    let code = parse_code("밤밣따희");
+    let mut io = BufIo { input: Vec::new(), output: String::new() };
+    let mut trace = Vec::new();
+    let (steps, end) = record(&code, &mut io, Some(&mut trace as &mut Write));
+    assert!(String::from_utf8(trace).unwrap().lines().nth(2).unwrap().starts_with(
+            r#"{"step":3,"x":2,"y":0,"char":"따","direction":"right","storage":0,"top":8,"size":2,"#));
+    assert!(steps.len() == 4 && end == "halt 32");
+    let text = write(&steps, &end);
+    assert!(text == "aheui-rec\n0 0 1 0 0:0::4\n1 0 1 0 0:1::8\n2 0 1 0 0:0:4,8:32\n3 0 1 0 0:0:32:\nhalt 32\n");
+    assert!(parse(&text) == Ok((steps.clone(), end.clone())));
+    assert!(parse("aheui-rec\n0 0 1 0 0:0::4\n").is_err());
+    assert!(parse("aheui-rec\n0 0 1 0 27:0::4\nhalt 0\n").is_err());
+
+    let mut r = Replay::new(steps, end);
+    r.goto(10);
+    assert!(r.pos == 4 && r.cell().is_none() && r.storages[0].is_empty());
+    r.back();
+    assert!(r.cell() == Some((3, 0)) && r.storages[0] == vec!(32));
+    assert!(r.last_change(0) && r.pos == 2 && r.storages[0] == vec!(4, 8));
+    assert!(r.last_visit(0, 0) && r.pos == 0 && r.storages[0].is_empty());
+    assert!(!r.last_visit(0, 0) && !r.last_change(1));
+    assert!(describe(&code, &r) == "step 0/4 (0, 0) 밤 moving down");
+
+    let (steps, end) = record(&parse_code("밤따"), &mut io, None);
+    assert!(steps.len() == 1 && end == "error 1 0 storage 0 has fewer than 2 values");
+
+    assert!(parse_command("") == Ok(Command::Forward(1)));
+    assert!(parse_command("back 3") == Ok(Command::Back(3)));
+    assert!(parse_command("visit 1 2") == Ok(Command::Visit(1, 2)));
+    assert!(parse_command("change 27").is_err() && parse_command("goto x").is_err());
+}
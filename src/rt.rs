@@ -0,0 +1,1316 @@
+use std::env;
+use std::fs::File;
+use std::hash::{Hash, Hasher, SipHasher};
+use std::io::{LineWriter, Write, stdin, stdout, stderr};
+use std::os::raw::c_void;
+use std::process;
+use std::slice;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
+#[cfg(target_os = "linux")]
+use std::sync::atomic::Ordering;
+
+// exit code of a program stopped by the runtime, like timeout(1)
+pub const LIMIT_EXIT: i32 = 124;
+// exit code of a program that failed, e.g. by popping an empty storage
+pub const ERROR_EXIT: i32 = 125;
+
+pub const EXTENSION: &'static str = "the extension storage (27) is not supported";
+
+// for files named in the environment that cannot be opened, which no
+// program can recover from
+fn die(why: String) -> ! {
+    let _ = writeln!(stderr(), "aheui: {}", why);
+    process::exit(ERROR_EXIT);
+}
+
+// Brent's cycle finding over state hashes: remembers the state seen at the
+// last power of two steps, so a loop of any length is caught within twice
+// its length without keeping every state
+pub struct Repeats {
+    saved: Option<u64>,
+    power: u64,
+    len: u64,
+}
+
+impl Repeats {
+    fn new() -> Repeats {
+        Repeats { saved: None, power: 1, len: 0 }
+    }
+
+    // true if `h` was seen before
+    fn see(&mut self, h: u64) -> bool {
+        if self.saved == Some(h) {
+            return true;
+        }
+        self.len += 1;
+        if self.len == self.power {
+            self.saved = Some(h);
+            self.power *= 2;
+            self.len = 0;
+        }
+        false
+    }
+}
+
+// how far storages may grow; 0 means no limit
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub storage: usize,
+    pub cells: usize,
+}
+
+impl Limits {
+    // AHEUI_MAX_STORAGE and AHEUI_MAX_CELLS override the given defaults
+    fn from_env(storage: usize, cells: usize) -> Result<Limits, String> {
+        fn var(name: &str, default: usize) -> Result<usize, String> {
+            match env::var(name) {
+                Ok(v) => v.trim().parse().map_err(|_| format!("{} is not a number: {}", name, v)),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(Limits {
+            storage: try!(var("AHEUI_MAX_STORAGE", storage)),
+            cells: try!(var("AHEUI_MAX_CELLS", cells)),
+        })
+    }
+}
+
+// what aheui_stat counts, for code generated with --stats
+pub const STAT_STEP: i32 = 0;
+pub const STAT_ARITHMETIC: i32 = 1;
+pub const STAT_STORAGE: i32 = 2;
+pub const STAT_IO: i32 = 3;
+pub const STAT_SWITCH: i32 = 4;
+// ㅊ going on, and ㅊ reversing on a zero
+pub const STAT_BRANCH: i32 = 5;
+pub const STAT_REVERSED: i32 = 6;
+const STAT_KINDS: usize = 7;
+
+pub struct Stats {
+    counts: [u64; STAT_KINDS],
+    peaks: Vec<usize>,
+}
+
+impl Stats {
+    fn new() -> Stats {
+        Stats { counts: [0; STAT_KINDS], peaks: vec!(0; 27) }
+    }
+
+    fn report(&self) -> String {
+        let c = &self.counts;
+        let peaks: Vec<String> = self.peaks.iter().enumerate().filter(|&(_, &p)| p > 0)
+            .map(|(i, p)| format!("{}:{}", i, p)).collect();
+        let peaks = if peaks.is_empty() { "all empty".to_string() } else { peaks.join(" ") };
+        format!("aheui: {} steps\n\
+                 \x20 arithmetic {}, storage {}, I/O {}, branch {}\n\
+                 \x20 storage switches {}, branches reversed {}\n\
+                 \x20 peak depths {}\n",
+                c[STAT_STEP as usize], c[STAT_ARITHMETIC as usize], c[STAT_STORAGE as usize],
+                c[STAT_IO as usize], c[STAT_BRANCH as usize] + c[STAT_REVERSED as usize],
+                c[STAT_SWITCH as usize], c[STAT_REVERSED as usize], peaks)
+    }
+}
+
+// bytes of output held before they are written out
+const OUT_BUFFER: usize = 8192;
+
+// what ㅎ does with a value that is no character, from AHEUI_INVALID_CHAR
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CharPolicy {
+    Replace,
+    Skip,
+    Error,
+}
+
+impl CharPolicy {
+    fn from_env() -> Result<CharPolicy, String> {
+        match env::var("AHEUI_INVALID_CHAR") {
+            Ok(v) => match &v.trim()[..] {
+                "replace" => Ok(CharPolicy::Replace),
+                "skip" => Ok(CharPolicy::Skip),
+                "error" => Ok(CharPolicy::Error),
+                _ => Err(format!("AHEUI_INVALID_CHAR is not replace, skip or error: {}", v)),
+            },
+            Err(_) => Ok(CharPolicy::Error),
+        }
+    }
+
+    // what to print for `c`, if anything
+    fn apply(self, c: i32) -> Result<Option<char>, String> {
+        match (::std::char::from_u32(c as u32), self) {
+            (Some(c), _) => Ok(Some(c)),
+            (None, CharPolicy::Replace) => Ok(Some('\u{fffd}')),
+            (None, CharPolicy::Skip) => Ok(None),
+            (None, CharPolicy::Error) => Err(format!("{} is not a character", c)),
+        }
+    }
+}
+
+// I/O in place of stdin and stdout, for embedding: both give the number
+// of bytes read or written, 0 at the end of input and negative on errors
+pub type ReadFn = extern "C" fn(data: *mut c_void, buf: *mut u8, n: usize) -> isize;
+pub type WriteFn = extern "C" fn(data: *mut c_void, buf: *const u8, n: usize) -> isize;
+
+pub struct AheuiRt {
+    dqs: Vec<Vec<i32>>,
+    steps: u64,
+    trace: Option<Box<Write>>,
+    repeats: Repeats,
+    limits: Limits,
+    // where the running cell is, kept by generated code
+    cell: CellRecord,
+    // the first error; entry points must not unwind into generated code, so
+    // they record it and return, and the caller checks aheui_failed
+    error: Option<String>,
+    // the exit code for that error: ERROR_EXIT, or LIMIT_EXIT for a program
+    // the runtime stopped
+    status: i32,
+    // a mistake in the environment, found when the context was made; every
+    // run on the context fails with it
+    setup: Option<String>,
+    // kept only when code generated with --stats runs with AHEUI_STATS=1
+    stats: Option<Stats>,
+    // the counters of code generated with --profile and --coverage
+    profile: Vec<u64>,
+    coverage: Vec<u64>,
+    // output not yet written; flushed when full, before input and at exit
+    out: Vec<u8>,
+    chars: CharPolicy,
+    input: Option<(ReadFn, *mut c_void)>,
+    output: Option<(WriteFn, *mut c_void)>,
+}
+
+impl AheuiRt {
+    fn fail(&mut self, why: String) {
+        self.fail_with(why, ERROR_EXIT);
+    }
+
+    fn misconfigured(&mut self, why: String) {
+        if self.setup.is_none() {
+            self.setup = Some(why.clone());
+        }
+        self.fail(why);
+    }
+
+    fn fail_with(&mut self, why: String, status: i32) {
+        if self.error.is_none() {
+            self.error = Some(why);
+            self.status = status;
+            self.cell.set_failed();
+        }
+    }
+
+    // pops storage `idx`, failing on an empty one
+    fn take(&mut self, idx: usize) -> i32 {
+        if idx == 27 {
+            self.fail(EXTENSION.to_string());
+            return 0;
+        }
+        if self.dqs[idx].is_empty() {
+            self.fail(format!("storage {} is empty", idx));
+            return 0;
+        }
+        if idx == 21 { self.dqs[idx].remove(0) } else { self.dqs[idx].pop().unwrap() }
+    }
+
+    // after a value went to storage `idx`
+    fn grown(&mut self, idx: usize) {
+        let len = self.dqs[idx].len();
+        if let Some(ref mut stats) = self.stats {
+            if stats.peaks[idx] < len {
+                stats.peaks[idx] = len;
+            }
+        }
+    }
+
+    fn emit(&mut self, s: &str) {
+        self.out.extend(s.bytes());
+        if self.out.len() >= OUT_BUFFER {
+            self.flush();
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.out.is_empty() {
+            return;
+        }
+        if let Some((write, ctx)) = self.output {
+            let mut done = 0;
+            while done < self.out.len() {
+                let n = write(ctx, self.out[done..].as_ptr(), self.out.len() - done);
+                if n <= 0 {
+                    self.fail("cannot write output".to_string());
+                    break;
+                }
+                done += n as usize;
+            }
+        } else {
+            let out = stdout();
+            let mut out = out.lock();
+            if let Err(e) = out.write_all(&self.out).and_then(|_| out.flush()) {
+                self.fail(format!("cannot write output: {}", e));
+            }
+        }
+        self.out.clear();
+    }
+
+    // on the way out, however the program ends
+    fn finish(&mut self) {
+        self.flush();
+        if let Some(ref stats) = self.stats {
+            let _ = stderr().write_all(stats.report().as_bytes());
+        }
+        // the record points into the frame of the entry, which is returning
+        self.cell = CellRecord::none();
+    }
+}
+
+impl AheuiRt {
+    fn new() -> Self {
+        let mut dqs = Vec::new();
+        // 26 stacks and the queue (21); 27 is the unsupported extension
+        for _ in 0.. 27 { dqs.push(Vec::new()); }
+
+        let mut ar = AheuiRt {
+            dqs: dqs,
+            steps: 0,
+            trace: None,
+            repeats: Repeats::new(),
+            limits: Limits { storage: 0, cells: 0 },
+            cell: CellRecord::none(),
+            error: None,
+            status: ERROR_EXIT,
+            setup: None,
+            stats: None,
+            profile: Vec::new(),
+            coverage: Vec::new(),
+            out: Vec::new(),
+            chars: CharPolicy::Error,
+            input: None,
+            output: None,
+        };
+        match Limits::from_env(0, 0) {
+            Ok(limits) => ar.limits = limits,
+            Err(why) => ar.misconfigured(why),
+        }
+        match CharPolicy::from_env() {
+            Ok(chars) => ar.chars = chars,
+            Err(why) => ar.misconfigured(why),
+        }
+        ar
+    }
+}
+
+// A context holds everything one run of a program has. Generated entry
+// functions take one and pass it to every aheui_* call, so runs on any
+// number of threads are independent of each other.
+#[no_mangle]
+pub extern "C" fn aheui_ctx_new() -> *mut AheuiRt {
+    Box::into_raw(Box::new(AheuiRt::new()))
+}
+
+// writes out what is left of the output
+#[no_mangle]
+pub extern "C" fn aheui_ctx_free(ctx: *mut AheuiRt) {
+    if ctx.is_null() {
+        return;
+    }
+    let mut ar = unsafe { Box::from_raw(ctx) };
+    ar.flush();
+}
+
+fn rt<'a>(ctx: *mut AheuiRt) -> &'a mut AheuiRt {
+    unsafe { &mut *ctx }
+}
+
+// writes out what the program has printed so far
+pub fn flush(ctx: *mut AheuiRt) {
+    rt(ctx).flush();
+}
+
+// copies of every storage, for the debugger
+pub fn storages(ctx: *mut AheuiRt) -> Vec<Vec<i32>> {
+    rt(ctx).dqs.clone()
+}
+
+pub fn set_storage(ctx: *mut AheuiRt, idx: usize, values: Vec<i32>) {
+    rt(ctx).dqs[idx] = values;
+}
+
+// for runtime roots to set limits at link time; the environment still wins
+pub fn set_limits(ctx: *mut AheuiRt, storage: usize, cells: usize) {
+    let ar = rt(ctx);
+    match Limits::from_env(storage, cells) {
+        Ok(limits) => ar.limits = limits,
+        Err(why) => ar.misconfigured(why),
+    }
+}
+
+// for embedders, before calling the entry function; `data` is handed back
+// to every call of `read` or `write`
+#[no_mangle]
+pub extern "C" fn aheui_set_input(ctx: *mut AheuiRt, read: ReadFn, data: *mut c_void) {
+    rt(ctx).input = Some((read, data));
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_set_output(ctx: *mut AheuiRt, write: WriteFn, data: *mut c_void) {
+    let ar = rt(ctx);
+    ar.flush();
+    ar.output = Some((write, data));
+}
+
+struct Script<'a> {
+    input: &'a [u8],
+    output: Vec<u8>,
+}
+
+extern "C" fn script_read(data: *mut c_void, buf: *mut u8, n: usize) -> isize {
+    let script = unsafe { &mut *(data as *mut Script) };
+    let n = if n < script.input.len() { n } else { script.input.len() };
+    let out = unsafe { slice::from_raw_parts_mut(buf, n) };
+    for (o, i) in out.iter_mut().zip(script.input.iter()) {
+        *o = *i;
+    }
+    script.input = &script.input[n..];
+    n as isize
+}
+
+extern "C" fn script_write(data: *mut c_void, buf: *const u8, n: usize) -> isize {
+    let script = unsafe { &mut *(data as *mut Script) };
+    script.output.extend(unsafe { slice::from_raw_parts(buf, n) }.iter().cloned());
+    n as isize
+}
+
+// runs a compiled program's entry function on `input`, giving what it wrote
+// to stdout and its exit code, which is ERROR_EXIT or LIMIT_EXIT for a
+// program that failed or was stopped
+pub fn run_captured(entry: extern "C" fn(*mut AheuiRt) -> i32, input: &[u8]) -> (Vec<u8>, i32) {
+    capture(input, |ctx| entry(ctx))
+}
+
+// the same for anything that runs a program in the context it is given
+pub fn capture<F: FnOnce(*mut AheuiRt) -> i32>(input: &[u8], run: F) -> (Vec<u8>, i32) {
+    let mut script = Script { input: input, output: Vec::new() };
+    let data = &mut script as *mut Script as *mut c_void;
+    let ctx = aheui_ctx_new();
+    aheui_set_input(ctx, script_read, data);
+    aheui_set_output(ctx, script_write, data);
+    let code = run(ctx);
+    aheui_ctx_free(ctx);
+    (script.output, code)
+}
+
+// What generated code tells the runtime about the running cell. A block
+// stores its index to `state[0]` only on its way to the error exit or to
+// aheui_poll, which selects an (x, y, character) triple of `cells`; the
+// runtime sets `state[1]` when a call fails so that generated code can check
+// it without a call. Flow and storage are read from the variables of
+// generated code.
+#[derive(Clone, Copy)]
+struct CellRecord {
+    state: *mut i32,
+    cells: *const i32,
+    flow: *const i8,
+    cur: *const i8,
+}
+
+impl CellRecord {
+    fn none() -> CellRecord {
+        CellRecord {
+            state: 0 as *mut i32,
+            cells: 0 as *const i32,
+            flow: 0 as *const i8,
+            cur: 0 as *const i8,
+        }
+    }
+
+    // x, y and the character of the running cell, if one runs
+    fn pos(&self) -> Option<(i32, i32, char)> {
+        if self.state.is_null() {
+            return None;
+        }
+        let idx = unsafe { *self.state };
+        if idx < 0 {
+            return None;
+        }
+        let r = unsafe { slice::from_raw_parts(self.cells.offset(idx as isize * 3), 3) };
+        Some((r[0], r[1], ::std::char::from_u32(r[2] as u32).unwrap_or('?')))
+    }
+
+    fn set_failed(&self) {
+        if !self.state.is_null() {
+            unsafe { *self.state.offset(1) = 1; }
+        }
+    }
+}
+
+// called once on entry by generated code with its cell record; an error of
+// an earlier run on the same context is forgotten, one in the environment
+// is not
+#[no_mangle]
+pub extern "C" fn aheui_cell_record(ctx: *mut AheuiRt, state: *mut i32, cells: *const i32,
+                                    flow: *const i8, cur: *const i8) {
+    let ar = rt(ctx);
+    ar.cell = CellRecord {
+        state: state,
+        cells: cells,
+        flow: flow,
+        cur: cur,
+    };
+    ar.error = ar.setup.clone();
+    ar.status = ERROR_EXIT;
+    if ar.error.is_some() {
+        ar.cell.set_failed();
+    }
+}
+
+// values shown per storage in a dump
+const DUMP_VALUES: usize = 8;
+
+// the state at the last cell that ran: where, which way, which storage, and
+// what is in every storage that has anything
+fn dump(why: &str, cell: CellRecord, dqs: &[Vec<i32>]) -> String {
+    let mut out = format!("aheui: {} {}", why, cell_name(cell));
+    if cell.pos().is_some() {
+        let (flow, cur) = unsafe { (*cell.flow, *cell.cur) };
+        out.push_str(&format!(" moving {}, storage {} selected", dir_name(flow), cur));
+    }
+    out.push('\n');
+    for (i, d) in dqs.iter().enumerate().filter(|&(_, d)| !d.is_empty()) {
+        // stacks from the bottom up to the top, the queue from its front
+        let (kind, shown, more) = if i == 21 {
+            let n = if d.len() > DUMP_VALUES { DUMP_VALUES } else { d.len() };
+            ("queue", &d[..n], if n < d.len() { " …" } else { "" })
+        } else {
+            let n = d.len().saturating_sub(DUMP_VALUES);
+            ("stack", &d[n..], if n > 0 { "… " } else { "" })
+        };
+        let values: Vec<String> = shown.iter().map(|v| v.to_string()).collect();
+        let values = if i == 21 {
+            format!("front {}{}", values.join(" "), more)
+        } else {
+            format!("{}{} top", more, values.join(" "))
+        };
+        out.push_str(&format!("  {:>2} {} of {}: {}\n", i, kind, d.len(), values));
+    }
+    if dqs.iter().all(|d| d.is_empty()) {
+        out.push_str("  all storages empty\n");
+    }
+    out
+}
+
+#[cfg(target_os = "linux")]
+const SIGINT: i32 = 2;
+#[cfg(target_os = "linux")]
+const SIGUSR1: i32 = 10;
+#[cfg(target_os = "linux")]
+const SIGTERM: i32 = 15;
+
+#[cfg(target_os = "linux")]
+extern "C" {
+    fn signal(sig: i32, handler: extern "C" fn(i32)) -> usize;
+    fn _exit(status: i32) -> !;
+}
+
+// the signal caught and not yet acted on, or 0. Generated code loads it
+// wherever a loop goes back and calls aheui_poll once it is set, so even a
+// loop that never calls the runtime otherwise sees it.
+#[no_mangle]
+#[allow(non_upper_case_globals)]
+pub static aheui_signal: AtomicUsize = ATOMIC_USIZE_INIT;
+
+// Only marks the signal pending, which is all a handler may safely do; the
+// runtime acts on it at the next poll. A second SIGINT or SIGTERM before
+// then, e.g. while waiting for input, ends the process.
+#[cfg(target_os = "linux")]
+extern "C" fn on_signal(sig: i32) {
+    if sig == SIGUSR1 {
+        aheui_signal.compare_and_swap(0, sig as usize, Ordering::SeqCst);
+        return;
+    }
+    let prev = aheui_signal.swap(sig as usize, Ordering::SeqCst) as i32;
+    if prev == SIGINT || prev == SIGTERM {
+        unsafe { _exit(128 + sig); }
+    }
+}
+
+// Dumps the state on SIGUSR1, SIGINT and SIGTERM; the last two also stop
+// the program through its error exit with 128 + the signal. For the main of
+// a runtime root: a program embedded elsewhere leaves the host's signals
+// alone.
+#[cfg(target_os = "linux")]
+pub fn install_signals() {
+    unsafe {
+        for &sig in [SIGINT, SIGTERM, SIGUSR1].iter() {
+            signal(sig, on_signal);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn take_signal(ar: &mut AheuiRt) {
+    let sig = aheui_signal.swap(0, Ordering::SeqCst) as i32;
+    let why = match sig {
+        0 => return,
+        SIGINT => "interrupted",
+        SIGTERM => "terminated",
+        _ => "dump requested",
+    };
+    // the output so far comes before the dump
+    ar.flush();
+    let _ = stderr().write_all(dump(why, ar.cell, &ar.dqs).as_bytes());
+    // SIGUSR1 only dumps; the program goes on
+    if sig != SIGUSR1 {
+        ar.fail_with(why.to_string(), 128 + sig);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn install_signals() {}
+
+#[cfg(not(target_os = "linux"))]
+fn take_signal(_: &mut AheuiRt) {}
+
+// called by generated code with aheui_signal set, after storing the index
+// of the block it is in
+#[no_mangle]
+pub extern "C" fn aheui_poll(ctx: *mut AheuiRt) {
+    take_signal(rt(ctx));
+}
+
+// the place of an error, as every backend reports it
+pub fn cell_at(x: i32, y: i32, c: char) -> String {
+    format!("at ({}, {}) '{}'", x, y, c)
+}
+
+fn cell_name(cell: CellRecord) -> String {
+    match cell.pos() {
+        Some((x, y, c)) => cell_at(x, y, c),
+        None => "at an unknown cell".to_string(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_failed(ctx: *mut AheuiRt) -> i32 {
+    rt(ctx).error.is_some() as i32
+}
+
+// the common error exit of generated code: reports the error and gives the
+// exit code for the program to return
+#[no_mangle]
+pub extern "C" fn aheui_error_exit(ctx: *mut AheuiRt) -> i32 {
+    let ar = rt(ctx);
+    ar.flush();
+    {
+        let why = ar.error.as_ref().map(|e| &e[..]).unwrap_or("unknown error");
+        let _ = writeln!(stderr(), "error {}: {}", cell_name(ar.cell), why);
+    }
+    ar.finish();
+    ar.status
+}
+
+// for the VM, which reports the error at its own instruction: the error and
+// the exit code for it
+pub fn take_error(ctx: *mut AheuiRt) -> Option<(String, i32)> {
+    let ar = rt(ctx);
+    ar.error.take().map(|why| (why, ar.status))
+}
+
+// the divisor of ㄴ and ㄹ, failing on 0 and giving 1 so that the generated
+// code does not divide by zero on its way to the error exit
+#[no_mangle]
+pub extern "C" fn aheui_divisor(ctx: *mut AheuiRt, v: i32) -> i32 {
+    if v != 0 {
+        return v;
+    }
+    rt(ctx).fail("division by zero".to_string());
+    1
+}
+
+fn sizes(dqs: &[Vec<i32>]) -> String {
+    let sizes: Vec<String> = dqs.iter().enumerate().filter(|&(_, d)| !d.is_empty())
+        .map(|(i, d)| format!("{}:{}", i, d.len())).collect();
+    if sizes.is_empty() { "all empty".to_string() } else { sizes.join(" ") }
+}
+
+// why one more value cannot go to storage `idx`, if it cannot
+fn over_limit(dqs: &[Vec<i32>], limits: Limits, idx: usize) -> Option<String> {
+    if limits.storage > 0 && dqs[idx].len() >= limits.storage {
+        return Some(format!("storage {} is full with {} values", idx, limits.storage));
+    }
+    if limits.cells > 0 && dqs.iter().fold(0, |a, d| a + d.len()) >= limits.cells {
+        return Some(format!("the storages are full with {} values", limits.cells));
+    }
+    None
+}
+
+// the push path: every value added to a storage comes through here. False
+// past a limit, with the error recorded for generated code to leave through
+// its error exit.
+fn grow(ar: &mut AheuiRt, idx: usize) -> bool {
+    match over_limit(&ar.dqs, ar.limits, idx) {
+        Some(why) => {
+            let why = format!("{}; storage sizes: {}", why, sizes(&ar.dqs));
+            ar.fail_with(why, LIMIT_EXIT);
+            false
+        },
+        None => true,
+    }
+}
+
+pub fn dir_name(flow: i8) -> &'static str {
+    match flow {
+        0 => "left",
+        1 => "right",
+        2 => "up",
+        _ => "down",
+    }
+}
+
+// a state seen again after input or output is not a hang
+fn forget_states(ctx: *mut AheuiRt) {
+    rt(ctx).repeats = Repeats::new();
+}
+
+// reads through `read` up to a newline
+fn read_line_from(ctx: *mut AheuiRt, read: ReadFn, data: *mut c_void) -> Option<String> {
+    let mut line = Vec::new();
+    loop {
+        let mut b = 0u8;
+        match read(data, &mut b, 1) {
+            0 => break,
+            n if n < 0 => {
+                rt(ctx).fail("cannot read input".to_string());
+                return None;
+            },
+            _ => {
+                line.push(b);
+                if b == b'\n' {
+                    break;
+                }
+            },
+        }
+    }
+    if line.is_empty() { None } else { Some(String::from_utf8_lossy(&line).into_owned()) }
+}
+
+// a line of input, or None at the end of it. There is no prompt, so that
+// output is the same from every backend.
+fn read_line(ctx: *mut AheuiRt) -> Option<String> {
+    let input = {
+        let ar = rt(ctx);
+        ar.flush();
+        ar.input
+    };
+    if let Some((read, data)) = input {
+        return read_line_from(ctx, read, data);
+    }
+    // through the buffer of stdin itself, which keeps what follows the line
+    let mut line = String::new();
+    match stdin().read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => Some(line),
+        Err(e) => {
+            rt(ctx).fail(format!("cannot read input: {}", e));
+            None
+        },
+    }
+}
+
+// -1 at the end of input
+#[no_mangle] #[inline(never)]
+pub extern "C" fn aheui_getchar(ctx: *mut AheuiRt) -> i32 {
+    forget_states(ctx);
+    match read_line(ctx) {
+        Some(line) => line.chars().next().map_or(-1, |c| c as i32),
+        None => -1,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_putchar(ctx: *mut AheuiRt, c: i32) {
+    forget_states(ctx);
+    let ar = rt(ctx);
+    let policy = ar.chars;
+    match policy.apply(c) {
+        Ok(Some(c)) => ar.emit(&c.to_string()),
+        Ok(None) => {},
+        Err(why) => ar.fail(why),
+    }
+}
+
+// -1 at the end of input
+#[no_mangle]
+pub extern "C" fn aheui_getint(ctx: *mut AheuiRt) -> i32 {
+    forget_states(ctx);
+    let line = match read_line(ctx) {
+        Some(line) => line,
+        None => return -1,
+    };
+    debug!("aheui_getint: line {:?}", line);
+    match line.trim().parse() {
+        Ok(i) => i,
+        Err(_) => {
+            rt(ctx).fail(format!("bad input: {:?} is not an integer", line.trim()));
+            0
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_putint(ctx: *mut AheuiRt, i: i32) {
+    forget_states(ctx);
+    debug!("aheui_putint({:?})", i);
+    rt(ctx).emit(&i.to_string());
+}
+
+// where --trace records go: the file named by AHEUI_TRACE, or stderr
+pub fn open_trace() -> Box<Write> {
+    match env::var("AHEUI_TRACE") {
+        Ok(path) => match File::create(&path) {
+            Ok(f) => Box::new(LineWriter::new(f)),
+            Err(e) => die(format!("cannot open {}: {}", path, e)),
+        },
+        Err(_) => Box::new(stderr()),
+    }
+}
+
+fn json_char(c: char) -> String {
+    match c {
+        '"' => "\\\"".to_string(),
+        '\\' => "\\\\".to_string(),
+        c if (c as u32) < 0x20 => format!("\\u{:04x}", c as u32),
+        c => c.to_string(),
+    }
+}
+
+// one JSON Lines record of the cell about to run
+// what all storages hold, so that traces disagree on any value and not only
+// on the selected storage
+fn digest(dqs: &[Vec<i32>]) -> u64 {
+    let mut h = SipHasher::new();
+    for (i, d) in dqs.iter().enumerate().filter(|&(_, d)| !d.is_empty()) {
+        (i, d).hash(&mut h);
+    }
+    h.finish()
+}
+
+pub fn trace_record(step: u64, x: i32, y: i32, c: char, flow: i8, idx: i8,
+                    dqs: &[Vec<i32>]) -> String {
+    let dir = dir_name(flow);
+    let dq = dqs.get(idx as usize).map(|dq| &dq[..]).unwrap_or(&[]);
+    let top = match (idx, dq.first(), dq.last()) {
+        (21, Some(v), _) => v.to_string(),
+        (_, _, Some(v)) => v.to_string(),
+        _ => "null".to_string(),
+    };
+    format!("{{\"step\":{},\"x\":{},\"y\":{},\"char\":\"{}\",\"direction\":\"{}\",\
+             \"storage\":{},\"top\":{},\"size\":{},\"digest\":\"{:016x}\"}}",
+            step, x, y, json_char(c), dir, idx, top, dq.len(), digest(dqs))
+}
+
+// only called by code generated with --trace. Exported as `aheui_trace` by
+// runtime.rs; runtime_debug.rs exports the debugger's hook instead.
+pub extern "C" fn aheui_trace(ctx: *mut AheuiRt, x: i32, y: i32, c: char, flow: i8, idx: i8) {
+    let ar = rt(ctx);
+    ar.steps += 1;
+    let record = trace_record(ar.steps, x, y, c, flow, idx, &ar.dqs);
+    if ar.trace.is_none() {
+        ar.trace = Some(open_trace());
+    }
+    let _ = writeln!(ar.trace.as_mut().unwrap(), "{}", record);
+}
+
+// the step budget of code generated with --max-steps: `n`, unless
+// AHEUI_MAX_STEPS says otherwise. 0 means no limit. The generated code
+// compares unsigned, so the budget is a u64 passed as i64; a bad value gives
+// a budget of 0, which sends the first cell to the error exit.
+#[no_mangle]
+pub extern "C" fn aheui_step_limit(ctx: *mut AheuiRt, n: i64) -> i64 {
+    let n = match env::var("AHEUI_MAX_STEPS") {
+        Ok(v) => match v.trim().parse::<u64>() {
+            Ok(n) => n,
+            Err(_) => {
+                rt(ctx).fail(format!("AHEUI_MAX_STEPS is not a number: {:?}", v));
+                return 0;
+            },
+        },
+        Err(_) => n as u64,
+    };
+    if n == 0 { -1 } else { n as i64 }
+}
+
+// the step budget ran out; generated code goes on to its error exit
+#[no_mangle]
+pub extern "C" fn aheui_step_exceeded(ctx: *mut AheuiRt, limit: i64) {
+    let ar = rt(ctx);
+    let why = match ar.cell.pos() {
+        Some(_) => {
+            let flow = unsafe { *ar.cell.flow };
+            format!("step budget of {} exceeded moving {}", limit as u64, dir_name(flow))
+        },
+        None => format!("step budget of {} exceeded", limit as u64),
+    };
+    ar.fail_with(why, LIMIT_EXIT);
+}
+
+// called on entry by code generated with --stats; counting starts only
+// with AHEUI_STATS set to something other than 0
+#[no_mangle]
+pub extern "C" fn aheui_stats_start(ctx: *mut AheuiRt) {
+    let on = env::var("AHEUI_STATS").map(|v| v != "" && v != "0").unwrap_or(false);
+    if on {
+        rt(ctx).stats = Some(Stats::new());
+    }
+}
+
+// one of the STAT_ kinds happened; any other kind is ignored
+#[no_mangle]
+pub extern "C" fn aheui_stat(ctx: *mut AheuiRt, kind: i32) {
+    if let Some(ref mut stats) = rt(ctx).stats {
+        if let Some(n) = stats.counts.get_mut(kind as usize) {
+            *n += 1;
+        }
+    }
+}
+
+fn state_hash(x: i32, y: i32, flow: i8, idx: i8, dqs: &[Vec<i32>]) -> u64 {
+    let mut h = SipHasher::new();
+    (x, y, flow, idx, dqs).hash(&mut h);
+    h.finish()
+}
+
+// called on every cell by code generated with --detect-hangs. The same
+// cell, direction and storages twice with no I/O in between loop forever.
+#[no_mangle]
+pub extern "C" fn aheui_hang_check(ctx: *mut AheuiRt, x: i32, y: i32, _: char, flow: i8,
+                                   idx: i8) {
+    let ar = rt(ctx);
+    let h = state_hash(x, y, flow, idx, &ar.dqs);
+    if ar.repeats.see(h) {
+        ar.fail_with(format!("endless loop moving {}; the state repeats with no input or \
+                              output in between", dir_name(flow)), LIMIT_EXIT);
+    }
+}
+
+// called on entry by code generated with --profile: a counter per block,
+// which generated code counts in directly. They belong to the context, so
+// every run starts from zero.
+#[no_mangle]
+pub extern "C" fn aheui_profile_counts(ctx: *mut AheuiRt, blocks: u32) -> *mut u64 {
+    let ar = rt(ctx);
+    ar.profile = vec!(0; blocks as usize);
+    ar.profile.as_mut_ptr()
+}
+
+// the same for --coverage, with six counters per block
+#[no_mangle]
+pub extern "C" fn aheui_coverage_counts(ctx: *mut AheuiRt, blocks: u32) -> *mut u64 {
+    let ar = rt(ctx);
+    ar.coverage = vec!(0; 6 * blocks as usize);
+    ar.coverage.as_mut_ptr()
+}
+
+// called on ㅎ and on the error exit by code generated with --profile; `pos`
+// holds an (x, y) pair per counter. Writes `x y count` lines for
+// `aheui profile` to read.
+#[no_mangle]
+pub extern "C" fn aheui_profile_write(ctx: *mut AheuiRt, pos: *const u32) {
+    let counts = &rt(ctx).profile;
+    let pos = unsafe { slice::from_raw_parts(pos, 2 * counts.len()) };
+    let path = env::var("AHEUI_PROFILE").unwrap_or("aheui.prof".to_string());
+    let mut f = match File::create(&path) {
+        Ok(f) => f,
+        Err(e) => die(format!("cannot open {}: {}", path, e)),
+    };
+    for (i, &count) in counts.iter().enumerate() {
+        if count > 0 {
+            let _ = writeln!(f, "{} {} {}", pos[2 * i], pos[2 * i + 1], count);
+        }
+    }
+}
+
+// called on ㅎ and on the error exit by code generated with --coverage; six
+// counters per (x, y) pair in `pos`. Writes `x y left right up down on
+// reversed` lines for `aheui coverage` to read.
+#[no_mangle]
+pub extern "C" fn aheui_coverage_write(ctx: *mut AheuiRt, pos: *const u32) {
+    let counts = &rt(ctx).coverage;
+    let pos = unsafe { slice::from_raw_parts(pos, counts.len() / 3) };
+    let path = env::var("AHEUI_COVERAGE").unwrap_or("aheui.cov".to_string());
+    let mut f = match File::create(&path) {
+        Ok(f) => f,
+        Err(e) => die(format!("cannot open {}: {}", path, e)),
+    };
+    for (i, slots) in counts.chunks(6).enumerate() {
+        if slots.iter().any(|&c| c > 0) {
+            let slots: Vec<String> = slots.iter().map(|c| c.to_string()).collect();
+            let _ = writeln!(f, "{} {} {}", pos[2 * i], pos[2 * i + 1], slots.join(" "));
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_push(ctx: *mut AheuiRt, idx: i8, v: i32) {
+    debug!("aheui_push(idx {}, val {})", idx, v);
+    let idx = idx as usize;
+
+    let ar = rt(ctx);
+    if idx == 27 {
+        return ar.fail(EXTENSION.to_string());
+    }
+    if !grow(ar, idx) {
+        return;
+    }
+    ar.dqs[idx].push(v);
+    ar.grown(idx);
+    debug!("aheui_push: stack[{}]: {:?}", idx, ar.dqs[idx]);
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_pop(ctx: *mut AheuiRt, idx: i8) -> i32 {
+    let idx = idx as usize;
+
+    let ar = rt(ctx);
+    let ret = ar.take(idx);
+    debug!("aheui_pop: storage {} gave {}", idx, ret);
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_dup(ctx: *mut AheuiRt, idx: i8) {
+    let idx = idx as usize;
+
+    let ar = rt(ctx);
+    if idx == 27 {
+        return ar.fail(EXTENSION.to_string());
+    }
+    if ar.dqs[idx].is_empty() {
+        return ar.fail(format!("storage {} is empty", idx));
+    }
+    if !grow(ar, idx) {
+        return;
+    }
+    match idx {
+        21 => {
+            let dqs = &mut ar.dqs[idx];
+            let n = dqs[0];
+            dqs.insert(0, n);
+        },
+        _ => {
+            let dqs = &mut ar.dqs[idx];
+            let len = dqs.len();
+            let n = dqs[len - 1];
+            dqs.push(n);
+        },
+    }
+    ar.grown(idx);
+    debug!("aheui_dup: stack[{}]: {:?}", idx, ar.dqs[idx]);
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_swap(ctx: *mut AheuiRt, idx: i8) {
+    let idx = idx as usize;
+
+    let ar = rt(ctx);
+    if idx == 27 {
+        return ar.fail(EXTENSION.to_string());
+    }
+    if ar.dqs[idx].len() < 2 {
+        return ar.fail(format!("storage {} has fewer than 2 values", idx));
+    }
+    match idx {
+        21 => {
+            let dqs = &mut ar.dqs[idx];
+            let m = dqs[0];
+            let n = dqs[1];
+            dqs[0] = n;
+            dqs[1] = m;
+        },
+        _ => {
+            let dqs = &mut ar.dqs[idx];
+            let len = dqs.len();
+            let m = dqs[len - 2];
+            let n = dqs[len - 1];
+            dqs[len - 2] = n;
+            dqs[len - 1] = m;
+        },
+    }
+    debug!("aheui_swap: stack[{}]: {:?}", idx, ar.dqs[idx]);
+}
+
+#[no_mangle]
+pub extern "C" fn aheui_halt(ctx: *mut AheuiRt, idx: i8) -> i32 {
+    let idx = idx as usize;
+
+    let ar = rt(ctx);
+    let ret = match idx {
+        _ if idx != 27 && ar.dqs[idx].is_empty() => 0,
+        _ => ar.take(idx),
+    };
+    debug!("aheui_halt: exit code {}", ret);
+    // a failed ㅎ goes on to the error exit, which finishes instead
+    ar.flush();
+    if ar.error.is_none() {
+        ar.finish();
+    }
+    ret
+}
+
+#[test]
+fn test_errors() {
+    let ctx = aheui_ctx_new();
+    aheui_push(ctx, 0, 5);
+    aheui_swap(ctx, 0);
+    assert!(aheui_failed(ctx) == 1 && aheui_pop(ctx, 0) == 5);
+    assert!(take_error(ctx) == Some(("storage 0 has fewer than 2 values".to_string(), ERROR_EXIT)));
+    assert!(aheui_failed(ctx) == 0);
+
+    // the first error is the one reported
+    assert!(aheui_pop(ctx, 21) == 0);
+    aheui_push(ctx, 27, 1);
+    assert!(aheui_divisor(ctx, 0) == 1 && aheui_divisor(ctx, 3) == 3);
+    assert!(take_error(ctx).unwrap().0 == "storage 21 is empty");
+    aheui_dup(ctx, 27);
+    assert!(take_error(ctx).unwrap().0 == EXTENSION);
+    assert!(aheui_halt(ctx, 3) == 0 && aheui_failed(ctx) == 0);
+    aheui_putchar(ctx, 0xD800);
+    assert!(take_error(ctx).unwrap().0 == "55296 is not a character");
+    assert!(aheui_error_exit(ctx) == ERROR_EXIT);
+
+    // the runtime stopping a program has an exit code of its own, though
+    // an earlier error still wins
+    aheui_step_exceeded(ctx, 100);
+    aheui_pop(ctx, 0);
+    assert!(take_error(ctx) == Some(("step budget of 100 exceeded".to_string(), LIMIT_EXIT)));
+    aheui_pop(ctx, 0);
+    aheui_step_exceeded(ctx, 100);
+    assert!(take_error(ctx) == Some(("storage 0 is empty".to_string(), ERROR_EXIT)));
+    aheui_step_exceeded(ctx, -1);
+    assert!(aheui_error_exit(ctx) == LIMIT_EXIT);
+    aheui_ctx_free(ctx);
+}
+
+#[test]
+fn test_contexts() {
+    // two programs in one thread, each with its own storages and errors
+    let (a, b) = (aheui_ctx_new(), aheui_ctx_new());
+    aheui_push(a, 0, 1);
+    aheui_push(b, 0, 2);
+    aheui_push(b, 21, 3);
+    assert!(aheui_pop(b, 21) == 3 && aheui_pop(a, 21) == 0);
+    assert!(aheui_failed(a) == 1 && aheui_failed(b) == 0);
+    assert!(storages(a)[0] == vec!(1) && storages(b)[0] == vec!(2));
+
+    // and its own counters, fresh for every run
+    let pa = aheui_profile_counts(a, 2);
+    unsafe { *pa.offset(1) += 5; }
+    let pb = aheui_profile_counts(b, 2);
+    assert!(rt(a).profile == vec!(0, 5) && rt(b).profile == vec!(0, 0) && pa != pb);
+    aheui_coverage_counts(a, 2);
+    assert!(rt(a).coverage.len() == 12);
+    aheui_ctx_free(a);
+    assert!(aheui_halt(b, 0) == 2);
+    aheui_ctx_free(b);
+    let c = aheui_ctx_new();
+    assert!(rt(c).profile.is_empty());
+    aheui_ctx_free(c);
+}
+
+#[test]
+fn test_output() {
+    assert!(CharPolicy::Error.apply('밤' as i32) == Ok(Some('밤')));
+    assert!(CharPolicy::Replace.apply(0xD800) == Ok(Some('\u{fffd}')));
+    assert!(CharPolicy::Skip.apply(0x110000) == Ok(None));
+    assert!(CharPolicy::Error.apply(-1) == Err("-1 is not a character".to_string()));
+
+    let ctx = aheui_ctx_new();
+    aheui_putint(ctx, 42);
+    aheui_putchar(ctx, '밤' as i32);
+    assert!(rt(ctx).out == "42밤".as_bytes());
+    rt(ctx).out.clear();
+    aheui_ctx_free(ctx);
+}
+
+#[test]
+fn test_run_captured() {
+    // what generated code for 밯빠맣맣 and then 희 would call
+    extern "C" fn echo_twice(ctx: *mut AheuiRt) -> i32 {
+        aheui_push(ctx, 0, aheui_getchar(ctx));
+        aheui_dup(ctx, 0);
+        aheui_putchar(ctx, aheui_pop(ctx, 0));
+        aheui_putchar(ctx, aheui_pop(ctx, 0));
+        aheui_push(ctx, 0, aheui_getint(ctx));
+        aheui_putint(ctx, aheui_pop(ctx, 0));
+        aheui_halt(ctx, 0)
+    }
+    let (out, code) = run_captured(echo_twice, "가\n 42\n".as_bytes());
+    assert!(out == "가가42".as_bytes() && code == 0);
+    // the integer is past the end of input
+    let (out, code) = run_captured(echo_twice, "a".as_bytes());
+    assert!(out == "aa-1".as_bytes() && code == 0);
+}
+
+#[test]
+fn test_limits() {
+    let mut dqs = vec!(Vec::new(); 27);
+    dqs[0] = vec!(1, 2);
+    dqs[21] = vec!(3);
+    let none = Limits { storage: 0, cells: 0 };
+    assert!(over_limit(&dqs, none, 0).is_none());
+    let limits = Limits { storage: 2, cells: 4 };
+    assert!(over_limit(&dqs, limits, 0) == Some("storage 0 is full with 2 values".to_string()));
+    assert!(over_limit(&dqs, limits, 21).is_none());
+    dqs[1] = vec!(4);
+    assert!(over_limit(&dqs, limits, 21) ==
+            Some("the storages are full with 4 values".to_string()));
+    assert!(sizes(&dqs) == "0:2 1:1 21:1" && sizes(&[Vec::new()]) == "all empty");
+
+    let cells = [0, 0, '아' as i32, 3, 1, '밤' as i32];
+    let (flow, cur) = (1i8, 0i8);
+    let mut state = [1, 0];
+    let record = CellRecord {
+        state: state.as_mut_ptr(),
+        cells: cells.as_ptr(),
+        flow: &flow,
+        cur: &cur,
+    };
+    assert!(cell_name(record) == "at (3, 1) '밤'");
+    assert!(cell_name(CellRecord::none()) == "at an unknown cell");
+    record.set_failed();
+    assert!(state == [1, 1]);
+    // before the first block
+    unsafe { *record.state = -1; }
+    assert!(cell_name(record) == "at an unknown cell");
+
+    // a full storage stops the program through the error exit
+    let ctx = aheui_ctx_new();
+    rt(ctx).limits = Limits { storage: 1, cells: 0 };
+    aheui_push(ctx, 0, 1);
+    aheui_push(ctx, 0, 2);
+    aheui_dup(ctx, 0);
+    assert!(rt(ctx).dqs[0] == vec!(1));
+    assert!(take_error(ctx) == Some(("storage 0 is full with 1 values; storage sizes: 0:1"
+                                     .to_string(), LIMIT_EXIT)));
+
+    // so does an exhausted step budget, which says which way it was going
+    unsafe { *record.state = 1; }
+    rt(ctx).cell = record;
+    aheui_step_exceeded(ctx, 100);
+    assert!(take_error(ctx) == Some(("step budget of 100 exceeded moving right".to_string(),
+                                     LIMIT_EXIT)));
+
+    // the record goes with the run that set it, and the next run starts
+    // without the errors of the last one
+    aheui_halt(ctx, 0);
+    assert!(cell_name(rt(ctx).cell) == "at an unknown cell");
+    aheui_pop(ctx, 0);
+    aheui_cell_record(ctx, state.as_mut_ptr(), cells.as_ptr(), &flow, &cur);
+    assert!(aheui_failed(ctx) == 0 && rt(ctx).error.is_none());
+
+    // a mistake in the environment fails every run on the context instead
+    // of ending the process
+    let why = "AHEUI_MAX_CELLS is not a number: x".to_string();
+    rt(ctx).misconfigured(why.clone());
+    assert!(aheui_failed(ctx) == 1);
+    state[1] = 0;
+    aheui_cell_record(ctx, state.as_mut_ptr(), cells.as_ptr(), &flow, &cur);
+    assert!(state[1] == 1 && take_error(ctx) == Some((why, ERROR_EXIT)));
+    aheui_ctx_free(ctx);
+}
+
+#[test]
+fn test_dump() {
+    let mut dqs = vec!(Vec::new(); 28);
+    assert!(dump("interrupted", CellRecord::none(), &dqs) ==
+            "aheui: interrupted at an unknown cell\n  all storages empty\n");
+
+    dqs[0] = (1..11).collect();
+    dqs[3] = vec!(7);
+    dqs[21] = (1..11).collect();
+    let cells = [2, 0, '따' as i32];
+    let (flow, cur) = (3i8, 3i8);
+    let mut state = [0, 0];
+    let record = CellRecord {
+        state: state.as_mut_ptr(),
+        cells: cells.as_ptr(),
+        flow: &flow,
+        cur: &cur,
+    };
+    assert!(dump("dump requested", record, &dqs) == "\
+aheui: dump requested at (2, 0) '따' moving down, storage 3 selected
+   0 stack of 10: … 3 4 5 6 7 8 9 10 top
+   3 stack of 1: 7 top
+  21 queue of 10: front 1 2 3 4 5 6 7 8 …
+");
+}
+
+#[test]
+fn test_stats() {
+    let ctx = aheui_ctx_new();
+    rt(ctx).stats = Some(Stats::new());
+    for &kind in [STAT_STEP, STAT_STEP, STAT_STORAGE, STAT_BRANCH, STAT_REVERSED].iter() {
+        aheui_stat(ctx, kind);
+    }
+    aheui_push(ctx, 0, 1);
+    aheui_dup(ctx, 0);
+    aheui_pop(ctx, 0);
+    aheui_push(ctx, 21, 1);
+    let report = rt(ctx).stats.as_ref().unwrap().report();
+    assert!(report == "\
+aheui: 2 steps
+  arithmetic 0, storage 1, I/O 0, branch 2
+  storage switches 0, branches reversed 1
+  peak depths 0:2 21:1
+");
+    aheui_ctx_free(ctx);
+
+    let ctx = aheui_ctx_new();
+    aheui_stat(ctx, STAT_STEP);
+    assert!(rt(ctx).stats.is_none());
+    rt(ctx).stats = Some(Stats::new());
+    aheui_stat(ctx, -1);
+    aheui_stat(ctx, 1000);
+    assert!(rt(ctx).stats.as_ref().unwrap().counts.iter().all(|&n| n == 0));
+    aheui_ctx_free(ctx);
+}
+
+#[test]
+fn test_repeats() {
+    // 1 2 3 4 5 6 7 5 6 7 ...: caught once a saved state is inside the loop
+    let states = [1, 2, 3, 4, 5, 6, 7, 5, 6, 7, 5, 6, 7, 5, 6, 7];
+    let mut r = Repeats::new();
+    let first = states.iter().position(|&h| r.see(h));
+    assert!(first == Some(9));
+
+    let mut r = Repeats::new();
+    assert!(!(0..1000).any(|h| r.see(h)));
+
+    let dqs = vec!(vec!(1), Vec::new());
+    assert!(state_hash(0, 0, 3, 0, &dqs) == state_hash(0, 0, 3, 0, &dqs.clone()));
+    assert!(state_hash(0, 0, 3, 0, &dqs) != state_hash(0, 0, 3, 1, &dqs));
+}
+
+#[test]
+fn test_trace_record() {
+    let mut dqs = vec!(Vec::new(); 27);
+    let empty = format!("{:016x}", digest(&dqs));
+    assert!(trace_record(1, 0, 0, '밤', 3, 0, &dqs) ==
+            format!(r#"{{"step":1,"x":0,"y":0,"char":"밤","direction":"down","storage":0,"top":null,"size":0,"digest":"{}"}}"#, empty));
+    dqs[21] = vec!(3, 4);
+    assert!(trace_record(7, 2, 1, '"', 1, 21, &dqs).starts_with(
+            r#"{"step":7,"x":2,"y":1,"char":"\"","direction":"right","storage":21,"top":3,"size":2,"#));
+    dqs[5] = vec!(3, 4);
+    assert!(trace_record(8, 2, 1, '\n', 0, 5, &dqs).contains(r#""char":"\u000a""#));
+    assert!(trace_record(8, 2, 1, '\n', 0, 5, &dqs).contains(r#""top":4"#));
+
+    // storages other than the selected one count too
+    let before = digest(&dqs);
+    dqs[0].push(1);
+    assert!(digest(&dqs) != before && digest(&dqs) == digest(&dqs.clone()));
+    // a storage past the last one used does not
+    let mut more = dqs.clone();
+    more.push(Vec::new());
+    assert!(digest(&more) == digest(&dqs));
+}
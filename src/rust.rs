@@ -0,0 +1,426 @@
+// Rust source backend: emits a self-contained module with a `run` function
+// that steps through the grid as a loop over a `match` on the cell index.
+// The storages mirror `rt::AheuiRt`, and I/O goes through the caller's
+// reader and writer instead of the process's stdin/stdout.
+
+use std::fmt::Write;
+
+use super::{Hangul, Flow, next_pos, move_pos};
+
+pub const ALLOW: &'static str = "dead_code, unused_mut, unused_variables, unused_assignments, unreachable_code";
+
+// the exit code of a failed program, as rt::ERROR_EXIT; this file is also
+// built into aheui-macros, which has no rt
+pub const ERROR_EXIT: i32 = 125;
+
+// errors are recorded like in rt.rs and reported once the cell is done, so
+// nothing here panics inside the embedding crate
+const PRELUDE: &'static str = r#"use std::io::{Read, Write};
+
+pub struct AheuiRt {
+    dqs: Vec<Vec<i32>>,
+    error: Option<String>,
+}
+
+impl AheuiRt {
+    fn new() -> Self {
+        let mut dqs = Vec::new();
+        for _ in 0..28 { dqs.push(Vec::new()); }
+
+        AheuiRt {
+            dqs: dqs,
+            error: None,
+        }
+    }
+
+    fn fail(&mut self, why: String) {
+        if self.error.is_none() {
+            self.error = Some(why);
+        }
+    }
+
+    fn storage(&mut self, idx: usize, n: usize) -> Option<&mut Vec<i32>> {
+        if idx == 27 {
+            self.fail("the extension storage (27) is not supported".to_string());
+            return None;
+        }
+        if self.dqs[idx].len() < n {
+            self.fail(match n {
+                1 => format!("storage {} is empty", idx),
+                _ => format!("storage {} has fewer than {} values", idx, n),
+            });
+            return None;
+        }
+        Some(&mut self.dqs[idx])
+    }
+
+    fn push(&mut self, idx: usize, v: i32) {
+        if let Some(dqs) = self.storage(idx, 0) {
+            dqs.push(v);
+        }
+    }
+
+    fn pop(&mut self, idx: usize) -> i32 {
+        match self.storage(idx, 1) {
+            Some(dqs) => if idx == 21 { dqs.remove(0) } else { dqs.pop().unwrap() },
+            None => 0,
+        }
+    }
+
+    fn dup(&mut self, idx: usize) {
+        if let Some(dqs) = self.storage(idx, 1) {
+            match idx {
+                21 => {
+                    let n = dqs[0];
+                    dqs.insert(0, n);
+                },
+                _ => {
+                    let n = dqs[dqs.len() - 1];
+                    dqs.push(n);
+                },
+            }
+        }
+    }
+
+    fn swap(&mut self, idx: usize) {
+        if let Some(dqs) = self.storage(idx, 2) {
+            let len = dqs.len();
+            match idx {
+                21 => dqs.swap(0, 1),
+                _ => dqs.swap(len - 2, len - 1),
+            }
+        }
+    }
+
+    fn halt(&mut self, idx: usize) -> i32 {
+        match self.storage(idx, 0) {
+            Some(dqs) => match idx {
+                21 if !dqs.is_empty() => dqs.remove(0),
+                _ => dqs.pop().unwrap_or(0),
+            },
+            None => 0,
+        }
+    }
+
+    fn divisor(&mut self, v: i32) -> i32 {
+        if v != 0 {
+            return v;
+        }
+        self.fail("division by zero".to_string());
+        1
+    }
+
+    // a line of input with its newline, or None at the end of input
+    fn read_line(&mut self, input: &mut Read) -> Option<String> {
+        let mut line = Vec::new();
+        let mut buf = [0u8];
+        loop {
+            match input.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {
+                    line.push(buf[0]);
+                    if buf[0] == b'\n' {
+                        break;
+                    }
+                },
+                Err(e) => {
+                    self.fail(format!("cannot read input: {}", e));
+                    return None;
+                },
+            }
+        }
+        if line.is_empty() {
+            return None;
+        }
+        match String::from_utf8(line) {
+            Ok(line) => Some(line),
+            Err(_) => {
+                self.fail("cannot read input: invalid UTF-8".to_string());
+                None
+            },
+        }
+    }
+
+    fn getchar(&mut self, input: &mut Read) -> i32 {
+        match self.read_line(input) {
+            Some(line) => line.chars().next().map_or(-1, |c| c as i32),
+            None => -1,
+        }
+    }
+
+    fn getint(&mut self, input: &mut Read) -> i32 {
+        let line = match self.read_line(input) {
+            Some(line) => line,
+            None => return -1,
+        };
+        match line.trim().parse() {
+            Ok(i) => i,
+            Err(_) => {
+                self.fail(format!("bad input: {:?} is not an integer", line.trim()));
+                0
+            },
+        }
+    }
+
+    fn write(&mut self, output: &mut Write, s: &str) {
+        if let Err(e) = output.write_all(s.as_bytes()) {
+            self.fail(format!("cannot write output: {}", e));
+        }
+    }
+
+    fn putchar(&mut self, output: &mut Write, c: i32) {
+        match ::std::char::from_u32(c as u32) {
+            Some(c) => self.write(output, &c.to_string()),
+            None => self.fail(format!("{} is not a character", c)),
+        }
+    }
+
+    // reports the error of the cell at (x, y), if there was one
+    fn failed(&mut self, output: &mut Write, x: usize, y: usize, c: char) -> bool {
+        match self.error.take() {
+            Some(why) => {
+                let _ = output.flush();
+                let _ = writeln!(::std::io::stderr(), "error at ({}, {}) '{}': {}", x, y, c, why);
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+const AHEUI_FL: [[usize; 4]; 4] = [
+    [0, 1, 2, 3],
+    [1, 0, 2, 3],
+    [0, 1, 3, 2],
+    [1, 0, 3, 2],
+];
+"#;
+
+fn gen_cell(out: &mut String, code: &[Vec<Hangul>], offsets: &[usize], x: usize, y: usize) {
+    use Cho::*;
+    use Jung::*;
+    use Jong::*;
+
+    let h = &code[y][x];
+    let idx = |(x, y): (usize, usize)| offsets[y] + x;
+    writeln!(out, "            {} => {{ // {} ({}, {})", idx((x, y)), h.c, x, y).unwrap();
+
+    let check = format!("                if rt.failed(output, {}, {}, {:?}) {{ return AHEUI_ERROR_EXIT; }}\n",
+                        x, y, h.c);
+    let binop = |out: &mut String, expr: &str| {
+        out.push_str("                let a = rt.pop(cur);\n");
+        out.push_str("                let b = rt.pop(cur);\n");
+        writeln!(out, "                let a = {};", expr).unwrap();
+        out.push_str("                rt.push(cur, a);\n");
+    };
+
+    match h.cho {
+        cㄴ => binop(out, "(b as u32 / rt.divisor(a) as u32) as i32"),
+        cㄷ => binop(out, "a.wrapping_add(b)"),
+        cㄸ => binop(out, "a.wrapping_mul(b)"),
+        cㄹ => binop(out, "(b as u32 % rt.divisor(a) as u32) as i32"),
+        cㅁ => {
+            out.push_str("                let a = rt.pop(cur);\n");
+            out.push_str(&check);
+            match h.jong {
+                jㅇ => out.push_str("                rt.write(output, &a.to_string());\n"),
+                jㅎ => out.push_str("                rt.putchar(output, a);\n"),
+                _ => {},
+            }
+        },
+        cㅂ => {
+            match h.jong {
+                jㅇ => out.push_str("                let a = rt.getint(input);\n"),
+                jㅎ => out.push_str("                let a = rt.getchar(input);\n"),
+                _ => writeln!(out, "                let a = {};", h.jong.val()).unwrap(),
+            }
+            out.push_str("                rt.push(cur, a);\n");
+        },
+        cㅃ => out.push_str("                rt.dup(cur);\n"),
+        cㅅ => writeln!(out, "                cur = {};", h.jong as usize).unwrap(),
+        cㅆ => {
+            out.push_str("                let a = rt.pop(cur);\n");
+            writeln!(out, "                rt.push({}, a);", h.jong as usize).unwrap();
+        },
+        cㅈ => binop(out, "((a as u32) <= (b as u32)) as i32"),
+        cㅊ => out.push_str("                let comp = rt.pop(cur) == 0;\n"),
+        cㅌ => binop(out, "b.wrapping_sub(a)"),
+        cㅍ => out.push_str("                rt.swap(cur);\n"),
+        cㅎ => {
+            out.push_str("                let ret = rt.halt(cur);\n");
+            out.push_str(&check);
+            out.push_str("                let _ = output.flush();\n");
+            out.push_str("                return ret;\n");
+            out.push_str("            },\n");
+            return;
+        },
+        cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+    }
+    match h.cho {
+        cㅅ | cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+        _ => out.push_str(&check),
+    }
+
+    match h.jung {
+        ㅏ | ㅓ | ㅗ | ㅜ | ㅑ | ㅕ | ㅛ | ㅠ => {
+            let flow = Flow::from_jung(h.jung).unwrap();
+            let next = idx(move_pos(code, x, y, h.jung, flow));
+            writeln!(out, "                flow = {};", flow as usize).unwrap();
+            if h.cho == cㅊ {
+                let rev = idx(move_pos(code, x, y, h.jung, flow.reverse()));
+                writeln!(out, "                if comp {{ {} }} else {{ {} }}", rev, next).unwrap();
+            } else {
+                writeln!(out, "                {}", next).unwrap();
+            }
+        },
+        _ => {
+            let j = match h.jung {
+                ㅣ => 1,
+                ㅡ => 2,
+                ㅢ => 3,
+                _ => 0,
+            };
+            if j != 0 {
+                writeln!(out, "                flow = AHEUI_FL[{}][flow];", j).unwrap();
+            }
+
+            let flows = [Flow::Left, Flow::Right, Flow::Up, Flow::Down];
+            if h.cho == cㅊ {
+                // a zero reverses the move but not the flow
+                out.push_str("                match if comp { flow ^ 1 } else { flow } {\n");
+            } else {
+                out.push_str("                match flow {\n");
+            }
+            for (i, f) in flows.iter().enumerate() {
+                let next = idx(next_pos(code, x, y, *f));
+                if i < 3 {
+                    writeln!(out, "                    {} => {},", i, next).unwrap();
+                } else {
+                    writeln!(out, "                    _ => {},", next).unwrap();
+                }
+            }
+            out.push_str("                }\n");
+        },
+    }
+    out.push_str("            },\n");
+}
+
+// the storage runtime and `run`, without any crate-level attributes
+pub fn gen_rust_items(code: &[Vec<Hangul>]) -> String {
+    let mut out = String::new();
+    out.push_str(PRELUDE);
+    writeln!(out, "\nconst AHEUI_ERROR_EXIT: i32 = {};", ERROR_EXIT).unwrap();
+
+    let mut offsets = Vec::new();
+    let mut n = 0;
+    for line in code.iter() {
+        offsets.push(n);
+        n += line.len();
+    }
+
+    out.push_str("\npub fn run(input: &mut Read, output: &mut Write) -> i32 {\n");
+    if n == 0 {
+        // no cell to start from; ends like ㅎ on an empty storage
+        out.push_str("    0\n");
+        out.push_str("}\n");
+        return out;
+    }
+    out.push_str("    let mut rt = AheuiRt::new();\n");
+    out.push_str("    let mut flow: usize = 3;\n");
+    out.push_str("    let mut cur: usize = 0;\n");
+    out.push_str("    let mut pc: usize = 0;\n");
+    out.push_str("    loop {\n");
+    out.push_str("        pc = match pc {\n");
+    for (y, line) in code.iter().enumerate() {
+        for x in 0..line.len() {
+            gen_cell(&mut out, code, &offsets, x, y);
+        }
+    }
+    out.push_str("            _ => unreachable!(),\n");
+    out.push_str("        };\n");
+    out.push_str("    }\n");
+    out.push_str("}\n");
+    out
+}
+
+pub fn gen_rust(code: &[Vec<Hangul>], in_fn: &str, fn_name: &str) -> String {
+    let mut out = String::new();
+    writeln!(out, "// generated by aheui-llvm from {}", in_fn.replace("\n", " ")).unwrap();
+    writeln!(out, "#![allow({})]\n", ALLOW).unwrap();
+    out.push_str(&gen_rust_items(code));
+
+    // convenience entry point bound to the process's stdin/stdout
+    writeln!(out, "\npub fn {}() -> i32 {{", fn_name).unwrap();
+    out.push_str("    let stdin = ::std::io::stdin();\n");
+    out.push_str("    let stdout = ::std::io::stdout();\n");
+    out.push_str("    run(&mut stdin.lock(), &mut stdout.lock())\n");
+    out.push_str("}\n");
+    out
+}
+
+#[cfg(test)]
+pub fn compile_and_run(name: &str, code: &str, input: &str) -> (String, i32) {
+    use std::env;
+    use std::fs::File;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use super::parse_code;
+
+    let dir = env::temp_dir().join(format!("aheui_rust_test_{}", name));
+    let _ = ::std::fs::create_dir(&dir);
+    let src = gen_rust(&parse_code(code), name, "aheui_main");
+    File::create(&dir.join("prog.rs")).unwrap().write_all(src.as_bytes()).unwrap();
+    File::create(&dir.join("main.rs")).unwrap().write_all(b"
+        mod prog;
+        fn main() {
+            let mut out = Vec::new();
+            let ret = prog::run(&mut std::io::stdin(), &mut out);
+            std::io::Write::write_all(&mut std::io::stdout(), &out).unwrap();
+            std::process::exit(ret);
+        }
+    ").unwrap();
+
+    let exe_fn = dir.join("prog");
+    let status = Command::new("rustc").arg("-O").arg("-o").arg(&exe_fn)
+                                      .arg(&dir.join("main.rs"))
+                                      .status().unwrap();
+    assert!(status.success(), "rustc failed for {}", name);
+
+    let mut child = Command::new(&exe_fn).stdin(Stdio::piped()).stdout(Stdio::piped())
+                                         .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    let output = child.wait_with_output().unwrap();
+    (String::from_utf8(output.stdout).unwrap(), output.status.code().unwrap())
+}
+
+#[test]
+fn test_rust_conformance() {
+    use super::conformance_cases;
+
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let (out, _) = compile_and_run(&name, &code, "");
+        assert!(out == expected, "unexpected output from {}", name);
+    }
+}
+
+#[test]
+fn test_rust_io() {
+    let cases: Vec<(&str, &str, &str, &str, i32)> = vec!(
+        ("int", "방망희", "  -42\n", "-42", 0),
+        ("char", "밯맣희", "한", "한", 0),
+        ("exit", "밯희", "A", "", 65),
+        ("eof", "밯망방망희", "", "-1-1", 0),
+        ("line", "밯맣밯맣희", "가나\n다\n", "가다", 0),
+        ("bad_int", "방망희", "x\n", "", 125),
+        ("underflow", "망희", "", "", 125),
+        ("div_zero", "반바나망희", "", "", 125),
+        ("empty", "", "", "", 0),
+        // ㅢ sends the flow back up to read again until a zero moves it on
+        ("choose", "붕\n츼\n붕\n뭉\n희", "5\n7\n0\n9\n", "9", 0),
+    );
+    for &(name, code, input, expected, status) in cases.iter() {
+        let (out, ret) = compile_and_run(&format!("io_{}", name), code, input);
+        assert!(out == expected, "unexpected output from {}: {:?}", name, out);
+        assert!(ret == status, "unexpected status from {}: {}", name, ret);
+    }
+}
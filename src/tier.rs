@@ -0,0 +1,344 @@
+// Tiered execution. Programs start in the bytecode VM, which counts how
+// often each state is entered. When a state gets hot, the loop through it is
+// compiled with LLVM and run on the ORC JIT from then on. Both tiers keep
+// their storages in the same `rt` context, so only the selected storage and the state index
+// have to cross over. I/O and ㅎ are left to the VM, which keeps the output
+// identical whatever `Io` the VM is given. A runtime error leaves the region
+// at the failing state for the VM to report.
+
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::mem;
+
+use libc::{c_uint, c_ulonglong};
+
+use rustc::lib::llvm::{BasicBlockRef, BuilderRef, ModuleRef, ValueRef};
+use rustc::lib::llvm::{IntULE, IntEQ, IntNE};
+use rustc::lib::llvm;
+
+use bytecode::{Instr, Op};
+use jit::Jit;
+use vm::{self, Io, Step};
+use rt::AheuiRt;
+use super::{declare_fn, fn_ty};
+
+pub const HOT: u32 = 1000;
+
+// takes the runtime context and the selected storage and returns the state
+// to continue from
+type RegionFn = extern "C" fn(*mut AheuiRt, *mut i8) -> i32;
+
+fn compilable(ins: &Instr) -> bool {
+    match ins.op {
+        Op::GetInt | Op::GetChar | Op::PutInt | Op::PutChar | Op::Halt => false,
+        _ => true,
+    }
+}
+
+fn successors(ins: &Instr) -> Vec<usize> {
+    match ins.op {
+        Op::Halt => vec!(),
+        Op::Branch => vec!(ins.next as usize, ins.alt as usize),
+        _ => vec!(ins.next as usize),
+    }
+}
+
+// The loop through `head`: the compilable states reachable from it that lead
+// back to it. Empty when `head` is not on such a loop.
+pub fn region(prog: &[Instr], head: usize) -> Vec<bool> {
+    let n = prog.len();
+    let mut preds = vec!(Vec::new(); n);
+    for (i, ins) in prog.iter().enumerate() {
+        if compilable(ins) {
+            for s in successors(ins).into_iter() {
+                preds[s].push(i);
+            }
+        }
+    }
+
+    let mut fwd = vec!(false; n);
+    let mut todo = vec!(head);
+    while let Some(i) = todo.pop() {
+        if fwd[i] || !compilable(&prog[i]) {
+            continue;
+        }
+        fwd[i] = true;
+        todo.extend(successors(&prog[i]).into_iter());
+    }
+
+    let mut bwd = vec!(false; n);
+    let mut todo = preds[head].clone();
+    while let Some(i) = todo.pop() {
+        if bwd[i] || !fwd[i] {
+            continue;
+        }
+        bwd[i] = true;
+        todo.extend(preds[i].iter().cloned());
+    }
+
+    if !bwd[head] {
+        return vec!(false; n);
+    }
+    bwd
+}
+
+struct RegionGen {
+    bld: BuilderRef,
+    i8_ty: llvm::TypeRef,
+    i32_ty: llvm::TypeRef,
+    pu: ValueRef,
+    po: ValueRef,
+    du: ValueRef,
+    sw: ValueRef,
+    dv: ValueRef,
+    fa: ValueRef,
+    ctx: ValueRef,
+}
+
+impl RegionGen {
+    // with the context first, as every runtime function takes it
+    fn call(&self, f: ValueRef, args: &[ValueRef]) -> ValueRef {
+        let name = CString::new("").unwrap();
+        let mut all = vec!(self.ctx);
+        all.extend(args.iter().cloned());
+        unsafe {
+            llvm::LLVMBuildCall(self.bld, f, all.as_ptr(), all.len() as c_uint, name.as_ptr())
+        }
+    }
+
+    fn i8(&self, v: u8) -> ValueRef {
+        unsafe { llvm::LLVMConstInt(self.i8_ty, v as c_ulonglong, 0) }
+    }
+
+    fn i32(&self, v: u32) -> ValueRef {
+        unsafe { llvm::LLVMConstInt(self.i32_ty, v as c_ulonglong, 0) }
+    }
+
+    // mirrors `vm::step`; the successor is left to the caller
+    fn gen_instr(&self, ins: &Instr, cur_ptr: ValueRef) -> Option<ValueRef> {
+        let tmp = CString::new("").unwrap();
+        let cur = unsafe { llvm::LLVMBuildLoad(self.bld, cur_ptr, tmp.as_ptr()) };
+        match ins.op {
+            Op::Div | Op::Add | Op::Mul | Op::Rem | Op::Cmp | Op::Sub => {
+                let a = self.call(self.po, &[cur]);
+                let b = self.call(self.po, &[cur]);
+                let a = match ins.op {
+                    Op::Div | Op::Rem => self.call(self.dv, &[a]),
+                    _ => a,
+                };
+                let v = unsafe {
+                    match ins.op {
+                        Op::Div => llvm::LLVMBuildUDiv(self.bld, b, a, tmp.as_ptr()),
+                        Op::Add => llvm::LLVMBuildAdd(self.bld, a, b, tmp.as_ptr()),
+                        Op::Mul => llvm::LLVMBuildMul(self.bld, a, b, tmp.as_ptr()),
+                        Op::Rem => llvm::LLVMBuildURem(self.bld, b, a, tmp.as_ptr()),
+                        Op::Cmp => {
+                            let cmp = llvm::LLVMBuildICmp(self.bld, IntULE as c_uint, a, b,
+                                                          tmp.as_ptr());
+                            llvm::LLVMBuildZExt(self.bld, cmp, self.i32_ty, tmp.as_ptr())
+                        },
+                        _ => llvm::LLVMBuildSub(self.bld, b, a, tmp.as_ptr()),
+                    }
+                };
+                self.call(self.pu, &[cur, v]);
+            },
+            Op::Pop => { self.call(self.po, &[cur]); },
+            Op::Push => { self.call(self.pu, &[cur, self.i32(ins.arg as u32)]); },
+            Op::Dup => { self.call(self.du, &[cur]); },
+            Op::Swap => { self.call(self.sw, &[cur]); },
+            Op::Select => unsafe { llvm::LLVMBuildStore(self.bld, self.i8(ins.arg), cur_ptr); },
+            Op::Move => {
+                let v = self.call(self.po, &[cur]);
+                self.call(self.pu, &[self.i8(ins.arg), v]);
+            },
+            Op::Branch => {
+                let v = self.call(self.po, &[cur]);
+                let zero = self.i32(0);
+                return Some(unsafe {
+                    llvm::LLVMBuildICmp(self.bld, IntEQ as c_uint, v, zero, tmp.as_ptr())
+                });
+            },
+            Op::Nop => {},
+            Op::GetInt | Op::GetChar | Op::PutInt | Op::PutChar | Op::Halt => {
+                unreachable!()
+            },
+        }
+        None
+    }
+}
+
+fn append_bb(cx: llvm::ContextRef, f: ValueRef, name: &str) -> BasicBlockRef {
+    let name = CString::new(name).unwrap();
+    unsafe { llvm::LLVMAppendBasicBlockInContext(cx, f, name.as_ptr()) }
+}
+
+// One function per region, entered at `head`. Every edge that leaves the
+// region returns the state it leads to.
+pub fn gen_region(prog: &[Instr], inside: &[bool], head: usize, fn_name: &str) -> ModuleRef {
+    let md_name = CString::new(fn_name).unwrap();
+    let c_fn_name = CString::new(fn_name).unwrap();
+    unsafe {
+        let cx = llvm::LLVMContextCreate();
+        let md = llvm::LLVMModuleCreateWithNameInContext(md_name.as_ptr(), cx);
+        let bld = llvm::LLVMCreateBuilderInContext(cx);
+
+        let i8_ty = llvm::LLVMInt8TypeInContext(cx);
+        let i32_ty = llvm::LLVMInt32TypeInContext(cx);
+        let void_ty = llvm::LLVMVoidTypeInContext(cx);
+        let i8p_ty = llvm::LLVMPointerType(i8_ty, 0);
+
+        // the context is opaque here too
+        let f = llvm::LLVMAddFunction(md, c_fn_name.as_ptr(),
+                                      fn_ty(i32_ty, &[i8p_ty, i8p_ty]));
+        let g = RegionGen {
+            bld: bld,
+            i8_ty: i8_ty,
+            i32_ty: i32_ty,
+            pu: declare_fn(md, "aheui_push", fn_ty(void_ty, &[i8p_ty, i8_ty, i32_ty])),
+            po: declare_fn(md, "aheui_pop", fn_ty(i32_ty, &[i8p_ty, i8_ty])),
+            du: declare_fn(md, "aheui_dup", fn_ty(void_ty, &[i8p_ty, i8_ty])),
+            sw: declare_fn(md, "aheui_swap", fn_ty(void_ty, &[i8p_ty, i8_ty])),
+            dv: declare_fn(md, "aheui_divisor", fn_ty(i32_ty, &[i8p_ty, i32_ty])),
+            fa: declare_fn(md, "aheui_failed", fn_ty(i32_ty, &[i8p_ty])),
+            ctx: llvm::LLVMGetParam(f, 0),
+        };
+        let cur_ptr = llvm::LLVMGetParam(f, 1);
+
+        let entry = append_bb(cx, f, "entry");
+        let mut bbs = HashMap::new();
+        for (i, _) in inside.iter().enumerate().filter(|&(_, &b)| b) {
+            bbs.insert(i, append_bb(cx, f, &format!("aheui_state_{}", i)));
+        }
+        for (i, _) in inside.iter().enumerate().filter(|&(_, &b)| b) {
+            for s in successors(&prog[i]).into_iter() {
+                if !bbs.contains_key(&s) {
+                    let bb = append_bb(cx, f, &format!("aheui_exit_{}", s));
+                    llvm::LLVMPositionBuilderAtEnd(bld, bb);
+                    llvm::LLVMBuildRet(bld, g.i32(s as u32));
+                    bbs.insert(s, bb);
+                }
+            }
+        }
+
+        llvm::LLVMPositionBuilderAtEnd(bld, entry);
+        llvm::LLVMBuildBr(bld, bbs[&head]);
+
+        for (i, _) in inside.iter().enumerate().filter(|&(_, &b)| b) {
+            let ins = &prog[i];
+            llvm::LLVMPositionBuilderAtEnd(bld, bbs[&i]);
+            let zero = g.gen_instr(ins, cur_ptr);
+            if ins.op != Op::Select && ins.op != Op::Nop {
+                let fail = append_bb(cx, f, &format!("aheui_failed_{}", i));
+                let cont = append_bb(cx, f, &format!("aheui_ok_{}", i));
+                let tmp = CString::new("").unwrap();
+                let failed = g.call(g.fa, &[]);
+                let failed = llvm::LLVMBuildICmp(bld, IntNE as c_uint, failed, g.i32(0),
+                                                 tmp.as_ptr());
+                llvm::LLVMBuildCondBr(bld, failed, fail, cont);
+                llvm::LLVMPositionBuilderAtEnd(bld, fail);
+                llvm::LLVMBuildRet(bld, g.i32(i as u32));
+                llvm::LLVMPositionBuilderAtEnd(bld, cont);
+            }
+            match zero {
+                Some(zero) => {
+                    let alt = bbs[&(ins.alt as usize)];
+                    let next = bbs[&(ins.next as usize)];
+                    llvm::LLVMBuildCondBr(bld, zero, alt, next);
+                },
+                None => {
+                    llvm::LLVMBuildBr(bld, bbs[&(ins.next as usize)]);
+                },
+            }
+        }
+        md
+    }
+}
+
+pub fn run<I: Io>(ctx: *mut AheuiRt, prog: &[Instr], io: &mut I) -> i32 {
+    run_with_threshold(ctx, prog, io, HOT)
+}
+
+pub fn run_with_threshold<I: Io>(ctx: *mut AheuiRt, prog: &[Instr], io: &mut I,
+                                 hot: u32) -> i32 {
+    let mut jit = None;
+    let mut compiled: Vec<Option<RegionFn>> = vec!(None; prog.len());
+    let mut counts = vec!(0u32; prog.len());
+
+    if let Some(ret) = vm::failed(ctx, &prog[0]) {
+        return ret;
+    }
+    let mut pc = 0;
+    let mut cur: i8 = 0;
+    loop {
+        if let Some(f) = compiled[pc] {
+            pc = f(ctx, &mut cur) as usize;
+            if let Some(ret) = vm::failed(ctx, &prog[pc]) {
+                return ret;
+            }
+            continue;
+        }
+
+        counts[pc] += 1;
+        if counts[pc] == hot {
+            let inside = region(prog, pc);
+            if inside[pc] {
+                let name = format!("aheui_region_{}", pc);
+                let md = gen_region(prog, &inside, pc, &name);
+                if jit.is_none() {
+                    jit = Some(Jit::new());
+                }
+                let jit = jit.as_mut().unwrap();
+                jit.add(md);
+                compiled[pc] = Some(unsafe { mem::transmute(jit.symbol(&name)) });
+                continue;
+            }
+        }
+
+        let next = vm::step(ctx, &prog[pc], &mut cur, io);
+        if let Some(ret) = vm::failed(ctx, &prog[pc]) {
+            return ret;
+        }
+        match next {
+            Step::Next(next) => pc = next,
+            Step::Halt(ret) => return ret,
+        }
+    }
+}
+
+#[test]
+fn test_region() {
+    use bytecode;
+    use super::parse_code;
+
+    // (0, 0) moving down leads into the loop but is not part of it
+    let prog = bytecode::compile(&parse_code("아아아")).unwrap();
+    assert!(prog.len() == 4);
+    assert!(region(&prog, 0) == vec!(false, false, false, false));
+    assert!(region(&prog, 1) == vec!(false, true, true, true));
+
+    // a loop through an output state stays in the VM
+    let prog = bytecode::compile(&parse_code("빠망")).unwrap();
+    assert!(region(&prog, 2).iter().all(|&b| !b));
+}
+
+#[test]
+fn test_tier_conformance() {
+    use bytecode;
+    use rt;
+    use vm::BufIo;
+    use super::{conformance_cases, parse_code};
+
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let prog = bytecode::compile(&parse_code(&code)).unwrap();
+        for &hot in [1, 2, HOT].iter() {
+            let (ctx, vm_ctx) = (rt::aheui_ctx_new(), rt::aheui_ctx_new());
+            let mut io = BufIo { input: Vec::new(), output: String::new() };
+            let ret = run_with_threshold(ctx, &prog, &mut io, hot);
+            let mut vm_io = BufIo { input: Vec::new(), output: String::new() };
+            assert!(ret == vm::run(vm_ctx, &prog, &mut vm_io));
+            rt::aheui_ctx_free(ctx);
+            rt::aheui_ctx_free(vm_ctx);
+            assert!(io.output == expected, "unexpected output from {} at {}", name, hot);
+        }
+    }
+}
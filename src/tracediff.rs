@@ -0,0 +1,180 @@
+// `aheui trace-diff A B`: finds the first record where two --trace outputs
+// disagree. Every field but the step number is compared, so a trace from
+// `jit --trace` can be held against one from `record --trace` to find the
+// first cell the generated code gets wrong.
+
+use std::char;
+
+// records shown before and after the divergence
+const CONTEXT: usize = 3;
+
+// the fields of one record in order, with strings unescaped
+pub type Record = Vec<(String, String)>;
+
+fn parse_string<I: Iterator<Item = char>>(chars: &mut I) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => match chars.next() {
+                Some('u') => {
+                    let hex: String = chars.by_ref().take(4).collect();
+                    match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                        Some(c) => s.push(c),
+                        None => return Err(format!("bad escape \\u{}", hex)),
+                    }
+                },
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".to_string()),
+            },
+            Some(c) => s.push(c),
+            None => return Err("unterminated string".to_string()),
+        }
+    }
+}
+
+// enough JSON for the flat objects rt::trace_record writes
+pub fn parse_record(line: &str) -> Result<Record, String> {
+    let mut chars = line.trim().chars().peekable();
+    if chars.next() != Some('{') {
+        return Err("expected an object".to_string());
+    }
+    let mut record = Vec::new();
+    loop {
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        match chars.next() {
+            Some('"') => {},
+            Some('}') if record.is_empty() => return Ok(record),
+            _ => return Err("expected a field name".to_string()),
+        }
+        let key = try!(parse_string(&mut chars));
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        if chars.next() != Some(':') {
+            return Err(format!("expected `:` after \"{}\"", key));
+        }
+        while chars.peek().map_or(false, |c| c.is_whitespace()) {
+            chars.next();
+        }
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            try!(parse_string(&mut chars))
+        } else {
+            let mut v = String::new();
+            while chars.peek().map_or(false, |&c| c != ',' && c != '}') {
+                v.push(chars.next().unwrap());
+            }
+            v.trim().to_string()
+        };
+        record.push((key, value));
+        match chars.next() {
+            Some(',') => {},
+            Some('}') => return Ok(record),
+            _ => return Err("expected `,` or `}`".to_string()),
+        }
+    }
+}
+
+pub fn parse(text: &str) -> Result<Vec<Record>, String> {
+    let mut records = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match parse_record(line) {
+            Ok(r) => records.push(r),
+            Err(e) => return Err(format!("line {}: {}", i + 1, e)),
+        }
+    }
+    Ok(records)
+}
+
+fn field<'a>(r: &'a Record, key: &str) -> &'a str {
+    r.iter().find(|&&(ref k, _)| k == key).map_or("?", |&(_, ref v)| &v[..])
+}
+
+fn describe(r: &Record) -> String {
+    format!("({}, {}) {} moving {}, storage {} top {} size {}",
+            field(r, "x"), field(r, "y"), field(r, "char"), field(r, "direction"),
+            field(r, "storage"), field(r, "top"), field(r, "size"))
+}
+
+// the fields that differ, ignoring the step number
+fn differences(a: &Record, b: &Record) -> Vec<String> {
+    let mut keys: Vec<&str> = Vec::new();
+    for &(ref k, _) in a.iter().chain(b.iter()) {
+        if k != "step" && !keys.contains(&&k[..]) {
+            keys.push(k);
+        }
+    }
+    keys.into_iter().filter(|k| field(a, k) != field(b, k)).map(|k| k.to_string()).collect()
+}
+
+// None when the traces agree
+pub fn report(a: &[Record], b: &[Record]) -> Option<String> {
+    let same = a.iter().zip(b.iter()).take_while(|&(x, y)| differences(x, y).is_empty()).count();
+    if same == a.len() && same == b.len() {
+        return None;
+    }
+
+    let mut out = format!("traces diverge at record {}\n", same + 1);
+    match (a.get(same), b.get(same)) {
+        (Some(x), Some(y)) => {
+            out.push_str(&format!("  differs in {}\n", differences(x, y).join(", ")));
+        },
+        (None, _) => out.push_str(&format!("  A ends after {} records\n", same)),
+        (_, None) => out.push_str(&format!("  B ends after {} records\n", same)),
+    }
+    let start = same.saturating_sub(CONTEXT);
+    for i in start..same {
+        out.push_str(&format!("  {:>6}   {}\n", i + 1, describe(&a[i])));
+    }
+    for i in same..same + CONTEXT {
+        for &(name, records) in [("A", a), ("B", b)].iter() {
+            if let Some(r) = records.get(i) {
+                out.push_str(&format!("{} {:>6}   {}\n", name, i + 1, describe(r)));
+            }
+        }
+    }
+    Some(out)
+}
+
+#[test]
+fn test_tracediff() {
+    let r = parse_record(r#"{"step":7,"x":2,"y":1,"char":"\"","direction":"right","top":null}"#).unwrap();
+    assert!(field(&r, "char") == "\"" && field(&r, "top") == "null" && field(&r, "x") == "2");
+    assert!(field(&parse_record(r#"{"char":"\u000a"}"#).unwrap(), "char") == "\n");
+    assert!(parse_record("{\"x\":1").is_err() && parse_record("[1]").is_err());
+
+    let a = parse("\
+{\"step\":1,\"x\":0,\"y\":0,\"char\":\"밤\",\"direction\":\"down\",\"storage\":0,\"top\":null,\"size\":0,\"digest\":\"a\"}
+{\"step\":2,\"x\":1,\"y\":0,\"char\":\"밣\",\"direction\":\"right\",\"storage\":0,\"top\":4,\"size\":1,\"digest\":\"b\"}
+{\"step\":3,\"x\":2,\"y\":0,\"char\":\"따\",\"direction\":\"right\",\"storage\":0,\"top\":8,\"size\":2,\"digest\":\"c\"}
+").unwrap();
+    let mut b = a.clone();
+    assert!(report(&a, &b).is_none());
+    // the step number alone does not count
+    b[0][0].1 = "10".to_string();
+    assert!(report(&a, &b).is_none());
+
+    b[2][6].1 = "9".to_string();
+    assert!(report(&a, &b).unwrap() == "\
+traces diverge at record 3
+  differs in top
+       1   (0, 0) 밤 moving down, storage 0 top null size 0
+       2   (1, 0) 밣 moving right, storage 0 top 4 size 1
+A      3   (2, 0) 따 moving right, storage 0 top 8 size 2
+B      3   (2, 0) 따 moving right, storage 0 top 9 size 2
+");
+    assert!(report(&a, &a[..2]).unwrap().contains("  B ends after 2 records\n"));
+
+    // a value deeper in a storage, or in another one, shows in the digest
+    let mut b = a.clone();
+    b[1][8].1 = "d".to_string();
+    assert!(report(&a, &b).unwrap().starts_with("traces diverge at record 2\n  differs in digest\n"));
+}
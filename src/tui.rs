@@ -0,0 +1,300 @@
+// `aheui tui`: steps interp.rs over the grid and redraws the terminal after
+// every command. Commands are lines typed on stdin; the program reads its
+// own input from the --input file instead.
+
+use std::char;
+use std::io::{self, BufRead, Write};
+use std::iter;
+use std::thread;
+use std::time::Duration;
+
+use interp::{Machine, STORAGES, QUEUE};
+use rt;
+use vm::Io;
+use super::{Hangul, Flow};
+
+// how much of the grid is shown around the cursor, in cells
+const VIEW_ROWS: usize = 20;
+const VIEW_COLS: usize = 30;
+const OUTPUT_LINES: usize = 5;
+const STORAGE_VALUES: usize = 8;
+
+// delay per step while running, slowest first
+const SPEEDS: [u64; 5] = [500, 200, 50, 10, 0];
+
+// East Asian wide ranges that show up in Aheui sources
+const WIDE: [(u32, u32); 7] = [
+    (0x1100, 0x115F), (0x2E80, 0xA4CF), (0xAC00, 0xD7A3), (0xF900, 0xFAFF),
+    (0xFE30, 0xFE4F), (0xFF00, 0xFF60), (0xFFE0, 0xFFE6),
+];
+
+const HELP: &'static str =
+    "[enter] step  r run  b X Y breakpoint  + faster  - slower  q quit";
+
+pub struct TuiIo {
+    pub input: Vec<char>,
+    pub output: String,
+}
+
+// end of input reads as -1
+impl Io for TuiIo {
+    fn getchar(&mut self) -> i32 {
+        if self.input.is_empty() {
+            return -1;
+        }
+        self.input.remove(0) as i32
+    }
+
+    fn putchar(&mut self, c: i32) {
+        self.output.push(char::from_u32(c as u32).unwrap_or('\u{fffd}'));
+    }
+
+    fn getint(&mut self) -> i32 {
+        while !self.input.is_empty() && self.input[0].is_whitespace() {
+            self.input.remove(0);
+        }
+        let mut s = String::new();
+        while !self.input.is_empty() && (self.input[0] == '-' || self.input[0].is_digit(10)) {
+            s.push(self.input.remove(0));
+        }
+        s.parse().unwrap_or(-1)
+    }
+
+    fn putint(&mut self, i: i32) {
+        self.output.push_str(&i.to_string());
+    }
+}
+
+fn char_width(c: char) -> usize {
+    let c = c as u32;
+    if WIDE.iter().any(|&(lo, hi)| lo <= c && c <= hi) { 2 } else { 1 }
+}
+
+// every cell takes two columns so that the rows line up
+fn cell_text(c: char) -> String {
+    if c.is_control() {
+        "  ".to_string()
+    } else if char_width(c) == 2 {
+        c.to_string()
+    } else {
+        format!("{} ", c)
+    }
+}
+
+fn arrow(flow: Flow) -> char {
+    match flow {
+        Flow::Left => '←',
+        Flow::Right => '→',
+        Flow::Up => '↑',
+        Flow::Down => '↓',
+    }
+}
+
+pub struct Ui {
+    pub breaks: Vec<(usize, usize)>,
+    pub speed: usize,
+    pub message: String,
+}
+
+fn grid_line(m: &Machine, ui: &Ui, y: usize, left: usize) -> String {
+    let mut out = String::new();
+    let row: &[Hangul] = if y < m.code.len() { &m.code[y] } else { &[] };
+    let mut cells = 0;
+    for x in left..row.len() {
+        if cells == VIEW_COLS {
+            break;
+        }
+        let text = cell_text(row[x].c);
+        if (x, y) == (m.x, m.y) {
+            out.push_str(&format!("\x1b[7m{}\x1b[0m", text));
+        } else if ui.breaks.contains(&(x, y)) {
+            out.push_str(&format!("\x1b[41m{}\x1b[0m", text));
+        } else {
+            out.push_str(&text);
+        }
+        cells += 1;
+    }
+    for _ in cells..VIEW_COLS {
+        out.push_str("  ");
+    }
+    out
+}
+
+fn storage_line(m: &Machine, i: usize) -> String {
+    let mark = if i == m.cur { '*' } else { ' ' };
+    let kind = if i == QUEUE { 'q' } else { ' ' };
+    let s = &m.storages[i];
+    let shown = if s.len() > STORAGE_VALUES { &s[s.len() - STORAGE_VALUES..] } else { &s[..] };
+    let values: Vec<String> = shown.iter().map(|v| v.to_string()).collect();
+    let more = if s.len() > STORAGE_VALUES { "… " } else { "" };
+    format!("{}{:>2}{} {}{}", mark, i, kind, more, values.join(" "))
+}
+
+fn repeat(s: &str, n: usize) -> String {
+    iter::repeat(s).take(n).collect()
+}
+
+pub fn render(m: &Machine, io: &TuiIo, ui: &Ui) -> String {
+    let mut out = "\x1b[H\x1b[2J".to_string();
+    let state = match m.halted {
+        Some(ret) => format!("halted with {}", ret),
+        None => format!("storage {}", m.cur),
+    };
+    out.push_str(&format!("step {}  ({}, {}) {} {}  {}  speed {}\n",
+                          m.steps, m.x, m.y, m.cell().c, arrow(m.flow), state, ui.speed + 1));
+
+    // the grid on the left, the storages beside it
+    let top = m.y.saturating_sub(VIEW_ROWS / 2);
+    let left = m.x.saturating_sub(VIEW_COLS / 2);
+    let lines = if VIEW_ROWS > STORAGES { VIEW_ROWS } else { STORAGES };
+    for i in 0..lines {
+        let grid = if i < VIEW_ROWS {
+            grid_line(m, ui, top + i, left)
+        } else {
+            repeat(" ", 2 * VIEW_COLS)
+        };
+        let side = if i < STORAGES { storage_line(m, i) } else { String::new() };
+        out.push_str(&format!("{} │ {}\n", grid, side));
+    }
+
+    // program output below
+    out.push_str(&repeat("─", 2 * VIEW_COLS));
+    out.push('\n');
+    let output: Vec<&str> = io.output.lines().collect();
+    let skip = if output.len() > OUTPUT_LINES { output.len() - OUTPUT_LINES } else { 0 };
+    for line in output[skip..].iter() {
+        out.push_str(&format!("{}\n", line));
+    }
+    out.push_str(&format!("({} input characters left)\n", io.input.len()));
+    if !ui.message.is_empty() {
+        out.push_str(&format!("{}\n", ui.message));
+    }
+    out.push_str(HELP);
+    out.push_str("\n> ");
+    out
+}
+
+#[derive(PartialEq, Eq, Debug)]
+enum Command {
+    Step,
+    Run,
+    Break(usize, usize),
+    Faster,
+    Slower,
+    Quit,
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match (words.first().map(|w| *w), words.len()) {
+        (None, _) | (Some("s"), 1) => Ok(Command::Step),
+        (Some("r"), 1) => Ok(Command::Run),
+        (Some("b"), 3) => match (words[1].parse::<usize>(), words[2].parse::<usize>()) {
+            (Ok(x), Ok(y)) => Ok(Command::Break(x, y)),
+            _ => Err("usage: b X Y".to_string()),
+        },
+        (Some("b"), _) => Err("usage: b X Y".to_string()),
+        (Some("+"), 1) => Ok(Command::Faster),
+        (Some("-"), 1) => Ok(Command::Slower),
+        (Some("q"), 1) => Ok(Command::Quit),
+        _ => Err(format!("unknown command `{}`", line.trim())),
+    }
+}
+
+// false once the program cannot go on
+fn step(m: &mut Machine, io: &mut TuiIo, ui: &mut Ui) -> bool {
+    if let Some(ret) = m.halted {
+        ui.message = format!("the program has halted with {}", ret);
+        return false;
+    }
+    match m.step(io) {
+        Ok(()) => m.halted.is_none(),
+        Err(e) => {
+            let at = rt::cell_at(m.x as i32, m.y as i32, m.cell().c);
+            ui.message = format!("error {}: {}", at, e);
+            false
+        },
+    }
+}
+
+pub fn run(code: &[Vec<Hangul>], input: &str) {
+    let mut m = Machine::new(code);
+    let mut pio = TuiIo { input: input.chars().collect(), output: String::new() };
+    let mut ui = Ui { breaks: Vec::new(), speed: 2, message: String::new() };
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+    loop {
+        print!("{}", render(&m, &pio, &ui));
+        io::stdout().flush().unwrap();
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => return,
+        };
+        ui.message.clear();
+        match parse_command(&line) {
+            Ok(Command::Step) => { step(&mut m, &mut pio, &mut ui); },
+            Ok(Command::Run) => {
+                while step(&mut m, &mut pio, &mut ui) && !ui.breaks.contains(&(m.x, m.y)) {
+                    let delay = SPEEDS[ui.speed];
+                    if delay > 0 {
+                        print!("{}", render(&m, &pio, &ui));
+                        io::stdout().flush().unwrap();
+                        thread::sleep(Duration::from_millis(delay));
+                    }
+                }
+            },
+            Ok(Command::Break(x, y)) => {
+                match ui.breaks.iter().position(|&b| b == (x, y)) {
+                    Some(i) => { ui.breaks.remove(i); },
+                    None => ui.breaks.push((x, y)),
+                }
+            },
+            Ok(Command::Faster) => {
+                if ui.speed + 1 < SPEEDS.len() {
+                    ui.speed += 1;
+                }
+            },
+            Ok(Command::Slower) => {
+                if ui.speed > 0 {
+                    ui.speed -= 1;
+                }
+            },
+            Ok(Command::Quit) => return,
+            Err(e) => ui.message = e,
+        }
+    }
+}
+
+#[test]
+fn test_tui() {
+    use super::parse_code;
+
+    assert!(parse_command("") == Ok(Command::Step));
+    assert!(parse_command("b 3 4") == Ok(Command::Break(3, 4)));
+    assert!(parse_command("b 3").is_err());
+    assert!(parse_command("x").is_err());
+
+    assert!(cell_text('밤') == "밤" && cell_text('a') == "a " && cell_text('\t') == "  ");
+
+    let code = parse_code("밤a망희");
+    let mut m = Machine::new(&code);
+    let mut io = TuiIo { input: Vec::new(), output: String::new() };
+    let mut ui = Ui { breaks: vec!((3, 0)), speed: 0, message: String::new() };
+    let screen = render(&m, &io, &ui);
+    assert!(screen.contains("(0, 0) 밤 ↓"));
+    assert!(screen.contains("\x1b[7m밤\x1b[0m? 망\x1b[41m희\x1b[0m"));
+
+    while step(&mut m, &mut io, &mut ui) {}
+    assert!(io.output == "4" && m.halted == Some(0));
+    let screen = render(&m, &io, &ui);
+    assert!(screen.contains("halted with 0"));
+    assert!(screen.contains("\n4\n(0 input characters left)\n"));
+    // every grid row is as wide as the others
+    let widths: Vec<usize> = screen.lines().skip(1).take(VIEW_ROWS).map(|l| {
+        let l = l.replace("\x1b[7m", "").replace("\x1b[41m", "").replace("\x1b[0m", "");
+        let grid = l.split(" │ ").next().unwrap();
+        grid.chars().map(char_width).fold(0, |a, w| a + w)
+    }).collect();
+    assert!(widths.iter().all(|&w| w == 2 * VIEW_COLS));
+}
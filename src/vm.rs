@@ -0,0 +1,203 @@
+// Dispatch loop over bytecode.rs instructions. Storages live in an `rt`
+// context, the same kind a compiled program linked with runtime.rs uses.
+
+use std::io::{Write, stderr};
+
+use bytecode::{Instr, Op};
+use rt::{self, AheuiRt};
+
+pub trait Io {
+    fn getchar(&mut self) -> i32;
+    fn putchar(&mut self, c: i32);
+    fn getint(&mut self) -> i32;
+    fn putint(&mut self, i: i32);
+}
+
+// the runtime's own stdin/stdout handling, through the given context
+pub struct RtIo(pub *mut AheuiRt);
+
+impl Io for RtIo {
+    fn getchar(&mut self) -> i32 {
+        rt::aheui_getchar(self.0)
+    }
+
+    fn putchar(&mut self, c: i32) {
+        rt::aheui_putchar(self.0, c)
+    }
+
+    fn getint(&mut self) -> i32 {
+        rt::aheui_getint(self.0)
+    }
+
+    fn putint(&mut self, i: i32) {
+        rt::aheui_putint(self.0, i)
+    }
+}
+
+pub enum Step {
+    Next(usize),
+    Halt(i32),
+}
+
+// executes one instruction with `cur` as the selected storage
+pub fn step<I: Io>(ctx: *mut AheuiRt, ins: &Instr, cur: &mut i8, io: &mut I) -> Step {
+    let c = *cur;
+    match ins.op {
+        Op::Nop => {},
+        Op::Div | Op::Add | Op::Mul | Op::Rem | Op::Cmp | Op::Sub => {
+            let a = rt::aheui_pop(ctx, c);
+            let b = rt::aheui_pop(ctx, c);
+            let a = match ins.op {
+                Op::Div | Op::Rem => rt::aheui_divisor(ctx, a),
+                _ => a,
+            };
+            let v = match ins.op {
+                Op::Div => (b as u32 / a as u32) as i32,
+                Op::Add => a.wrapping_add(b),
+                Op::Mul => a.wrapping_mul(b),
+                Op::Rem => (b as u32 % a as u32) as i32,
+                Op::Cmp => ((a as u32) <= (b as u32)) as i32,
+                _ => b.wrapping_sub(a),
+            };
+            rt::aheui_push(ctx, c, v);
+        },
+        Op::Pop => { rt::aheui_pop(ctx, c); },
+        Op::PutInt | Op::PutChar => {
+            // nothing is printed for a failed pop
+            let v = rt::aheui_pop(ctx, c);
+            if rt::aheui_failed(ctx) == 0 {
+                if ins.op == Op::PutInt { io.putint(v) } else { io.putchar(v) }
+            }
+        },
+        Op::Push => rt::aheui_push(ctx, c, ins.arg as i32),
+        Op::GetInt => rt::aheui_push(ctx, c, io.getint()),
+        Op::GetChar => rt::aheui_push(ctx, c, io.getchar()),
+        Op::Dup => rt::aheui_dup(ctx, c),
+        Op::Swap => rt::aheui_swap(ctx, c),
+        Op::Select => *cur = ins.arg as i8,
+        Op::Move => {
+            let v = rt::aheui_pop(ctx, c);
+            rt::aheui_push(ctx, ins.arg as i8, v);
+        },
+        Op::Branch => {
+            if rt::aheui_pop(ctx, c) == 0 {
+                return Step::Next(ins.alt as usize);
+            }
+        },
+        Op::Halt => return Step::Halt(rt::aheui_halt(ctx, c)),
+    }
+    Step::Next(ins.next as usize)
+}
+
+// reports an error the runtime recorded while `ins` ran
+pub fn failed(ctx: *mut AheuiRt, ins: &Instr) -> Option<i32> {
+    rt::take_error(ctx).map(|(why, status)| {
+        rt::flush(ctx);
+        let at = rt::cell_at(ins.x as i32, ins.y as i32, ins.c);
+        let _ = writeln!(stderr(), "error {}: {}", at, why);
+        status
+    })
+}
+
+pub fn run<I: Io>(ctx: *mut AheuiRt, prog: &[Instr], io: &mut I) -> i32 {
+    // the context may have failed already, on a mistake in the environment
+    if let Some(ret) = failed(ctx, &prog[0]) {
+        return ret;
+    }
+    let mut pc = 0;
+    let mut cur: i8 = 0;
+    loop {
+        let next = step(ctx, &prog[pc], &mut cur, io);
+        if let Some(ret) = failed(ctx, &prog[pc]) {
+            return ret;
+        }
+        match next {
+            Step::Next(next) => pc = next,
+            Step::Halt(ret) => return ret,
+        }
+    }
+}
+
+#[cfg(test)]
+pub struct BufIo {
+    pub input: Vec<char>,
+    pub output: String,
+}
+
+#[cfg(test)]
+impl Io for BufIo {
+    fn getchar(&mut self) -> i32 {
+        self.input.remove(0) as i32
+    }
+
+    fn putchar(&mut self, c: i32) {
+        self.output.push(::std::char::from_u32(c as u32).unwrap());
+    }
+
+    fn getint(&mut self) -> i32 {
+        while self.input[0].is_whitespace() {
+            self.input.remove(0);
+        }
+        let mut s = String::new();
+        while !self.input.is_empty() && (self.input[0] == '-' || self.input[0].is_digit(10)) {
+            s.push(self.input.remove(0));
+        }
+        s.parse().unwrap()
+    }
+
+    fn putint(&mut self, i: i32) {
+        self.output.push_str(&i.to_string());
+    }
+}
+
+#[test]
+fn test_vm_conformance() {
+    use bytecode;
+    use super::{conformance_cases, parse_code};
+
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let bytes = bytecode::encode(&bytecode::compile(&parse_code(&code)).unwrap());
+        let prog = bytecode::decode(&bytes).unwrap();
+        let mut io = BufIo { input: Vec::new(), output: String::new() };
+        let ctx = rt::aheui_ctx_new();
+        run(ctx, &prog, &mut io);
+        rt::aheui_ctx_free(ctx);
+        assert!(io.output == expected, "unexpected output from {}", name);
+    }
+}
+
+#[test]
+fn test_vm_io() {
+    use bytecode;
+    use super::parse_code;
+
+    let cases: Vec<(&str, &str, &str, i32)> = vec!(
+        ("방망희", "  -42\n", "-42", 0),
+        ("밯맣희", "한", "한", 0),
+        ("밯희", "A", "", 65),
+    );
+    for &(code, input, expected, status) in cases.iter() {
+        let prog = bytecode::compile(&parse_code(code)).unwrap();
+        let mut io = BufIo { input: input.chars().collect(), output: String::new() };
+        let ctx = rt::aheui_ctx_new();
+        assert!(run(ctx, &prog, &mut io) == status);
+        rt::aheui_ctx_free(ctx);
+        assert!(io.output == expected);
+    }
+}
+
+#[test]
+fn test_vm_errors() {
+    use bytecode;
+    use super::parse_code;
+
+    // popping an empty storage, dividing by zero or swapping one value ends
+    // the program early
+    for code in ["망희", "반반반타나망희", "밤파망희"].iter() {
+        let prog = bytecode::compile(&parse_code(code)).unwrap();
+        let mut io = BufIo { input: Vec::new(), output: String::new() };
+        let ctx = rt::aheui_ctx_new();
+        assert!(run(ctx, &prog, &mut io) == rt::ERROR_EXIT && io.output == "", "{}", code);
+        rt::aheui_ctx_free(ctx);
+    }
+}
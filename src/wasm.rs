@@ -0,0 +1,1193 @@
+// WebAssembly backend. The grid becomes a dispatch loop over cell indices
+// (one `block` per cell, entered through a `br_table`), and the storage
+// runtime is emitted into the module itself. The only imports are WASI
+// fd_read/fd_write/proc_exit, so the result runs under any WASI host.
+
+use std::fmt::Write;
+
+use super::{Hangul, Flow, next_pos, move_pos};
+use rt::ERROR_EXIT;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlockType {
+    Empty,
+    I32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Instr {
+    Unreachable,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    BrTable(Vec<u32>, u32),
+    Return,
+    Call(u32),
+    Drop,
+    Select,
+    LocalGet(u32),
+    LocalSet(u32),
+    // memory accesses carry their static offset
+    I32Load(u32),
+    I32Load8U(u32),
+    I32Store(u32),
+    I32Store8(u32),
+    MemorySize,
+    MemoryGrow,
+    I32Const(i32),
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtU,
+    I32LeU,
+    I32GeU,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivU,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrU,
+}
+
+macro_rules! simple_ops {
+    ($($name:ident = $op:tt $text:expr,)*) => {
+        pub fn simple_opcode(i: &Instr) -> Option<(u8, &'static str)> {
+            match *i {
+                $(Instr::$name => Some(($op, $text)),)*
+                _ => None,
+            }
+        }
+
+        #[cfg(test)]
+        pub fn simple_from_opcode(op: u8) -> Option<Instr> {
+            match op {
+                $($op => Some(Instr::$name),)*
+                _ => None,
+            }
+        }
+    }
+}
+
+simple_ops! {
+    Unreachable = 0x00 "unreachable",
+    Else = 0x05 "else",
+    End = 0x0b "end",
+    Return = 0x0f "return",
+    Drop = 0x1a "drop",
+    Select = 0x1b "select",
+    I32Eqz = 0x45 "i32.eqz",
+    I32Eq = 0x46 "i32.eq",
+    I32Ne = 0x47 "i32.ne",
+    I32LtS = 0x48 "i32.lt_s",
+    I32LtU = 0x49 "i32.lt_u",
+    I32GtU = 0x4b "i32.gt_u",
+    I32LeU = 0x4d "i32.le_u",
+    I32GeU = 0x4f "i32.ge_u",
+    I32Add = 0x6a "i32.add",
+    I32Sub = 0x6b "i32.sub",
+    I32Mul = 0x6c "i32.mul",
+    I32DivU = 0x6e "i32.div_u",
+    I32RemU = 0x70 "i32.rem_u",
+    I32And = 0x71 "i32.and",
+    I32Or = 0x72 "i32.or",
+    I32Xor = 0x73 "i32.xor",
+    I32Shl = 0x74 "i32.shl",
+    I32ShrU = 0x76 "i32.shr_u",
+}
+
+pub const VALTYPE_I32: u8 = 0x7f;
+pub const BLOCKTYPE_EMPTY: u8 = 0x40;
+
+// every value in the module is an i32, so a signature is just its arity
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FuncType {
+    pub params: u32,
+    pub results: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Import {
+    pub module: String,
+    pub name: String,
+    pub ty: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Func {
+    pub ty: u32,
+    pub locals: u32,
+    // without the trailing `end`
+    pub body: Vec<Instr>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportKind {
+    Func,
+    Memory,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Export {
+    pub name: String,
+    pub kind: ExportKind,
+    pub idx: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Data {
+    pub offset: u32,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Module {
+    pub types: Vec<FuncType>,
+    pub imports: Vec<Import>,
+    pub funcs: Vec<Func>,
+    pub memory_pages: u32,
+    pub exports: Vec<Export>,
+    pub data: Vec<Data>,
+}
+
+pub fn write_u32(out: &mut Vec<u8>, mut v: u32) {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+pub fn write_i32(out: &mut Vec<u8>, mut v: i32) {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if (v == 0 && b & 0x40 == 0) || (v == -1 && b & 0x40 != 0) {
+            out.push(b);
+            return;
+        }
+        out.push(b | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, s: &str) {
+    write_u32(out, s.len() as u32);
+    out.extend(s.as_bytes().iter().cloned());
+}
+
+fn write_section(out: &mut Vec<u8>, id: u8, count: usize, body: Vec<u8>) {
+    let mut content = Vec::new();
+    write_u32(&mut content, count as u32);
+    content.extend(body.into_iter());
+    out.push(id);
+    write_u32(out, content.len() as u32);
+    out.extend(content.into_iter());
+}
+
+fn write_blocktype(out: &mut Vec<u8>, bt: BlockType) {
+    out.push(match bt {
+        BlockType::Empty => BLOCKTYPE_EMPTY,
+        BlockType::I32 => VALTYPE_I32,
+    });
+}
+
+fn write_memarg(out: &mut Vec<u8>, align: u32, offset: u32) {
+    write_u32(out, align);
+    write_u32(out, offset);
+}
+
+pub fn write_instr(out: &mut Vec<u8>, i: &Instr) {
+    use self::Instr::*;
+
+    if let Some((op, _)) = simple_opcode(i) {
+        out.push(op);
+        return;
+    }
+    match *i {
+        Block(bt) => { out.push(0x02); write_blocktype(out, bt); },
+        Loop(bt) => { out.push(0x03); write_blocktype(out, bt); },
+        If(bt) => { out.push(0x04); write_blocktype(out, bt); },
+        Br(l) => { out.push(0x0c); write_u32(out, l); },
+        BrIf(l) => { out.push(0x0d); write_u32(out, l); },
+        BrTable(ref ls, l) => {
+            out.push(0x0e);
+            write_u32(out, ls.len() as u32);
+            for l in ls.iter() {
+                write_u32(out, *l);
+            }
+            write_u32(out, l);
+        },
+        Call(f) => { out.push(0x10); write_u32(out, f); },
+        LocalGet(n) => { out.push(0x20); write_u32(out, n); },
+        LocalSet(n) => { out.push(0x21); write_u32(out, n); },
+        I32Load(o) => { out.push(0x28); write_memarg(out, 2, o); },
+        I32Load8U(o) => { out.push(0x2d); write_memarg(out, 0, o); },
+        I32Store(o) => { out.push(0x36); write_memarg(out, 2, o); },
+        I32Store8(o) => { out.push(0x3a); write_memarg(out, 0, o); },
+        // the memory index, always 0
+        MemorySize => { out.push(0x3f); out.push(0x00); },
+        MemoryGrow => { out.push(0x40); out.push(0x00); },
+        I32Const(v) => { out.push(0x41); write_i32(out, v); },
+        _ => unreachable!(),
+    }
+}
+
+fn blocktype_text(bt: BlockType) -> &'static str {
+    match bt {
+        BlockType::Empty => "",
+        BlockType::I32 => " (result i32)",
+    }
+}
+
+pub fn instr_text(i: &Instr) -> String {
+    use self::Instr::*;
+
+    if let Some((_, text)) = simple_opcode(i) {
+        return text.to_string();
+    }
+    match *i {
+        Block(bt) => format!("block{}", blocktype_text(bt)),
+        Loop(bt) => format!("loop{}", blocktype_text(bt)),
+        If(bt) => format!("if{}", blocktype_text(bt)),
+        Br(l) => format!("br {}", l),
+        BrIf(l) => format!("br_if {}", l),
+        BrTable(ref ls, l) => {
+            let mut s = "br_table".to_string();
+            for l in ls.iter() {
+                write!(s, " {}", l).unwrap();
+            }
+            write!(s, " {}", l).unwrap();
+            s
+        },
+        Call(f) => format!("call {}", f),
+        LocalGet(n) => format!("local.get {}", n),
+        LocalSet(n) => format!("local.set {}", n),
+        I32Load(o) => format!("i32.load offset={}", o),
+        I32Load8U(o) => format!("i32.load8_u offset={}", o),
+        I32Store(o) => format!("i32.store offset={}", o),
+        I32Store8(o) => format!("i32.store8 offset={}", o),
+        MemorySize => "memory.size".to_string(),
+        MemoryGrow => "memory.grow".to_string(),
+        I32Const(v) => format!("i32.const {}", v),
+        _ => unreachable!(),
+    }
+}
+
+fn sig_text(ty: &FuncType) -> String {
+    let mut s = String::new();
+    if ty.params > 0 {
+        s.push_str(" (param");
+        for _ in 0..ty.params {
+            s.push_str(" i32");
+        }
+        s.push_str(")");
+    }
+    if ty.results > 0 {
+        s.push_str(" (result i32)");
+    }
+    s
+}
+
+fn escape_bytes(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for b in bytes.iter() {
+        match *b {
+            b'"' | b'\\' => { s.push('\\'); s.push(*b as char); },
+            0x20...0x7e => s.push(*b as char),
+            _ => write!(s, "\\{:02x}", b).unwrap(),
+        }
+    }
+    s
+}
+
+impl Module {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec!(0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00);
+
+        let mut body = Vec::new();
+        for ty in self.types.iter() {
+            body.push(0x60);
+            write_u32(&mut body, ty.params);
+            for _ in 0..ty.params {
+                body.push(VALTYPE_I32);
+            }
+            write_u32(&mut body, ty.results);
+            for _ in 0..ty.results {
+                body.push(VALTYPE_I32);
+            }
+        }
+        write_section(&mut out, 1, self.types.len(), body);
+
+        let mut body = Vec::new();
+        for imp in self.imports.iter() {
+            write_name(&mut body, &imp.module);
+            write_name(&mut body, &imp.name);
+            body.push(0x00);
+            write_u32(&mut body, imp.ty);
+        }
+        write_section(&mut out, 2, self.imports.len(), body);
+
+        let mut body = Vec::new();
+        for f in self.funcs.iter() {
+            write_u32(&mut body, f.ty);
+        }
+        write_section(&mut out, 3, self.funcs.len(), body);
+
+        let mut body = Vec::new();
+        body.push(0x00);
+        write_u32(&mut body, self.memory_pages);
+        write_section(&mut out, 5, 1, body);
+
+        let mut body = Vec::new();
+        for e in self.exports.iter() {
+            write_name(&mut body, &e.name);
+            body.push(match e.kind {
+                ExportKind::Func => 0x00,
+                ExportKind::Memory => 0x02,
+            });
+            write_u32(&mut body, e.idx);
+        }
+        write_section(&mut out, 7, self.exports.len(), body);
+
+        let mut body = Vec::new();
+        for f in self.funcs.iter() {
+            let mut code = Vec::new();
+            if f.locals > 0 {
+                write_u32(&mut code, 1);
+                write_u32(&mut code, f.locals);
+                code.push(VALTYPE_I32);
+            } else {
+                write_u32(&mut code, 0);
+            }
+            for i in f.body.iter() {
+                write_instr(&mut code, i);
+            }
+            write_instr(&mut code, &Instr::End);
+            write_u32(&mut body, code.len() as u32);
+            body.extend(code.into_iter());
+        }
+        write_section(&mut out, 10, self.funcs.len(), body);
+
+        let mut body = Vec::new();
+        for d in self.data.iter() {
+            body.push(0x00);
+            write_instr(&mut body, &Instr::I32Const(d.offset as i32));
+            write_instr(&mut body, &Instr::End);
+            write_u32(&mut body, d.bytes.len() as u32);
+            body.extend(d.bytes.iter().cloned());
+        }
+        write_section(&mut out, 11, self.data.len(), body);
+
+        out
+    }
+
+    pub fn to_wat(&self) -> String {
+        let mut s = "(module\n".to_string();
+        for (i, ty) in self.types.iter().enumerate() {
+            writeln!(s, "  (type (;{};) (func{}))", i, sig_text(ty)).unwrap();
+        }
+        for (i, imp) in self.imports.iter().enumerate() {
+            writeln!(s, "  (import \"{}\" \"{}\" (func (;{};) (type {})))",
+                     imp.module, imp.name, i, imp.ty).unwrap();
+        }
+        let nimports = self.imports.len();
+        for (i, f) in self.funcs.iter().enumerate() {
+            let ty = &self.types[f.ty as usize];
+            writeln!(s, "  (func (;{};) (type {}){}", i + nimports, f.ty, sig_text(ty)).unwrap();
+            if f.locals > 0 {
+                s.push_str("    (local");
+                for _ in 0..f.locals {
+                    s.push_str(" i32");
+                }
+                s.push_str(")\n");
+            }
+            let mut depth = 2;
+            for i in f.body.iter() {
+                match *i {
+                    Instr::End | Instr::Else => depth -= 1,
+                    _ => {},
+                }
+                for _ in 0..depth {
+                    s.push_str("  ");
+                }
+                s.push_str(&instr_text(i));
+                s.push('\n');
+                match *i {
+                    Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::Else => depth += 1,
+                    _ => {},
+                }
+            }
+            s.push_str("  )\n");
+        }
+        writeln!(s, "  (memory (;0;) {})", self.memory_pages).unwrap();
+        for e in self.exports.iter() {
+            let kind = match e.kind {
+                ExportKind::Func => "func",
+                ExportKind::Memory => "memory",
+            };
+            writeln!(s, "  (export \"{}\" ({} {}))", e.name, kind, e.idx).unwrap();
+        }
+        for d in self.data.iter() {
+            writeln!(s, "  (data (i32.const {}) \"{}\")", d.offset, escape_bytes(&d.bytes)).unwrap();
+        }
+        s.push_str(")\n");
+        s
+    }
+}
+
+// memory layout
+const IOV: i32 = 0;
+const NBYTES: i32 = 8;
+const BUF: i32 = 16;
+const BUF_LEN: i32 = 16;
+// the end of what the storages have taken so far
+const HEAP: i32 = 48;
+const DESC: i32 = 64;
+const FLOW_TBL: i32 = 512;
+const MSG: i32 = 576;
+// entries of a storage's first ring buffer; a full one is copied into one
+// twice its size at the heap end, growing the memory when needed
+const FIRST_CAP: i32 = 16;
+const PAGE: usize = 65536;
+
+// function indices, imports first
+const FD_READ: u32 = 0;
+const FD_WRITE: u32 = 1;
+const PROC_EXIT: u32 = 2;
+const FAIL: u32 = 3;
+const STORAGE_DESC: u32 = 4;
+const GROW: u32 = 5;
+const AT: u32 = 6;
+const TOP: u32 = 7;
+const PUSH: u32 = 8;
+const POP: u32 = 9;
+const DUP: u32 = 10;
+const SWAP: u32 = 11;
+const HALT: u32 = 12;
+const DIV: u32 = 13;
+const READ_BYTE: u32 = 14;
+const WRITE: u32 = 15;
+const GETCHAR: u32 = 16;
+const PUTCHAR: u32 = 17;
+const GETINT: u32 = 18;
+const PUTINT: u32 = 19;
+const MAIN: u32 = 20;
+const START: u32 = 21;
+
+const MESSAGES: [&'static str; 7] = [
+    "aheui: the extension storage (27) is not supported\n",
+    "aheui: storage underflow\n",
+    "aheui: out of memory\n",
+    "aheui: division by zero\n",
+    "aheui: cannot read input: invalid UTF-8\n",
+    "aheui: invalid character\n",
+    "aheui: bad input: not an integer\n",
+];
+const MSG_EXT: usize = 0;
+const MSG_UNDERFLOW: usize = 1;
+const MSG_MEMORY: usize = 2;
+const MSG_DIV: usize = 3;
+const MSG_INPUT: usize = 4;
+const MSG_CHAR: usize = 5;
+const MSG_INT: usize = 6;
+
+struct Layout {
+    msgs: Vec<(i32, i32)>,
+    next_tbl: i32,
+    store: i32,
+    pages: u32,
+}
+
+impl Layout {
+    fn new(ncells: usize) -> Layout {
+        let mut msgs = Vec::new();
+        let mut off = MSG;
+        for m in MESSAGES.iter() {
+            msgs.push((off, m.len() as i32));
+            off += m.len() as i32;
+        }
+        let next_tbl = (off + 15) & !15;
+        let store = (next_tbl + ncells as i32 * 16 + 15) & !15;
+        Layout {
+            msgs: msgs,
+            next_tbl: next_tbl,
+            store: store,
+            pages: (store as usize / PAGE + 1) as u32,
+        }
+    }
+
+    fn fail(&self, msg: usize) -> Vec<Instr> {
+        use self::Instr::*;
+        let (ptr, len) = self.msgs[msg];
+        vec!(I32Const(ptr), I32Const(len), Call(FAIL))
+    }
+
+    fn fail_if(&self, cond: Vec<Instr>, msg: usize) -> Vec<Instr> {
+        let mut v = cond;
+        v.push(Instr::If(BlockType::Empty));
+        v.extend(self.fail(msg).into_iter());
+        v.push(Instr::End);
+        v
+    }
+}
+
+// c == ' ' || c == '\t' || c == '\r', for the local c
+fn is_blank(c: u32) -> Vec<Instr> {
+    use self::Instr::*;
+    vec!(
+        LocalGet(c), I32Const(32), I32Eq,
+        LocalGet(c), I32Const(9), I32Eq, I32Or,
+        LocalGet(c), I32Const(13), I32Eq, I32Or,
+    )
+}
+
+fn rt_funcs(l: &Layout) -> Vec<(FuncType, u32, Vec<Instr>)> {
+    use self::Instr::*;
+    use self::BlockType::*;
+
+    let sig = |params, results| FuncType { params: params, results: results };
+    let mut fs = Vec::new();
+
+    // fail(msg, len)
+    fs.push((sig(2, 0), 0, vec!(
+        I32Const(2), LocalGet(0), LocalGet(1), Call(WRITE),
+        I32Const(ERROR_EXIT), Call(PROC_EXIT),
+        Unreachable,
+    )));
+
+    // storage_desc(idx) -> address of {base, head, len, cap}
+    let mut b = l.fail_if(vec!(LocalGet(0), I32Const(27), I32Eq), MSG_EXT);
+    b.extend(vec!(LocalGet(0), I32Const(16), I32Mul, I32Const(DESC), I32Add).into_iter());
+    fs.push((sig(1, 1), 0, b));
+
+    // grow(idx) moves the storage to a ring buffer twice as large
+    let mut b = vec!(
+        LocalGet(0), Call(STORAGE_DESC), LocalSet(1),
+        LocalGet(1), I32Load(12), I32Const(2), I32Mul,
+        I32Const(FIRST_CAP),
+        LocalGet(1), I32Load(12), Select, LocalSet(2),
+        I32Const(HEAP), I32Load(0), LocalSet(3),
+        LocalGet(3), LocalGet(2), I32Const(4), I32Mul, I32Add, LocalSet(4),
+    );
+    b.extend(l.fail_if(vec!(LocalGet(4), LocalGet(3), I32LtU), MSG_MEMORY).into_iter());
+    b.extend(vec!(LocalGet(4), MemorySize, I32Const(16), I32Shl, I32GtU, If(Empty)).into_iter());
+    b.extend(l.fail_if(vec!(
+        LocalGet(4), MemorySize, I32Const(16), I32Shl, I32Sub,
+        I32Const(PAGE as i32 - 1), I32Add, I32Const(16), I32ShrU,
+        MemoryGrow, I32Const(-1), I32Eq,
+    ), MSG_MEMORY).into_iter());
+    b.extend(vec!(
+        End,
+        I32Const(0), LocalSet(5),
+        Block(Empty),
+            Loop(Empty),
+                LocalGet(5), LocalGet(1), I32Load(8), I32GeU, BrIf(1),
+                LocalGet(3), LocalGet(5), I32Const(4), I32Mul, I32Add,
+                LocalGet(0), LocalGet(5), Call(AT), I32Load(0),
+                I32Store(0),
+                LocalGet(5), I32Const(1), I32Add, LocalSet(5),
+                Br(0),
+            End,
+        End,
+        LocalGet(1), LocalGet(3), I32Store(0),
+        LocalGet(1), I32Const(0), I32Store(4),
+        LocalGet(1), LocalGet(2), I32Store(12),
+        I32Const(HEAP), LocalGet(4), I32Store(0),
+    ).into_iter());
+    fs.push((sig(1, 0), 5, b));
+
+    // at(idx, i) -> address of the i-th element from the head
+    fs.push((sig(2, 1), 1, vec!(
+        LocalGet(0), Call(STORAGE_DESC), LocalSet(2),
+        LocalGet(2), I32Load(0),
+        LocalGet(2), I32Load(4), LocalGet(1), I32Add,
+        LocalGet(2), I32Load(12), I32RemU, I32Const(4), I32Mul,
+        I32Add,
+    )));
+
+    // top(idx, i) -> address of the i-th element from the top; the top of
+    // the queue is its head
+    fs.push((sig(2, 1), 0, vec!(
+        LocalGet(0),
+        LocalGet(0), I32Const(21), I32Eq,
+        If(I32),
+            LocalGet(1),
+        Else,
+            LocalGet(0), Call(STORAGE_DESC), I32Load(8),
+            I32Const(1), I32Sub, LocalGet(1), I32Sub,
+        End,
+        Call(AT),
+    )));
+
+    // push(idx, v)
+    fs.push((sig(2, 0), 1, vec!(
+        LocalGet(0), Call(STORAGE_DESC), LocalSet(2),
+        LocalGet(2), I32Load(8), LocalGet(2), I32Load(12), I32GeU,
+        If(Empty),
+            LocalGet(0), Call(GROW),
+        End,
+        LocalGet(2), LocalGet(2), I32Load(8), I32Const(1), I32Add, I32Store(8),
+        LocalGet(0), LocalGet(2), I32Load(8), I32Const(1), I32Sub, Call(AT),
+        LocalGet(1), I32Store(0),
+    )));
+
+    // pop(idx) -> v
+    let mut b = vec!(LocalGet(0), Call(STORAGE_DESC), LocalSet(1));
+    b.extend(l.fail_if(vec!(LocalGet(1), I32Load(8), I32Eqz), MSG_UNDERFLOW).into_iter());
+    b.extend(vec!(
+        LocalGet(0), I32Const(0), Call(TOP), I32Load(0), LocalSet(2),
+        LocalGet(0), I32Const(21), I32Eq,
+        If(Empty),
+            LocalGet(1), LocalGet(1), I32Load(4), I32Const(1), I32Add,
+            LocalGet(1), I32Load(12), I32RemU, I32Store(4),
+        End,
+        LocalGet(1), LocalGet(1), I32Load(8), I32Const(1), I32Sub, I32Store(8),
+        LocalGet(2),
+    ).into_iter());
+    fs.push((sig(1, 1), 2, b));
+
+    // dup(idx)
+    let mut b = vec!(LocalGet(0), Call(STORAGE_DESC), LocalSet(1));
+    b.extend(l.fail_if(vec!(LocalGet(1), I32Load(8), I32Eqz), MSG_UNDERFLOW).into_iter());
+    b.extend(vec!(
+        LocalGet(0), I32Const(0), Call(TOP), I32Load(0), LocalSet(2),
+        LocalGet(1), I32Load(8), LocalGet(1), I32Load(12), I32GeU,
+        If(Empty),
+            LocalGet(0), Call(GROW),
+        End,
+        LocalGet(0), I32Const(21), I32Eq,
+        If(Empty),
+            LocalGet(1), LocalGet(1), I32Load(4), LocalGet(1), I32Load(12), I32Add,
+            I32Const(1), I32Sub, LocalGet(1), I32Load(12), I32RemU, I32Store(4),
+        End,
+        LocalGet(1), LocalGet(1), I32Load(8), I32Const(1), I32Add, I32Store(8),
+        LocalGet(0), I32Const(0), Call(TOP), LocalGet(2), I32Store(0),
+    ).into_iter());
+    fs.push((sig(1, 0), 2, b));
+
+    // swap(idx)
+    let mut b = l.fail_if(vec!(LocalGet(0), Call(STORAGE_DESC), I32Load(8),
+                               I32Const(2), I32LtU), MSG_UNDERFLOW);
+    b.extend(vec!(
+        LocalGet(0), I32Const(0), Call(TOP), LocalSet(1),
+        LocalGet(0), I32Const(1), Call(TOP), LocalSet(2),
+        LocalGet(1), I32Load(0), LocalSet(3),
+        LocalGet(1), LocalGet(2), I32Load(0), I32Store(0),
+        LocalGet(2), LocalGet(3), I32Store(0),
+    ).into_iter());
+    fs.push((sig(1, 0), 3, b));
+
+    // halt(idx) -> exit code
+    fs.push((sig(1, 1), 0, vec!(
+        LocalGet(0), Call(STORAGE_DESC), I32Load(8), I32Eqz,
+        If(I32),
+            I32Const(0),
+        Else,
+            LocalGet(0), Call(POP),
+        End,
+    )));
+
+    // div(a, b, rem) -> b / a or b % a
+    let mut b = l.fail_if(vec!(LocalGet(0), I32Eqz), MSG_DIV);
+    b.extend(vec!(
+        LocalGet(2),
+        If(I32),
+            LocalGet(1), LocalGet(0), I32RemU,
+        Else,
+            LocalGet(1), LocalGet(0), I32DivU,
+        End,
+    ).into_iter());
+    fs.push((sig(3, 1), 0, b));
+
+    // read_byte() -> byte, or -1 at the end of input
+    fs.push((sig(0, 1), 0, vec!(
+        I32Const(IOV), I32Const(BUF), I32Store(0),
+        I32Const(IOV), I32Const(1), I32Store(4),
+        I32Const(0), I32Const(IOV), I32Const(1), I32Const(NBYTES), Call(FD_READ), Drop,
+        I32Const(NBYTES), I32Load(0), I32Eqz,
+        If(I32),
+            I32Const(-1),
+        Else,
+            I32Const(BUF), I32Load8U(0),
+        End,
+    )));
+
+    // write(fd, ptr, len)
+    fs.push((sig(3, 0), 0, vec!(
+        I32Const(IOV), LocalGet(1), I32Store(0),
+        I32Const(IOV), LocalGet(2), I32Store(4),
+        LocalGet(0), I32Const(IOV), I32Const(1), I32Const(NBYTES), Call(FD_WRITE), Drop,
+    )));
+
+    // getchar() -> the first character of a line decoded from UTF-8, or -1
+    // at the end of input, like rt.rs
+    let mut b = vec!(
+        Call(READ_BYTE), LocalSet(0),
+        LocalGet(0), I32Const(0), I32LtS,
+        If(Empty),
+            I32Const(-1), Return,
+        End,
+        LocalGet(0), I32Const(0x80), I32GeU,
+        If(Empty),
+            // number of continuation bytes
+            I32Const(3),
+            I32Const(2), I32Const(1), LocalGet(0), I32Const(0xe0), I32GeU, Select,
+            LocalGet(0), I32Const(0xf0), I32GeU, Select,
+            LocalSet(1),
+            LocalGet(0), I32Const(0x3f), LocalGet(1), I32ShrU, I32And, LocalSet(0),
+            Block(Empty),
+                Loop(Empty),
+                    LocalGet(1), I32Eqz, BrIf(1),
+                    Call(READ_BYTE), LocalSet(2),
+    );
+    b.extend(l.fail_if(vec!(LocalGet(2), I32Const(0xc0), I32And, I32Const(0x80), I32Ne),
+                       MSG_INPUT).into_iter());
+    b.extend(vec!(
+                    LocalGet(0), I32Const(6), I32Shl,
+                    LocalGet(2), I32Const(0x3f), I32And, I32Or, LocalSet(0),
+                    LocalGet(1), I32Const(1), I32Sub, LocalSet(1),
+                    Br(0),
+                End,
+            End,
+        End,
+        // the rest of the line is dropped
+        LocalGet(0), I32Const(10), I32Ne,
+        If(Empty),
+            Block(Empty),
+                Loop(Empty),
+                    Call(READ_BYTE), LocalSet(2),
+                    LocalGet(2), I32Const(10), I32Eq,
+                    LocalGet(2), I32Const(-1), I32Eq, I32Or, BrIf(1),
+                    Br(0),
+                End,
+            End,
+        End,
+        LocalGet(0),
+    ).into_iter());
+    fs.push((sig(0, 1), 3, b));
+
+    // putchar(c), encoded as UTF-8; surrogates are not characters
+    let mut b = l.fail_if(vec!(
+        LocalGet(0), I32Const(0x10ffff), I32GtU,
+        LocalGet(0), I32Const(0xd800), I32Sub, I32Const(0x800), I32LtU, I32Or,
+    ), MSG_CHAR);
+    let cont = |shift: i32, off: u32| vec!(
+        I32Const(BUF), LocalGet(0), I32Const(shift), I32ShrU,
+        I32Const(0x3f), I32And, I32Const(0x80), I32Or, I32Store8(off),
+    );
+    let lead = |shift: i32, mark: i32| vec!(
+        I32Const(BUF), LocalGet(0), I32Const(shift), I32ShrU,
+        I32Const(mark), I32Or, I32Store8(0),
+    );
+    b.extend(vec!(
+        LocalGet(0), I32Const(0x80), I32LtU,
+        If(Empty),
+            I32Const(BUF), LocalGet(0), I32Store8(0),
+            I32Const(1), I32Const(BUF), I32Const(1), Call(WRITE), Return,
+        End,
+    ).into_iter());
+    for &(limit, n, mark) in [(0x800, 2, 0xc0), (0x10000, 3, 0xe0)].iter() {
+        b.extend(vec!(LocalGet(0), I32Const(limit), I32LtU, If(Empty)).into_iter());
+        b.extend(lead(6 * (n - 1), mark).into_iter());
+        for k in 1..n {
+            b.extend(cont(6 * (n - 1 - k), k as u32).into_iter());
+        }
+        b.extend(vec!(I32Const(1), I32Const(BUF), I32Const(n), Call(WRITE), Return, End)
+                  .into_iter());
+    }
+    b.extend(lead(18, 0xf0).into_iter());
+    for k in 1..4 {
+        b.extend(cont(6 * (3 - k), k as u32).into_iter());
+    }
+    b.extend(vec!(I32Const(1), I32Const(BUF), I32Const(4), Call(WRITE)).into_iter());
+    fs.push((sig(1, 0), 0, b));
+
+    // getint() -> a line holding one decimal integer with blanks around it,
+    // or -1 at the end of input, like rt.rs
+    let mut skip_blanks = vec!(Block(Empty), Loop(Empty));
+    skip_blanks.extend(is_blank(0).into_iter());
+    skip_blanks.extend(vec!(
+                I32Eqz, BrIf(1),
+                Call(READ_BYTE), LocalSet(0),
+                Br(0),
+            End,
+        End,
+    ).into_iter());
+    let mut b = vec!(
+        Call(READ_BYTE), LocalSet(0),
+        LocalGet(0), I32Const(0), I32LtS,
+        If(Empty),
+            I32Const(-1), Return,
+        End,
+    );
+    b.extend(skip_blanks.iter().cloned());
+    b.extend(vec!(
+        LocalGet(0), I32Const(45), I32Eq, LocalSet(1),
+        LocalGet(0), I32Const(45), I32Eq, LocalGet(0), I32Const(43), I32Eq, I32Or,
+        If(Empty),
+            Call(READ_BYTE), LocalSet(0),
+        End,
+        Block(Empty),
+            Loop(Empty),
+                LocalGet(0), I32Const(48), I32Sub, I32Const(10), I32GeU, BrIf(1),
+    ).into_iter());
+    // past 2147483647, or 2147483648 when negative
+    b.extend(l.fail_if(vec!(
+        LocalGet(2), I32Const(214748364), I32GtU,
+        LocalGet(2), I32Const(214748364), I32Eq,
+        LocalGet(0), I32Const(48 + 7), LocalGet(1), I32Add, I32GtU, I32And,
+        I32Or,
+    ), MSG_INT).into_iter());
+    b.extend(vec!(
+                LocalGet(2), I32Const(10), I32Mul,
+                LocalGet(0), I32Const(48), I32Sub, I32Add, LocalSet(2),
+                I32Const(1), LocalSet(3),
+                Call(READ_BYTE), LocalSet(0),
+                Br(0),
+            End,
+        End,
+    ).into_iter());
+    b.extend(skip_blanks.into_iter());
+    b.extend(l.fail_if(vec!(
+        LocalGet(3), I32Eqz,
+        LocalGet(0), I32Const(10), I32Ne,
+        LocalGet(0), I32Const(-1), I32Ne, I32And,
+        I32Or,
+    ), MSG_INT).into_iter());
+    b.extend(vec!(
+        LocalGet(1),
+        If(I32),
+            I32Const(0), LocalGet(2), I32Sub,
+        Else,
+            LocalGet(2),
+        End,
+    ).into_iter());
+    fs.push((sig(0, 1), 4, b));
+
+    // putint(i), digits are written backwards from the end of BUF
+    fs.push((sig(1, 0), 3, vec!(
+        I32Const(BUF + BUF_LEN), LocalSet(1),
+        LocalGet(0), I32Const(0), I32LtS, LocalSet(2),
+        LocalGet(2),
+        If(I32),
+            I32Const(0), LocalGet(0), I32Sub,
+        Else,
+            LocalGet(0),
+        End,
+        LocalSet(3),
+        Loop(Empty),
+            LocalGet(1), I32Const(1), I32Sub, LocalSet(1),
+            LocalGet(1), LocalGet(3), I32Const(10), I32RemU, I32Const(48), I32Add, I32Store8(0),
+            LocalGet(3), I32Const(10), I32DivU, LocalSet(3),
+            LocalGet(3), BrIf(0),
+        End,
+        LocalGet(2),
+        If(Empty),
+            LocalGet(1), I32Const(1), I32Sub, LocalSet(1),
+            LocalGet(1), I32Const(45), I32Store8(0),
+        End,
+        I32Const(1), LocalGet(1), I32Const(BUF + BUF_LEN), LocalGet(1), I32Sub, Call(WRITE),
+    )));
+
+    fs
+}
+
+// locals of the entry function
+const L_FLOW: u32 = 0;
+const L_CUR: u32 = 1;
+const L_COMP: u32 = 2;
+const L_A: u32 = 3;
+const L_B: u32 = 4;
+const L_PC: u32 = 5;
+
+fn gen_cell(b: &mut Vec<Instr>, next: &mut Vec<u8>, l: &Layout, code: &[Vec<Hangul>],
+            idx: &Vec<Vec<i32>>, x: usize, y: usize, depth: u32) {
+    use self::Instr::*;
+    use Cho::*;
+    use Jung::*;
+    use Jong::*;
+
+    let h = &code[y][x];
+    let flows = [Flow::Left, Flow::Right, Flow::Up, Flow::Down];
+    for f in flows.iter() {
+        let (nx, ny) = next_pos(code, x, y, *f);
+        write_le(next, idx[ny][nx]);
+    }
+    b.extend(vec!(I32Const(0), LocalSet(L_COMP)).into_iter());
+
+    let binop = |b: &mut Vec<Instr>, op: Vec<Instr>| {
+        b.extend(vec!(
+            LocalGet(L_CUR), Call(POP), LocalSet(L_A),
+            LocalGet(L_CUR), Call(POP), LocalSet(L_B),
+            LocalGet(L_CUR),
+        ).into_iter());
+        b.extend(op.into_iter());
+        b.push(Call(PUSH));
+    };
+
+    match h.cho {
+        cㄴ => binop(b, vec!(LocalGet(L_A), LocalGet(L_B), I32Const(0), Call(DIV))),
+        cㄷ => binop(b, vec!(LocalGet(L_A), LocalGet(L_B), I32Add)),
+        cㄸ => binop(b, vec!(LocalGet(L_A), LocalGet(L_B), I32Mul)),
+        cㄹ => binop(b, vec!(LocalGet(L_A), LocalGet(L_B), I32Const(1), Call(DIV))),
+        cㅁ => {
+            b.extend(vec!(LocalGet(L_CUR), Call(POP)).into_iter());
+            b.push(match h.jong {
+                jㅇ => Call(PUTINT),
+                jㅎ => Call(PUTCHAR),
+                _ => Drop,
+            });
+        },
+        cㅂ => {
+            b.push(LocalGet(L_CUR));
+            b.push(match h.jong {
+                jㅇ => Call(GETINT),
+                jㅎ => Call(GETCHAR),
+                _ => I32Const(h.jong.val() as i32),
+            });
+            b.push(Call(PUSH));
+        },
+        cㅃ => b.extend(vec!(LocalGet(L_CUR), Call(DUP)).into_iter()),
+        cㅅ => b.extend(vec!(I32Const(h.jong as i32), LocalSet(L_CUR)).into_iter()),
+        cㅆ => b.extend(vec!(
+            I32Const(h.jong as i32), LocalGet(L_CUR), Call(POP), Call(PUSH),
+        ).into_iter()),
+        cㅈ => binop(b, vec!(LocalGet(L_A), LocalGet(L_B), I32LeU)),
+        cㅊ => b.extend(vec!(LocalGet(L_CUR), Call(POP), I32Eqz, LocalSet(L_COMP)).into_iter()),
+        cㅌ => binop(b, vec!(LocalGet(L_B), LocalGet(L_A), I32Sub)),
+        cㅍ => b.extend(vec!(LocalGet(L_CUR), Call(SWAP)).into_iter()),
+        cㅎ => {
+            b.extend(vec!(LocalGet(L_CUR), Call(HALT), Return).into_iter());
+            return;
+        },
+        cㄱ | cㄲ | cㅇ | cㅉ | cㅋ | cNone => {},
+    }
+
+    match h.jung {
+        ㅏ | ㅓ | ㅗ | ㅜ | ㅑ | ㅕ | ㅛ | ㅠ => {
+            let flow = Flow::from_jung(h.jung).unwrap();
+            let (nx, ny) = move_pos(code, x, y, h.jung, flow);
+            let (rx, ry) = move_pos(code, x, y, h.jung, flow.reverse());
+            b.extend(vec!(
+                I32Const(flow as i32), LocalSet(L_FLOW),
+                I32Const(idx[ry][rx]), I32Const(idx[ny][nx]), LocalGet(L_COMP), Select,
+                LocalSet(L_PC),
+            ).into_iter());
+        },
+        _ => {
+            let j = match h.jung {
+                ㅣ => 1,
+                ㅡ => 2,
+                ㅢ => 3,
+                _ => 0,
+            };
+            let tbl = (l.next_tbl + idx[y][x] * 16) as u32;
+            b.extend(vec!(
+                LocalGet(L_FLOW), I32Load8U((FLOW_TBL + j * 4) as u32), LocalSet(L_FLOW),
+                LocalGet(L_FLOW),
+            ).into_iter());
+            if h.cho == cㅊ {
+                // a zero reverses the move but not the flow: left and right,
+                // up and down differ in the lowest bit
+                b.extend(vec!(LocalGet(L_COMP), I32Xor).into_iter());
+            }
+            b.extend(vec!(I32Const(4), I32Mul, I32Load(tbl), LocalSet(L_PC)).into_iter());
+        },
+    }
+    b.push(Br(depth));
+}
+
+fn write_le(out: &mut Vec<u8>, v: i32) {
+    for k in 0..4 {
+        out.push((v >> (8 * k)) as u8);
+    }
+}
+
+pub fn gen_wasm(code: &[Vec<Hangul>], fn_name: &str) -> Module {
+    use self::Instr::*;
+    use self::BlockType::*;
+
+    let mut idx = Vec::new();
+    let mut ncells = 0;
+    for line in code.iter() {
+        let mut row = Vec::new();
+        for _ in line.iter() {
+            row.push(ncells);
+            ncells += 1;
+        }
+        idx.push(row);
+    }
+    let l = Layout::new(ncells as usize);
+
+    let mut m = Module {
+        types: Vec::new(),
+        imports: Vec::new(),
+        funcs: Vec::new(),
+        memory_pages: l.pages,
+        exports: Vec::new(),
+        data: Vec::new(),
+    };
+    fn type_idx(m: &mut Module, ty: FuncType) -> u32 {
+        match m.types.iter().position(|t| *t == ty) {
+            Some(i) => i as u32,
+            None => {
+                m.types.push(ty);
+                (m.types.len() - 1) as u32
+            }
+        }
+    }
+
+    let wasi = [("fd_read", 4, 1), ("fd_write", 4, 1), ("proc_exit", 1, 0)];
+    for &(name, params, results) in wasi.iter() {
+        let ty = type_idx(&mut m, FuncType { params: params, results: results });
+        m.imports.push(Import {
+            module: "wasi_snapshot_preview1".to_string(),
+            name: name.to_string(),
+            ty: ty,
+        });
+    }
+    for (sig, locals, body) in rt_funcs(&l).into_iter() {
+        let ty = type_idx(&mut m, sig);
+        m.funcs.push(Func { ty: ty, locals: locals, body: body });
+    }
+
+    // the entry function: one block per cell, innermost first, so that
+    // falling out of block `i` lands on the code of cell `i`
+    let n = ncells as u32;
+    let mut b = vec!(I32Const(Flow::Down as i32), LocalSet(L_FLOW), Loop(Empty));
+    for _ in 0..n {
+        b.push(Block(Empty));
+    }
+    b.push(LocalGet(L_PC));
+    b.push(BrTable((0..n).collect(), n - 1));
+    let mut next = Vec::new();
+    for (y, line) in code.iter().enumerate() {
+        for x in 0..line.len() {
+            b.push(End);
+            let depth = n - 1 - idx[y][x] as u32;
+            gen_cell(&mut b, &mut next, &l, code, &idx, x, y, depth);
+        }
+    }
+    b.push(End);
+    b.push(Unreachable);
+    let ty = type_idx(&mut m, FuncType { params: 0, results: 1 });
+    m.funcs.push(Func { ty: ty, locals: 6, body: b });
+
+    let ty = type_idx(&mut m, FuncType { params: 0, results: 0 });
+    m.funcs.push(Func { ty: ty, locals: 0, body: vec!(Call(MAIN), Call(PROC_EXIT)) });
+    assert!(m.imports.len() + m.funcs.len() == START as usize + 1);
+
+    m.exports.push(Export { name: "memory".to_string(), kind: ExportKind::Memory, idx: 0 });
+    m.exports.push(Export { name: fn_name.to_string(), kind: ExportKind::Func, idx: MAIN });
+    m.exports.push(Export { name: "_start".to_string(), kind: ExportKind::Func, idx: START });
+
+    let fl: Vec<u8> = vec!(
+        0, 1, 2, 3,
+        1, 0, 2, 3,
+        0, 1, 3, 2,
+        1, 0, 3, 2,
+    );
+    m.data.push(Data { offset: FLOW_TBL as u32, bytes: fl });
+    let mut heap = Vec::new();
+    write_le(&mut heap, l.store);
+    m.data.push(Data { offset: HEAP as u32, bytes: heap });
+    let mut msgs = Vec::new();
+    for s in MESSAGES.iter() {
+        msgs.extend(s.as_bytes().iter().cloned());
+    }
+    m.data.push(Data { offset: MSG as u32, bytes: msgs });
+    m.data.push(Data { offset: l.next_tbl as u32, bytes: next });
+    m
+}
+
+#[test]
+fn test_leb128() {
+    let mut v = Vec::new();
+    write_u32(&mut v, 624485);
+    assert!(v == vec!(0xe5, 0x8e, 0x26));
+
+    let mut v = Vec::new();
+    write_i32(&mut v, -123456);
+    assert!(v == vec!(0xc0, 0xbb, 0x78));
+
+    let mut v = Vec::new();
+    write_i32(&mut v, 64);
+    assert!(v == vec!(0xc0, 0x00));
+}
+
+#[test]
+fn test_wasm_conformance() {
+    use super::{conformance_cases, parse_code};
+    use wasm_interp;
+
+    for (name, code, expected) in conformance_cases().into_iter() {
+        let module = gen_wasm(&parse_code(&code), "aheui_main");
+        let bytes = module.encode();
+        let decoded = wasm_interp::decode(&bytes).unwrap();
+        assert!(decoded == module, "decoded module differs for {}", name);
+
+        let (out, _) = wasm_interp::run_start(&decoded, b"").unwrap();
+        assert!(out == expected.as_bytes(), "unexpected output from {}", name);
+    }
+}
+
+#[test]
+fn test_wasm_io() {
+    use super::parse_code;
+    use wasm_interp;
+
+    let cases: Vec<(&str, &str, &str, i32)> = vec!(
+        ("방망희", "  -42\n", "-42", 0),
+        ("밯맣희", "한", "한", 0),
+        ("밯희", "A", "", 65),
+        ("밯맣밯맣희", "가나\n다\n", "가다", 0),
+        ("방망방망희", "+7 \n-2147483648\n", "7-2147483648", 0),
+        ("밯망방망희", "", "-1-1", 0),
+        ("방망희", "4two\n", "", ERROR_EXIT),
+        ("방망희", "2147483648\n", "", ERROR_EXIT),
+        ("방망희", "\n", "", ERROR_EXIT),
+        ("망희", "", "", ERROR_EXIT),
+        // ㅢ sends the flow back up to read again until a zero moves it on
+        ("붕\n츼\n붕\n뭉\n희", "5\n7\n0\n9\n", "9", 0),
+    );
+    for &(code, input, expected, status) in cases.iter() {
+        let module = gen_wasm(&parse_code(code), "aheui_main");
+        let (out, ret) = wasm_interp::run_start(&module, input.as_bytes()).unwrap();
+        assert!(out == expected.as_bytes(), "unexpected output from {}", code);
+        assert!(ret == status, "unexpected status from {}", code);
+    }
+}
+
+#[test]
+fn test_wasm_deep() {
+    use super::parse_code;
+    use wasm_interp;
+
+    // moves 32768, 32767, ..., 1 to a stack or to the queue, well past the
+    // first ring buffers and the first memory page, then prints two and
+    // exits with the third
+    let cases = vec!(
+        ("밣빠빠빠빠따따따따빠싹반반나타빠추\n어어어어어어어어어오어어어어어어어\n\
+          어어어어어어어어어어어어어허멍멍석", "12", 3),
+        ("밣빠빠빠빠따따따따빠쌍반반나타빠추\n어어어어어어어어어오어어어어어어어\n\
+          어어어어어어어어어어어어어허멍멍성", "3276832767", 32766),
+    );
+    for &(code, expected, status) in cases.iter() {
+        let module = gen_wasm(&parse_code(code), "aheui_main");
+        let (out, ret) = wasm_interp::run_start(&module, b"").unwrap();
+        assert!(out == expected.as_bytes() && ret == status);
+    }
+}
@@ -0,0 +1,738 @@
+// A small WebAssembly decoder and interpreter covering the subset emitted by
+// the wasm backend, used to validate and run generated modules in tests
+// without an external runtime. WASI fd_read/fd_write/proc_exit are served
+// from in-memory buffers.
+
+use std::collections::HashMap;
+
+use wasm::{Module, FuncType, Import, Func, Export, ExportKind, Data, Instr, BlockType};
+use wasm::{VALTYPE_I32, BLOCKTYPE_EMPTY, simple_from_opcode};
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn eof(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        if self.eof() {
+            return Err(format!("unexpected end of module at {}", self.pos));
+        }
+        self.pos += 1;
+        Ok(self.bytes[self.pos - 1])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let mut v = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = try!(self.byte());
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 28 {
+                return Err("u32 LEB128 too long".to_string());
+            }
+        }
+        if v > 0xffffffff {
+            return Err("u32 LEB128 out of range".to_string());
+        }
+        Ok(v as u32)
+    }
+
+    fn i32(&mut self) -> Result<i32, String> {
+        let mut v = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = try!(self.byte());
+            v |= ((b & 0x7f) as i64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if shift < 64 && b & 0x40 != 0 {
+                    v |= -1i64 << shift;
+                }
+                break;
+            }
+            if shift > 28 {
+                return Err("i32 LEB128 too long".to_string());
+            }
+        }
+        if v < -0x80000000 || v > 0x7fffffff {
+            return Err("i32 LEB128 out of range".to_string());
+        }
+        Ok(v as i32)
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.pos + n > self.bytes.len() {
+            return Err(format!("unexpected end of module at {}", self.pos));
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let n = try!(self.u32()) as usize;
+        let b = try!(self.bytes(n));
+        String::from_utf8(b.to_vec()).map_err(|_| "invalid UTF-8 in name".to_string())
+    }
+
+    fn valtype(&mut self) -> Result<(), String> {
+        match try!(self.byte()) {
+            VALTYPE_I32 => Ok(()),
+            t => Err(format!("unsupported value type 0x{:02x}", t)),
+        }
+    }
+
+    fn blocktype(&mut self) -> Result<BlockType, String> {
+        match try!(self.byte()) {
+            BLOCKTYPE_EMPTY => Ok(BlockType::Empty),
+            VALTYPE_I32 => Ok(BlockType::I32),
+            t => Err(format!("unsupported block type 0x{:02x}", t)),
+        }
+    }
+
+    fn memarg(&mut self, max_align: u32) -> Result<u32, String> {
+        let align = try!(self.u32());
+        if align > max_align {
+            return Err(format!("alignment 2^{} too large", align));
+        }
+        self.u32()
+    }
+
+    fn instr(&mut self) -> Result<Instr, String> {
+        use wasm::Instr::*;
+
+        let op = try!(self.byte());
+        if let Some(i) = simple_from_opcode(op) {
+            return Ok(i);
+        }
+        let i = match op {
+            0x02 => Block(try!(self.blocktype())),
+            0x03 => Loop(try!(self.blocktype())),
+            0x04 => If(try!(self.blocktype())),
+            0x0c => Br(try!(self.u32())),
+            0x0d => BrIf(try!(self.u32())),
+            0x0e => {
+                let n = try!(self.u32());
+                let mut ls = Vec::new();
+                for _ in 0..n {
+                    ls.push(try!(self.u32()));
+                }
+                BrTable(ls, try!(self.u32()))
+            },
+            0x10 => Call(try!(self.u32())),
+            0x20 => LocalGet(try!(self.u32())),
+            0x21 => LocalSet(try!(self.u32())),
+            0x28 => I32Load(try!(self.memarg(2))),
+            0x2d => I32Load8U(try!(self.memarg(0))),
+            0x36 => I32Store(try!(self.memarg(2))),
+            0x3a => I32Store8(try!(self.memarg(0))),
+            0x3f | 0x40 => {
+                if try!(self.byte()) != 0x00 {
+                    return Err("unknown memory index".to_string());
+                }
+                if op == 0x3f { MemorySize } else { MemoryGrow }
+            },
+            0x41 => I32Const(try!(self.i32())),
+            _ => return Err(format!("unsupported opcode 0x{:02x} at {}", op, self.pos - 1)),
+        };
+        Ok(i)
+    }
+
+    // instructions up to the `end` closing the expression
+    fn expr(&mut self) -> Result<Vec<Instr>, String> {
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let i = try!(self.instr());
+            match i {
+                Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => depth += 1,
+                Instr::End if depth == 0 => return Ok(body),
+                Instr::End => depth -= 1,
+                _ => {},
+            }
+            body.push(i);
+        }
+    }
+}
+
+fn check_body(m: &Module, f: &Func) -> Result<(), String> {
+    let ty = m.types[f.ty as usize];
+    let nlocals = ty.params + f.locals;
+    let nfuncs = (m.imports.len() + m.funcs.len()) as u32;
+    let mut labels = 1;
+    let mut kinds = Vec::new();
+    for i in f.body.iter() {
+        match *i {
+            Instr::Block(_) | Instr::Loop(_) => { labels += 1; kinds.push(false); },
+            Instr::If(_) => { labels += 1; kinds.push(true); },
+            Instr::Else => {
+                if kinds.last() != Some(&true) {
+                    return Err("`else` outside of `if`".to_string());
+                }
+            },
+            Instr::End => {
+                if kinds.pop().is_none() {
+                    return Err("unbalanced `end`".to_string());
+                }
+                labels -= 1;
+            },
+            Instr::Br(l) | Instr::BrIf(l) => {
+                if l >= labels {
+                    return Err(format!("branch depth {} out of range", l));
+                }
+            },
+            Instr::BrTable(ref ls, l) => {
+                if ls.iter().any(|l| *l >= labels) || l >= labels {
+                    return Err("br_table depth out of range".to_string());
+                }
+            },
+            Instr::Call(f) => {
+                if f >= nfuncs {
+                    return Err(format!("call to unknown function {}", f));
+                }
+            },
+            Instr::LocalGet(n) | Instr::LocalSet(n) => {
+                if n >= nlocals {
+                    return Err(format!("unknown local {}", n));
+                }
+            },
+            _ => {},
+        }
+    }
+    if !kinds.is_empty() {
+        return Err("unterminated block".to_string());
+    }
+    Ok(())
+}
+
+// decodes a binary module, checking its structure along the way
+pub fn decode(bytes: &[u8]) -> Result<Module, String> {
+    let mut r = Reader { bytes: bytes, pos: 0 };
+    if try!(r.bytes(4)) != &b"\0asm"[..] {
+        return Err("bad magic".to_string());
+    }
+    if try!(r.bytes(4)) != &[1u8, 0, 0, 0][..] {
+        return Err("unsupported version".to_string());
+    }
+
+    let mut m = Module {
+        types: Vec::new(),
+        imports: Vec::new(),
+        funcs: Vec::new(),
+        memory_pages: 0,
+        exports: Vec::new(),
+        data: Vec::new(),
+    };
+    let mut func_types = Vec::new();
+    let mut has_memory = false;
+    let mut last_id = 0;
+
+    while !r.eof() {
+        let id = try!(r.byte());
+        let size = try!(r.u32()) as usize;
+        if id <= last_id {
+            return Err(format!("section {} out of order", id));
+        }
+        last_id = id;
+        let end = r.pos + size;
+        if end > bytes.len() {
+            return Err(format!("section {} exceeds module", id));
+        }
+        let count = try!(r.u32());
+        for _ in 0..count {
+            match id {
+                1 => {
+                    if try!(r.byte()) != 0x60 {
+                        return Err("expected function type".to_string());
+                    }
+                    let params = try!(r.u32());
+                    for _ in 0..params {
+                        try!(r.valtype());
+                    }
+                    let results = try!(r.u32());
+                    for _ in 0..results {
+                        try!(r.valtype());
+                    }
+                    if results > 1 {
+                        return Err("multiple results are not supported".to_string());
+                    }
+                    m.types.push(FuncType { params: params, results: results });
+                },
+                2 => {
+                    let module = try!(r.name());
+                    let name = try!(r.name());
+                    if try!(r.byte()) != 0x00 {
+                        return Err("only function imports are supported".to_string());
+                    }
+                    m.imports.push(Import { module: module, name: name, ty: try!(r.u32()) });
+                },
+                3 => func_types.push(try!(r.u32())),
+                5 => {
+                    if has_memory {
+                        return Err("multiple memories".to_string());
+                    }
+                    has_memory = true;
+                    match try!(r.byte()) {
+                        0x00 => m.memory_pages = try!(r.u32()),
+                        0x01 => {
+                            m.memory_pages = try!(r.u32());
+                            try!(r.u32());
+                        },
+                        _ => return Err("bad memory limits".to_string()),
+                    }
+                },
+                7 => {
+                    let name = try!(r.name());
+                    let kind = match try!(r.byte()) {
+                        0x00 => ExportKind::Func,
+                        0x02 => ExportKind::Memory,
+                        k => return Err(format!("unsupported export kind {}", k)),
+                    };
+                    m.exports.push(Export { name: name, kind: kind, idx: try!(r.u32()) });
+                },
+                10 => {
+                    let size = try!(r.u32()) as usize;
+                    let body_end = r.pos + size;
+                    let mut locals = 0;
+                    for _ in 0..try!(r.u32()) {
+                        locals += try!(r.u32());
+                        try!(r.valtype());
+                    }
+                    let body = try!(r.expr());
+                    if r.pos != body_end {
+                        return Err("function body size mismatch".to_string());
+                    }
+                    let i = m.funcs.len();
+                    if i >= func_types.len() {
+                        return Err("more bodies than functions".to_string());
+                    }
+                    m.funcs.push(Func { ty: func_types[i], locals: locals, body: body });
+                },
+                11 => {
+                    if try!(r.u32()) != 0 {
+                        return Err("only active data segments are supported".to_string());
+                    }
+                    let e = try!(r.expr());
+                    let offset = match (e.len(), e.first()) {
+                        (1, Some(&Instr::I32Const(o))) => o as u32,
+                        _ => return Err("data offset must be a constant".to_string()),
+                    };
+                    let n = try!(r.u32()) as usize;
+                    let bytes = try!(r.bytes(n)).to_vec();
+                    m.data.push(Data { offset: offset, bytes: bytes });
+                },
+                _ => return Err(format!("unsupported section {}", id)),
+            }
+        }
+        if r.pos != end {
+            return Err(format!("section {} size mismatch", id));
+        }
+    }
+
+    if m.funcs.len() != func_types.len() {
+        return Err("function and code section counts differ".to_string());
+    }
+    let ntypes = m.types.len() as u32;
+    if m.imports.iter().any(|i| i.ty >= ntypes) || func_types.iter().any(|t| *t >= ntypes) {
+        return Err("unknown type index".to_string());
+    }
+    let nfuncs = (m.imports.len() + m.funcs.len()) as u32;
+    for e in m.exports.iter() {
+        let ok = match e.kind {
+            ExportKind::Func => e.idx < nfuncs,
+            ExportKind::Memory => has_memory && e.idx == 0,
+        };
+        if !ok {
+            return Err(format!("export `{}` refers to nothing", e.name));
+        }
+    }
+    let mem_size = m.memory_pages as usize * 65536;
+    for d in m.data.iter() {
+        if d.offset as usize + d.bytes.len() > mem_size {
+            return Err("data segment out of bounds".to_string());
+        }
+    }
+    for f in m.funcs.iter() {
+        try!(check_body(&m, f));
+    }
+    Ok(m)
+}
+
+enum Trap {
+    Exit(i32),
+    Error(String),
+}
+
+// the most a wasm32 memory can have
+const MAX_PAGES: usize = 65536;
+
+fn trap<T>(msg: &str) -> Result<T, Trap> {
+    Err(Trap::Error(msg.to_string()))
+}
+
+struct Label {
+    cont: usize,
+    height: usize,
+    arity: usize,
+    is_loop: bool,
+}
+
+struct Instance<'a> {
+    m: &'a Module,
+    mem: Vec<u8>,
+    input: &'a [u8],
+    in_pos: usize,
+    output: Vec<u8>,
+    // position of the matching `end` (and `else`) of every structured
+    // instruction, per function
+    ends: Vec<HashMap<usize, usize>>,
+    elses: Vec<HashMap<usize, usize>>,
+}
+
+impl<'a> Instance<'a> {
+    fn new(m: &'a Module, input: &'a [u8]) -> Instance<'a> {
+        let mut mem = vec![0u8; m.memory_pages as usize * 65536];
+        for d in m.data.iter() {
+            let off = d.offset as usize;
+            for (i, b) in d.bytes.iter().enumerate() {
+                mem[off + i] = *b;
+            }
+        }
+
+        let mut ends = Vec::new();
+        let mut elses = Vec::new();
+        for f in m.funcs.iter() {
+            let mut end = HashMap::new();
+            let mut els = HashMap::new();
+            let mut open = Vec::new();
+            for (pc, i) in f.body.iter().enumerate() {
+                match *i {
+                    Instr::Block(_) | Instr::Loop(_) | Instr::If(_) => open.push(pc),
+                    Instr::Else => {
+                        let start = *open.last().unwrap();
+                        els.insert(start, pc);
+                        open.push(pc);
+                    },
+                    Instr::End => {
+                        let start = open.pop().unwrap();
+                        end.insert(start, pc);
+                        if let Instr::Else = f.body[start] {
+                            let start = open.pop().unwrap();
+                            end.insert(start, pc);
+                        }
+                    },
+                    _ => {},
+                }
+            }
+            ends.push(end);
+            elses.push(els);
+        }
+
+        Instance {
+            m: m,
+            mem: mem,
+            input: input,
+            in_pos: 0,
+            output: Vec::new(),
+            ends: ends,
+            elses: elses,
+        }
+    }
+
+    fn addr(&self, base: i32, offset: u32, size: usize) -> Result<usize, Trap> {
+        let a = base as u32 as usize + offset as usize;
+        if a + size > self.mem.len() {
+            return trap("out of bounds memory access");
+        }
+        Ok(a)
+    }
+
+    fn load(&self, a: usize) -> i32 {
+        let m = &self.mem;
+        (m[a] as u32 | (m[a + 1] as u32) << 8 | (m[a + 2] as u32) << 16 |
+         (m[a + 3] as u32) << 24) as i32
+    }
+
+    fn store(&mut self, a: usize, v: i32) {
+        for k in 0..4 {
+            self.mem[a + k] = (v >> (8 * k)) as u8;
+        }
+    }
+
+    fn call_host(&mut self, import: &Import, args: &[i32]) -> Result<Option<i32>, Trap> {
+        if import.module != "wasi_snapshot_preview1" {
+            return trap("unknown import module");
+        }
+        match &import.name[..] {
+            "fd_read" | "fd_write" => {
+                let (fd, iovs, n, nbytes) = (args[0], args[1], args[2], args[3]);
+                let mut total = 0;
+                for k in 0..n {
+                    let iov = try!(self.addr(iovs, (k * 8) as u32, 8));
+                    let ptr = self.load(iov);
+                    let len = self.load(iov + 4) as u32 as usize;
+                    let buf = try!(self.addr(ptr, 0, len));
+                    if import.name == "fd_read" {
+                        if fd != 0 {
+                            return Ok(Some(8)); // EBADF
+                        }
+                        let avail = self.input.len() - self.in_pos;
+                        let len = if len < avail { len } else { avail };
+                        for i in 0..len {
+                            self.mem[buf + i] = self.input[self.in_pos + i];
+                        }
+                        self.in_pos += len;
+                        total += len;
+                    } else {
+                        match fd {
+                            1 => self.output.extend(self.mem[buf..buf + len].iter().cloned()),
+                            2 => {},
+                            _ => return Ok(Some(8)),
+                        }
+                        total += len;
+                    }
+                }
+                let a = try!(self.addr(nbytes, 0, 4));
+                self.store(a, total as i32);
+                Ok(Some(0))
+            },
+            "proc_exit" => Err(Trap::Exit(args[0])),
+            _ => trap("unknown WASI function"),
+        }
+    }
+
+    fn call(&mut self, f: u32, args: Vec<i32>) -> Result<Option<i32>, Trap> {
+        let nimports = self.m.imports.len();
+        if (f as usize) < nimports {
+            let import = self.m.imports[f as usize].clone();
+            return self.call_host(&import, &args);
+        }
+        let fi = f as usize - nimports;
+        let m = self.m;
+        let func = &m.funcs[fi];
+        let results = m.types[func.ty as usize].results as usize;
+
+        let mut locals = args;
+        for _ in 0..func.locals {
+            locals.push(0);
+        }
+        let mut stack: Vec<i32> = Vec::new();
+        let mut labels: Vec<Label> = Vec::new();
+        let mut pc = 0;
+
+        macro_rules! pop {
+            () => (match stack.pop() {
+                Some(v) => v,
+                None => return trap("value stack underflow"),
+            })
+        }
+        macro_rules! binop {
+            ($a:ident, $b:ident, $e:expr) => ({
+                let $b = pop!();
+                let $a = pop!();
+                stack.push($e);
+            })
+        }
+        macro_rules! cmp {
+            ($a:ident, $b:ident, $e:expr) => ({
+                let $b = pop!();
+                let $a = pop!();
+                stack.push(($e) as i32);
+            })
+        }
+
+        loop {
+            if pc >= func.body.len() {
+                // implicit `end` of the function
+                if stack.len() < results {
+                    return trap("missing function result");
+                }
+                return Ok(if results > 0 { stack.pop() } else { None });
+            }
+
+            let mut branch = None;
+            match func.body[pc] {
+                Instr::Unreachable => return trap("unreachable"),
+                Instr::Block(bt) | Instr::Loop(bt) => {
+                    let is_loop = match func.body[pc] {
+                        Instr::Loop(_) => true,
+                        _ => false,
+                    };
+                    let end = self.ends[fi][&pc];
+                    labels.push(Label {
+                        cont: if is_loop { pc + 1 } else { end + 1 },
+                        height: stack.len(),
+                        arity: if is_loop || bt == BlockType::Empty { 0 } else { 1 },
+                        is_loop: is_loop,
+                    });
+                },
+                Instr::If(bt) => {
+                    let c = pop!();
+                    let end = self.ends[fi][&pc];
+                    let label = Label {
+                        cont: end + 1,
+                        height: stack.len(),
+                        arity: if bt == BlockType::Empty { 0 } else { 1 },
+                        is_loop: false,
+                    };
+                    if c != 0 {
+                        labels.push(label);
+                    } else {
+                        match self.elses[fi].get(&pc) {
+                            Some(&els) => {
+                                labels.push(label);
+                                pc = els;
+                            },
+                            None => pc = end,
+                        }
+                    }
+                },
+                Instr::Else => branch = Some(0),
+                Instr::End => { labels.pop(); },
+                Instr::Br(l) => branch = Some(l),
+                Instr::BrIf(l) => {
+                    if pop!() != 0 {
+                        branch = Some(l);
+                    }
+                },
+                Instr::BrTable(ref ls, l) => {
+                    let i = pop!() as u32 as usize;
+                    branch = Some(if i < ls.len() { ls[i] } else { l });
+                },
+                Instr::Return => {
+                    return Ok(if results > 0 { Some(pop!()) } else { None });
+                },
+                Instr::Call(callee) => {
+                    let ty = if (callee as usize) < nimports {
+                        m.types[m.imports[callee as usize].ty as usize]
+                    } else {
+                        m.types[m.funcs[callee as usize - nimports].ty as usize]
+                    };
+                    let n = ty.params as usize;
+                    if stack.len() < n {
+                        return trap("value stack underflow");
+                    }
+                    let at = stack.len() - n;
+                    let args = stack.split_off(at);
+                    if let Some(v) = try!(self.call(callee, args)) {
+                        stack.push(v);
+                    }
+                },
+                Instr::Drop => { pop!(); },
+                Instr::Select => {
+                    let c = pop!();
+                    let b = pop!();
+                    let a = pop!();
+                    stack.push(if c != 0 { a } else { b });
+                },
+                Instr::LocalGet(n) => stack.push(locals[n as usize]),
+                Instr::LocalSet(n) => locals[n as usize] = pop!(),
+                Instr::I32Load(o) => {
+                    let a = try!(self.addr(pop!(), o, 4));
+                    stack.push(self.load(a));
+                },
+                Instr::I32Load8U(o) => {
+                    let a = try!(self.addr(pop!(), o, 1));
+                    stack.push(self.mem[a] as i32);
+                },
+                Instr::I32Store(o) => {
+                    let v = pop!();
+                    let a = try!(self.addr(pop!(), o, 4));
+                    self.store(a, v);
+                },
+                Instr::I32Store8(o) => {
+                    let v = pop!();
+                    let a = try!(self.addr(pop!(), o, 1));
+                    self.mem[a] = v as u8;
+                },
+                Instr::MemorySize => stack.push((self.mem.len() / 65536) as i32),
+                Instr::MemoryGrow => {
+                    let n = pop!() as u32 as usize;
+                    let pages = self.mem.len() / 65536;
+                    if pages + n > MAX_PAGES {
+                        stack.push(-1);
+                    } else {
+                        self.mem.resize((pages + n) * 65536, 0);
+                        stack.push(pages as i32);
+                    }
+                },
+                Instr::I32Const(v) => stack.push(v),
+                Instr::I32Eqz => {
+                    let v = pop!();
+                    stack.push((v == 0) as i32);
+                },
+                Instr::I32Eq => cmp!(a, b, a == b),
+                Instr::I32Ne => cmp!(a, b, a != b),
+                Instr::I32LtS => cmp!(a, b, a < b),
+                Instr::I32LtU => cmp!(a, b, (a as u32) < (b as u32)),
+                Instr::I32GtU => cmp!(a, b, (a as u32) > (b as u32)),
+                Instr::I32LeU => cmp!(a, b, (a as u32) <= (b as u32)),
+                Instr::I32GeU => cmp!(a, b, (a as u32) >= (b as u32)),
+                Instr::I32Add => binop!(a, b, a.wrapping_add(b)),
+                Instr::I32Sub => binop!(a, b, a.wrapping_sub(b)),
+                Instr::I32Mul => binop!(a, b, a.wrapping_mul(b)),
+                Instr::I32DivU | Instr::I32RemU => {
+                    let b = pop!();
+                    let a = pop!();
+                    if b == 0 {
+                        return trap("integer divide by zero");
+                    }
+                    stack.push(match func.body[pc] {
+                        Instr::I32DivU => ((a as u32) / (b as u32)) as i32,
+                        _ => ((a as u32) % (b as u32)) as i32,
+                    });
+                },
+                Instr::I32And => binop!(a, b, a & b),
+                Instr::I32Or => binop!(a, b, a | b),
+                Instr::I32Xor => binop!(a, b, a ^ b),
+                Instr::I32Shl => binop!(a, b, a.wrapping_shl(b as u32)),
+                Instr::I32ShrU => binop!(a, b, (a as u32).wrapping_shr(b as u32) as i32),
+            }
+
+            match branch {
+                Some(l) if l as usize == labels.len() => {
+                    // the function body itself
+                    return Ok(if results > 0 { Some(pop!()) } else { None });
+                },
+                Some(l) => {
+                    let target = labels.len() - 1 - l as usize;
+                    let (cont, height, arity, is_loop) = {
+                        let t = &labels[target];
+                        (t.cont, t.height, t.arity, t.is_loop)
+                    };
+                    let at = stack.len() - arity;
+                    let vals = stack.split_off(at);
+                    stack.truncate(height);
+                    stack.extend(vals.into_iter());
+                    labels.truncate(if is_loop { target + 1 } else { target });
+                    pc = cont;
+                },
+                None => pc += 1,
+            }
+        }
+    }
+}
+
+// runs the `_start` export, returning what was written to stdout and the
+// exit status passed to proc_exit
+pub fn run_start(m: &Module, input: &[u8]) -> Result<(Vec<u8>, i32), String> {
+    let start = match m.exports.iter().find(|e| e.name == "_start") {
+        Some(e) if e.kind == ExportKind::Func => e.idx,
+        _ => return Err("no `_start` export".to_string()),
+    };
+    let mut inst = Instance::new(m, input);
+    let status = match inst.call(start, Vec::new()) {
+        Ok(_) => 0,
+        Err(Trap::Exit(status)) => status,
+        Err(Trap::Error(msg)) => return Err(msg),
+    };
+    Ok((inst.output, status))
+}